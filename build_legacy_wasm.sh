@@ -0,0 +1,26 @@
+#!/bin/bash
+
+# Builds the canisters of an earlier revision into target/legacy, for the
+# upgrade tests that install an old wasm and upgrade it to the current one.
+# Defaults to the first commit of the repository.
+
+set -e
+
+ROOT="$(cd "$(dirname "$0")" && pwd)"
+REVISION="${1:-$(git -C "$ROOT" rev-list --max-parents=0 HEAD)}"
+WORKTREE="$(mktemp -d)"
+OUT="$ROOT/target/legacy"
+
+echo "📦 Building legacy canisters from $REVISION"
+
+trap 'git -C "$ROOT" worktree remove --force "$WORKTREE"' EXIT
+git -C "$ROOT" worktree add --detach "$WORKTREE" "$REVISION"
+
+(cd "$WORKTREE" && cargo build --target wasm32-unknown-unknown --release \
+  -p crnl_ledger_canister -p chronolock_canister)
+
+mkdir -p "$OUT"
+for canister in crnl_ledger_canister chronolock_canister; do
+  cp "$WORKTREE/target/wasm32-unknown-unknown/release/$canister.wasm" "$OUT/$canister.wasm"
+  echo "✅ $OUT/$canister.wasm"
+done
@@ -45,6 +45,10 @@ fi
 echo ""
 echo "📝 Generating initialization arguments..."
 
+# Optional cap on the CRNL supply, fixed at install time,
+# e.g. MAX_SUPPLY_ARG='opt (20000000000000000 : nat)'
+MAX_SUPPLY_ARG=${MAX_SUPPLY_ARG:-null}
+
 # Generate init_args.did for crnl_ledger
 cat <<EOF > src/backend/crnl_ledger_canister/ledger_init_args.did
 (
@@ -53,15 +57,25 @@ cat <<EOF > src/backend/crnl_ledger_canister/ledger_init_args.did
   10000000000000000 : nat,
   31536000 : nat64,
   10000 : nat,
-  principal "$ADMIN_PRINCIPAL"
+  principal "$ADMIN_PRINCIPAL",
+  $MAX_SUPPLY_ARG
 )
 EOF
 
+# The chronolock canister collects mint payments on the CRNL ledger
+LEDGER_CANISTER_ID=$(dfx canister id crnl_ledger_canister --network "$NETWORK" 2>/dev/null)
+if [ -n "$LEDGER_CANISTER_ID" ]; then
+  LEDGER_ARG="opt principal \"$LEDGER_CANISTER_ID\""
+else
+  LEDGER_ARG="null"
+fi
+
 # Generate init_args.did for chronolock (uses management canister directly)
 cat <<EOF > src/backend/chronolock_canister/chronolock_init_args.did
 (
   principal "$ADMIN_PRINCIPAL",
-  opt "$NETWORK"
+  opt "$NETWORK",
+  $LEDGER_ARG
 )
 EOF
 
@@ -74,6 +88,7 @@ echo "🎯 Configuration Summary:"
 echo "   👤 Admin Principal: $ADMIN_PRINCIPAL"
 echo "   🔐 VetKD System:    Management canister (direct)"
 echo "   🌐 Network:         $NETWORK"
+echo "   💰 CRNL Ledger:     ${LEDGER_CANISTER_ID:-not created yet}"
 echo "   📋 dfx.json:        Configured for $NETWORK deployment"
 
 if [ "$NETWORK" = "ic" ]; then
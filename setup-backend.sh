@@ -31,6 +31,8 @@ candid-extractor target/wasm32-unknown-unknown/release/crnl_ledger_canister.wasm
 echo "Extracted crnl_ledger_canister.did" &&
 candid-extractor target/wasm32-unknown-unknown/release/chronolock_canister.wasm > src/backend/chronolock_canister/chronolock_canister.did &&
 echo "Extracted chronolock_canister.did" &&
+candid-extractor target/wasm32-unknown-unknown/release/crnl_archive_canister.wasm > src/backend/crnl_archive_canister/crnl_archive_canister.did &&
+echo "Extracted crnl_archive_canister.did" &&
 
 # Generate declarations for remaining canisters
 
@@ -75,5 +77,13 @@ else
   echo "   • internet_identity"
 fi
 
+# Upload the archive canister wasm so the ledger can spawn its archive
+echo ""
+echo "📦 Uploading archive canister wasm to crnl_ledger_canister..."
+ARCHIVE_ARG_FILE=$(mktemp)
+printf '(blob "%s")' "$(od -An -v -tx1 target/wasm32-unknown-unknown/release/crnl_archive_canister.wasm | tr -d ' \n' | sed 's/../\\&/g')" > "$ARCHIVE_ARG_FILE"
+dfx canister call --network "$NETWORK" crnl_ledger_canister set_archive_wasm --argument-file "$ARCHIVE_ARG_FILE"
+rm -f "$ARCHIVE_ARG_FILE"
+
 echo ""
 echo "🎉 Backend setup completed successfully!"
\ No newline at end of file
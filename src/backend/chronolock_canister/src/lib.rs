@@ -1,10 +1,13 @@
 // src/backend/chronolock/src/lib.rs
 
-use candid::{CandidType, Principal};
-use ic_cdk::api::call::call_with_payment;
-use ic_cdk::api::time;
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::call::{
+    accept_message, arg_data_raw_size, call_with_payment, method_name, msg_cycles_refunded128,
+};
+use ic_cdk::api::{canister_balance128, time};
 use ic_cdk::caller;
-use ic_cdk_macros::{init, query, update};
+use ic_cdk_macros::{init, inspect_message, post_upgrade, pre_upgrade, query, update};
+use ic_cdk_timers::TimerId;
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
@@ -12,8 +15,11 @@ use ic_stable_structures::{
 };
 use serde::Deserialize;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -30,6 +36,13 @@ enum ChronoError {
     InvalidPrincipal,
     UnauthorizedCaller,
     AdminRequired,
+    // Payment-related errors
+    PaymentFailed(String),
+    InvalidRoleChange(String),
+    // Too many requests; retry after the given number of seconds
+    RateLimited { retry_after: u64 },
+    // The operating mode or a circuit breaker refuses the call
+    Unavailable(String),
 }
 
 #[derive(CandidType, Deserialize)]
@@ -85,6 +98,396 @@ pub struct VetKDDeriveKeyReply {
     pub encrypted_key: Vec<u8>,
 }
 
+// ICRC-1 account on the CRNL ledger
+#[derive(CandidType, Deserialize, Clone)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<[u8; 32]>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferArgs {
+    from_subaccount: Option<[u8; 32]>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<[u8; 32]>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// CRNL collected from the caller, kept to refund it if the paid operation fails
+struct Payment {
+    ledger_id: Principal,
+    amount: u64,
+    memo: &'static [u8],
+}
+
+// Price of VetKD key derivations. How often a principal may derive keys is
+// governed by the rate limits of the key-derivation endpoints.
+#[derive(CandidType, Deserialize, Clone)]
+struct DerivationPricing {
+    price: u64,         // CRNL base units per derivation; zero makes them free
+    free_per_lock: u32, // Free derivations per lock for its owner and each recipient
+}
+
+impl Default for DerivationPricing {
+    fn default() -> Self {
+        DerivationPricing {
+            price: 0,
+            free_per_lock: DEFAULT_FREE_DERIVATIONS_PER_LOCK,
+        }
+    }
+}
+
+impl Storable for DerivationPricing {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Token bucket limit of an endpoint: a principal may make up to capacity calls
+// in a burst and regains one call every refill_interval seconds. A capacity of
+// zero turns the limit off.
+#[derive(CandidType, Deserialize, Clone)]
+struct RateLimit {
+    capacity: u32,
+    refill_interval: u64,
+}
+
+impl Storable for RateLimit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Calls a principal has left on an endpoint; updated_at is in seconds
+#[derive(CandidType, Deserialize, Clone)]
+struct TokenBucket {
+    tokens: u32,
+    updated_at: u64,
+}
+
+// Rate limiter buckets by (caller, method), indexed by updated_at so that the
+// stalest bucket can be evicted without scanning the map. An evicted bucket is
+// simply full again on its next use.
+struct RateBuckets {
+    buckets: BTreeMap<(Principal, String), TokenBucket>,
+    by_age: BTreeSet<(u64, Principal, String)>,
+}
+
+impl RateBuckets {
+    const fn new() -> Self {
+        RateBuckets {
+            buckets: BTreeMap::new(),
+            by_age: BTreeSet::new(),
+        }
+    }
+
+    fn get(&self, key: &(Principal, String)) -> Option<&TokenBucket> {
+        self.buckets.get(key)
+    }
+
+    // Stores a bucket, evicting the stalest ones to stay within MAX_RATE_BUCKETS.
+    fn insert(&mut self, key: (Principal, String), bucket: TokenBucket) {
+        match self.buckets.get(&key) {
+            Some(old) => {
+                self.by_age.remove(&(old.updated_at, key.0, key.1.clone()));
+            }
+            None => {
+                while self.buckets.len() >= MAX_RATE_BUCKETS {
+                    let Some((_, caller, method)) = self.by_age.pop_first() else {
+                        break;
+                    };
+                    self.buckets.remove(&(caller, method));
+                }
+            }
+        }
+        self.by_age
+            .insert((bucket.updated_at, key.0, key.1.clone()));
+        self.buckets.insert(key, bucket);
+    }
+}
+
+// Rate limiter buckets kept across upgrades
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct RateLimitSnapshot {
+    buckets: Vec<(Principal, String, TokenBucket)>,
+}
+
+impl Storable for RateLimitSnapshot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// How a derivation is paid for
+enum DerivationCharge {
+    Free { usage_key: String },
+    Paid(Option<Payment>),
+}
+
+// Errors of the CRNL ledger, as returned by convert_dapp_funds_to_cycles and
+// record_qualifying_action
+#[derive(CandidType, Deserialize, Debug)]
+enum LedgerError {
+    InsufficientBalance,
+    InsufficientFee,
+    InsufficientPoolFunds,
+    InsufficientAllowance,
+    AlreadyRegistered,
+    InvalidReferral,
+    TransferError,
+    FeeProcessingError,
+    Unauthorized,
+    InvalidAccount,
+    ArithmeticError,
+    VestingLocked,
+    NotAuthenticated,
+    InvalidPrincipal,
+    UnauthorizedCaller,
+    AdminRequired,
+    ArchiveError(String),
+    MemoTooLong { max_length: u64 },
+    InvalidFeeDistribution(String),
+    InvalidVestingSchedule(String),
+    InvalidRoleChange(String),
+    ProposalPending { proposal_id: u64 },
+    InvalidProposal(String),
+    InvalidMultisigConfig(String),
+    MintLimitExceeded { headroom: Nat },
+    InvalidMintBudget(String),
+    CyclesConversionError(String),
+    RateLimited { retry_after: u64 },
+    InvalidRateLimit(String),
+    QuarantinedValueNotFound,
+    ImportError(String),
+    Unavailable(String),
+    InvalidReferralCampaign(String),
+    ReferralLimitReached(String),
+}
+
+// Top-up requested from the ledger's dapp funds
+#[derive(CandidType, Deserialize)]
+struct CyclesTopUp {
+    canister_id: Principal,
+    amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum CyclesConversionStatus {
+    Pending,
+    Completed,
+    Failed(String),
+    Refunded(String),
+    RefundFailed(String),
+    SwapUnknown(String),
+    TopUpFailed(String),
+}
+
+// Part of the ledger's conversion record that this canister reports
+#[derive(CandidType, Deserialize)]
+struct CyclesConversion {
+    id: u64,
+    status: CyclesConversionStatus,
+}
+
+// Symbol, name and description reported by the ICRC-7 endpoints
+#[derive(CandidType, Deserialize, Clone)]
+struct CollectionMetadata {
+    symbol: String,
+    name: String,
+    description: String,
+}
+
+impl Default for CollectionMetadata {
+    fn default() -> Self {
+        CollectionMetadata {
+            symbol: "CHRONOLOCK".to_string(),
+            name: "Chronolock Collection".to_string(),
+            description: "A collection of time-locked NFTs".to_string(),
+        }
+    }
+}
+
+impl Storable for CollectionMetadata {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Optional argument of an upgrade; set fields replace the stored values
+#[derive(CandidType, Deserialize)]
+struct UpgradeArgs {
+    network: Option<String>,
+    ledger_id: Option<Principal>,
+    max_metadata_size: Option<u64>,
+    mint_price: Option<u64>,
+}
+
+// Cycles thresholds and costs. Below low_water_mark the periodic check logs an
+// alert and, when top_up_amount is set, asks the ledger to convert that much of
+// its dapp funds into cycles for this canister.
+#[derive(CandidType, Deserialize, Clone)]
+struct CyclesConfig {
+    low_water_mark: u128,
+    top_up_amount: u128,      // CRNL base units; zero only alerts
+    check_interval: u64,      // Seconds between balance checks
+    vetkd_derive_cycles: u64, // Attached to each VetKD derivation; unused cycles are refunded
+}
+
+impl Default for CyclesConfig {
+    fn default() -> Self {
+        CyclesConfig {
+            low_water_mark: DEFAULT_LOW_WATER_MARK,
+            top_up_amount: 0,
+            check_interval: DEFAULT_CYCLES_CHECK_INTERVAL_SECONDS,
+            vetkd_derive_cycles: DEFAULT_VETKD_DERIVE_CYCLES,
+        }
+    }
+}
+
+impl Storable for CyclesConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Cycles spent by an operation since install
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct OperationCycles {
+    calls: u64,
+    cycles: u128,
+}
+
+impl Storable for OperationCycles {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct TopUpRequest {
+    timestamp: u64, // Seconds
+    amount: Nat,
+    outcome: String,
+}
+
+// Balances seen by the periodic check as (seconds, cycles), oldest first, and
+// the last top-up requested from the ledger
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct CyclesHistory {
+    samples: Vec<(u64, u128)>,
+    last_top_up: Option<TopUpRequest>,
+}
+
+impl Storable for CyclesHistory {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize)]
+struct CyclesStats {
+    balance: Nat,
+    burn_rate_per_day: Nat, // Averaged over the recorded samples
+    low_water_mark: Nat,
+    operations: Vec<(String, OperationCycles)>,
+    last_top_up: Option<TopUpRequest>,
+}
+
+// Administrative roles. The owner implicitly holds every other role.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    Owner,
+    Minter,
+    PoolManager,
+    Moderator,
+    Auditor,
+}
+
+impl Role {
+    // Tag of the role in stable memory keys
+    fn tag(self) -> u8 {
+        self as u8
+    }
+
+    // Role of a stored tag; tags written by a newer version are unknown here
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Role::Owner),
+            1 => Some(Role::Minter),
+            2 => Some(Role::PoolManager),
+            3 => Some(Role::Moderator),
+            4 => Some(Role::Auditor),
+            _ => None,
+        }
+    }
+}
+
 // Merged Chronolock struct - contains all metadata directly
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Chronolock {
@@ -116,10 +519,10 @@ pub struct EncryptedMetadataPayload {
 
 impl Storable for Chronolock {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(candid::encode_one(self).expect("Failed to encode Chronolock"))
+        encode_versioned(self)
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).expect("Failed to decode Chronolock")
+        decode_versioned(&bytes)
     }
     const BOUND: Bound = Bound::Unbounded;
 }
@@ -133,10 +536,10 @@ struct LogEntry {
 
 impl Storable for LogEntry {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(candid::encode_one(self).expect("Failed to encode LogEntry"))
+        encode_versioned(self)
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).expect("Failed to decode LogEntry")
+        decode_versioned(&bytes)
     }
     const BOUND: Bound = Bound::Bounded {
         max_size: 1024,
@@ -151,12 +554,10 @@ struct TokenList {
 
 impl Storable for TokenList {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(candid::encode_one(&self.tokens).expect("Failed to encode TokenList"))
+        encode_versioned(self)
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Self {
-            tokens: candid::decode_one(&bytes).expect("Failed to decode TokenList"),
-        }
+        decode_versioned(&bytes)
     }
     const BOUND: Bound = Bound::Unbounded;
 }
@@ -170,14 +571,300 @@ struct MediaUploadState {
 
 impl Storable for MediaUploadState {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(candid::encode_one(self).expect("Failed to encode MediaUploadState"))
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Operating mode of the canister, set by the owner during incidents and restores:
+// - Normal: every call runs, unless the breaker of its subsystem is tripped
+// - Paused: creation, transfers, media uploads and key derivations are halted
+// - ReadOnly: as Paused, and chronolocks can't be updated or burned either;
+//   only administration runs
+// - Restoring: as ReadOnly, and import_state rebuilds the state exported by
+//   another canister
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+enum OperatingMode {
+    Normal,
+    ReadOnly,
+    Paused,
+    Restoring,
+}
+
+// Part of the canister that a circuit breaker halts on its own
+#[derive(
+    CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum Subsystem {
+    Creation,
+    Transfer,
+    MediaUpload,
+    KeyDerivation,
+}
+
+impl Subsystem {
+    // Tag of the subsystem in stable memory keys
+    fn tag(self) -> u8 {
+        self as u8
+    }
+
+    // Subsystem of a stored tag; tags written by a newer version are unknown here
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Subsystem::Creation),
+            1 => Some(Subsystem::Transfer),
+            2 => Some(Subsystem::MediaUpload),
+            3 => Some(Subsystem::KeyDerivation),
+            _ => None,
+        }
+    }
+}
+
+impl Storable for OperatingMode {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Part of the state covered by export_state and import_state
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+enum ExportSection {
+    Chronolocks,
+    OwnerIndex,
+    Media,
+    Creators,
+}
+
+// Record of an export stream. The layout of the stream is versioned by
+// EXPORT_FORMAT_VERSION; new kinds of records are added as new variants.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+enum ExportRecord {
+    Chronolock(Chronolock),
+    OwnerTokens {
+        owner: Principal,
+        tokens: Vec<String>,
+    },
+    // Part of a media file starting at offset; a file is rebuilt from its
+    // chunks in order
+    MediaChunk {
+        media_id: String,
+        offset: u64,
+        total_size: u64,
+        bytes: Vec<u8>,
+    },
+    Creator {
+        creator: Principal,
+        first_created_at: u64,
+    },
+}
+
+// Page of an export stream. The checksum is the SHA-256 of the records, each
+// encoded with Candid and prefixed by its length as a big-endian u64.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct ExportPage {
+    version: u8,
+    section: ExportSection,
+    offset: u64,
+    records: Vec<ExportRecord>,
+    checksum: [u8; 32],
+    next_offset: Option<u64>, // None on the last page of the section
+}
+
+// Checksum of an export page without its records, to compare two canisters
+#[derive(CandidType, Deserialize, Serialize)]
+struct ExportChecksum {
+    section: ExportSection,
+    offset: u64,
+    records: u64,
+    checksum: [u8; 32],
+    next_offset: Option<u64>,
+}
+
+// -------------------------
+// Versioned Encoding
+// -------------------------
+
+// Values in stable memory are written as a version byte followed by their
+// Candid encoding. Values written before the version byte was introduced start
+// with the Candid magic bytes and are read as version 0.
+const CANDID_MAGIC: &[u8] = b"DIDL";
+
+trait Versioned: CandidType + for<'de> Deserialize<'de> {
+    // Version written by this code. Bump it and handle the previous version in
+    // decode_version when a change cannot be absorbed by Candid subtyping.
+    const VERSION: u8 = 1;
+
+    // Decodes a payload written with the given version.
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, String> {
+        if version > Self::VERSION {
+            return Err(format!("Unknown version {}", version));
+        }
+        candid::decode_one(payload).map_err(|e| e.to_string())
+    }
+
+    // Stand-in returned for a value that cannot be decoded. Its bytes are kept
+    // in the quarantine so that the value can be repaired.
+    fn quarantined() -> Self;
+}
+
+fn encode_versioned<T: Versioned>(value: &T) -> Cow<'static, [u8]> {
+    let mut bytes = vec![T::VERSION];
+    bytes.extend(candid::encode_one(value).expect("Failed to encode stable value"));
+    Cow::Owned(bytes)
+}
+
+fn decode_versioned<T: Versioned>(bytes: &[u8]) -> T {
+    let decoded = if bytes.starts_with(CANDID_MAGIC) {
+        T::decode_version(0, bytes)
+    } else {
+        match bytes.split_first() {
+            Some((version, payload)) => T::decode_version(*version, payload),
+            None => Err("Empty value".to_string()),
+        }
+    };
+    decoded.unwrap_or_else(|error| {
+        quarantine_value(std::any::type_name::<T>(), bytes, error);
+        T::quarantined()
+    })
+}
+
+// Stored value that could not be decoded, kept for inspection and repair
+#[derive(CandidType, Deserialize, Clone)]
+struct QuarantinedValue {
+    id: u64,
+    type_name: String,
+    bytes: Vec<u8>,
+    error: String,
+    detected_at: u64,
+}
+
+// The quarantine itself is plain Candid: it is the sink for decode failures.
+impl Storable for QuarantinedValue {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode QuarantinedValue"))
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).expect("Failed to decode MediaUploadState")
+        candid::decode_one(&bytes).unwrap_or_else(|e| QuarantinedValue {
+            id: 0,
+            type_name: "QuarantinedValue".to_string(),
+            bytes: bytes.to_vec(),
+            error: e.to_string(),
+            detected_at: 0,
+        })
     }
     const BOUND: Bound = Bound::Unbounded;
 }
 
+impl Versioned for DerivationPricing {
+    fn quarantined() -> Self {
+        DerivationPricing::default()
+    }
+}
+
+// Turns the limit off until it is set again
+impl Versioned for RateLimit {
+    fn quarantined() -> Self {
+        RateLimit {
+            capacity: 0,
+            refill_interval: 1,
+        }
+    }
+}
+
+impl Versioned for RateLimitSnapshot {
+    fn quarantined() -> Self {
+        RateLimitSnapshot::default()
+    }
+}
+
+impl Versioned for CollectionMetadata {
+    fn quarantined() -> Self {
+        CollectionMetadata::default()
+    }
+}
+
+impl Versioned for CyclesConfig {
+    fn quarantined() -> Self {
+        CyclesConfig::default()
+    }
+}
+
+impl Versioned for OperationCycles {
+    fn quarantined() -> Self {
+        OperationCycles::default()
+    }
+}
+
+impl Versioned for CyclesHistory {
+    fn quarantined() -> Self {
+        CyclesHistory::default()
+    }
+}
+
+// A lock without keys that never opens
+impl Versioned for Chronolock {
+    fn quarantined() -> Self {
+        Chronolock {
+            id: String::new(),
+            owner: Principal::anonymous(),
+            title: "Quarantined".to_string(),
+            unlock_time: u64::MAX,
+            created_at: 0,
+            user_keys: Vec::new(),
+            encrypted_metadata: String::new(),
+        }
+    }
+}
+
+impl Versioned for LogEntry {
+    fn quarantined() -> Self {
+        LogEntry {
+            id: String::new(),
+            timestamp: 0,
+            activity: "Log entry could not be decoded".to_string(),
+        }
+    }
+}
+
+// Version 0 stored the bare list of token ids.
+impl Versioned for TokenList {
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, String> {
+        let decoded = match version {
+            0 => candid::decode_one(payload).map(|tokens| TokenList { tokens }),
+            1 => candid::decode_one(payload),
+            _ => return Err(format!("Unknown version {}", version)),
+        };
+        decoded.map_err(|e| e.to_string())
+    }
+    fn quarantined() -> Self {
+        TokenList { tokens: Vec::new() }
+    }
+}
+
+// An upload that can never be finished
+impl Versioned for MediaUploadState {
+    fn quarantined() -> Self {
+        MediaUploadState {
+            total_chunks: 0,
+            received_chunks: 0,
+            chunks: Vec::new(),
+        }
+    }
+}
+
+impl Versioned for OperatingMode {
+    fn quarantined() -> Self {
+        OperatingMode::Normal
+    }
+}
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
@@ -215,9 +902,6 @@ thread_local! {
     static MEDIA_UPLOADS: RefCell<StableBTreeMap<String, MediaUploadState, Memory>> = RefCell::new(
         StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))))
     );
-    static SYMBOL: RefCell<String> = RefCell::new("CHRONOLOCK".to_string());
-    static NAME: RefCell<String> = RefCell::new("Chronolock Collection".to_string());
-    static DESCRIPTION: RefCell<String> = RefCell::new("A collection of time-locked NFTs".to_string());
     // Whitelist for trusted principals (e.g., Internet Identity principals)
     static TRUSTED_PRINCIPALS: RefCell<StableBTreeMap<Principal, bool, Memory>> = RefCell::new(
         StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))))
@@ -227,7 +911,142 @@ thread_local! {
         StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))), false)
             .unwrap_or_else(|e| panic!("Failed to initialize ADMIN_BYPASS_ENABLED: {:?}", e))
     );
-}
+    // CRNL ledger used to collect mint payments
+    static LEDGER_CANISTER_ID: RefCell<StableCell<Option<Principal>, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))), None)
+            .unwrap_or_else(|e| panic!("Failed to initialize LEDGER_CANISTER_ID: {:?}", e))
+    );
+    // Price of minting a chronolock in CRNL base units; zero disables payment
+    static MINT_PRICE: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))), 0)
+            .unwrap_or_else(|e| panic!("Failed to initialize MINT_PRICE: {:?}", e))
+    );
+    // Role assignments: (role, principal) -> ()
+    static ROLE_MEMBERS: RefCell<StableBTreeMap<(u8, Principal), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))))
+    );
+    // Low-water mark, top-up amount and per-call cycle costs
+    static CYCLES_CONFIG: RefCell<StableCell<CyclesConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))), CyclesConfig::default())
+            .unwrap_or_else(|e| panic!("Failed to initialize CYCLES_CONFIG: {:?}", e))
+    );
+    // Cycles spent by operation name
+    static CYCLES_SPENT: RefCell<StableBTreeMap<String, OperationCycles, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))))
+    );
+    static CYCLES_HISTORY: RefCell<StableCell<CyclesHistory, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))), CyclesHistory::default())
+            .unwrap_or_else(|e| panic!("Failed to initialize CYCLES_HISTORY: {:?}", e))
+    );
+    static CYCLES_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    // Set while a top-up request to the ledger is in flight
+    static TOP_UP_IN_FLIGHT: Cell<bool> = const { Cell::new(false) };
+    static DERIVATION_PRICING: RefCell<StableCell<DerivationPricing, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))), DerivationPricing::default())
+            .unwrap_or_else(|e| panic!("Failed to initialize DERIVATION_PRICING: {:?}", e))
+    );
+    // Free derivations used, keyed by "<token id>:<principal>"
+    static FREE_DERIVATIONS_USED: RefCell<StableBTreeMap<String, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))))
+    );
+    // Rate limits overriding DEFAULT_RATE_LIMITS, by method
+    static RATE_LIMITS: RefCell<StableBTreeMap<String, RateLimit, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))))
+    );
+    // Rate limiter buckets saved by pre_upgrade
+    static RATE_LIMIT_SNAPSHOT: RefCell<StableCell<RateLimitSnapshot, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))), RateLimitSnapshot::default())
+            .unwrap_or_else(|e| panic!("Failed to initialize RATE_LIMIT_SNAPSHOT: {:?}", e))
+    );
+    static COLLECTION_METADATA: RefCell<StableCell<CollectionMetadata, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))), CollectionMetadata::default())
+            .unwrap_or_else(|e| panic!("Failed to initialize COLLECTION_METADATA: {:?}", e))
+    );
+    // Version of the stable memory layout; zero for canisters installed before it was recorded
+    static SCHEMA_VERSION: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))), 0)
+            .unwrap_or_else(|e| panic!("Failed to initialize SCHEMA_VERSION: {:?}", e))
+    );
+    // Stored values that could not be decoded, by id
+    static QUARANTINE: RefCell<StableBTreeMap<u64, QuarantinedValue, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))))
+    );
+    // Quarantine ids by the hash of the value's type and bytes
+    static QUARANTINE_INDEX: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))))
+    );
+    // Mode set by set_operating_mode
+    static OPERATING_MODE: RefCell<StableCell<OperatingMode, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))), OperatingMode::Normal)
+            .unwrap_or_else(|e| panic!("Failed to initialize OPERATING_MODE: {:?}", e))
+    );
+    // Subsystems halted by set_circuit_breaker
+    static TRIPPED_BREAKERS: RefCell<StableBTreeMap<u8, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))))
+    );
+    // Creators without their creation time, moved into CREATORS by the migration
+    // to schema version 4
+    static LEGACY_CREATORS: RefCell<StableBTreeMap<Principal, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))))
+    );
+    // Time in nanoseconds at which each principal created its first chronolock.
+    // A creation after a referral was claimed qualifies that referral.
+    static CREATORS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))))
+    );
+    // Rate limiter buckets by (caller, method)
+    static RATE_BUCKETS: RefCell<RateBuckets> = const { RefCell::new(RateBuckets::new()) };
+}
+
+// Memos attached to payments and refunds on the ledger
+const MINT_PAYMENT_MEMO: &[u8] = b"chronolock-mint";
+const DERIVATION_PAYMENT_MEMO: &[u8] = b"chronolock-vetkd";
+
+// Keys of ADMINS
+const OWNER_KEY: u8 = 0;
+const PENDING_OWNER_KEY: u8 = 1;
+
+// Cycles accounting defaults
+const DEFAULT_LOW_WATER_MARK: u128 = 1_000_000_000_000;
+const DEFAULT_CYCLES_CHECK_INTERVAL_SECONDS: u64 = 6 * 60 * 60;
+// Covers a derivation with the production key; unused cycles are refunded
+const DEFAULT_VETKD_DERIVE_CYCLES: u64 = 30_000_000_000;
+// Number of balance samples kept to estimate the burn rate
+const MAX_CYCLES_SAMPLES: usize = 48;
+
+// Derivation pricing defaults
+const DEFAULT_FREE_DERIVATIONS_PER_LOCK: u32 = 3;
+
+// Rate-limited endpoints as (method, capacity, refill interval in seconds)
+const DEFAULT_RATE_LIMITS: &[(&str, u32, u64)] = &[
+    ("create_chronolock", 10, 60),
+    ("start_media_upload", 10, 60),
+    ("get_time_decryption_key", 30, 10),
+    ("get_user_time_decryption_key", 30, 10),
+];
+// Number of rate limiter buckets above which the stalest ones are evicted
+const MAX_RATE_BUCKETS: usize = 10_000;
+// Largest ingress argument accepted by inspect_message, in bytes
+const MAX_INGRESS_ARG_BYTES: u64 = 64 * 1024;
+
+// Version of the stable memory layout written by this code
+const CURRENT_SCHEMA_VERSION: u32 = 4;
+// Longest accepted collection symbol and name, and description (bytes)
+const MAX_COLLECTION_NAME_LENGTH: usize = 64;
+const MAX_COLLECTION_DESCRIPTION_LENGTH: usize = 1024;
+// Largest media file accepted by finish_media_upload and import_state
+const MAX_MEDIA_FILE_SIZE: usize = 10 * 1024 * 1024; // 10MB
+
+// Version of the record stream written by export_state
+const EXPORT_FORMAT_VERSION: u8 = 1;
+// Maximum number of records in an export page
+const MAX_EXPORT_PAGE_SIZE: u64 = 1_000;
+// Media files are exported in chunks of MEDIA_EXPORT_CHUNK_SIZE bytes, one per
+// page to stay below the 2MiB message limit. Chunk c of the i-th file is at
+// position i * MEDIA_EXPORT_CHUNKS_PER_FILE + c of the Media section.
+const MEDIA_EXPORT_CHUNK_SIZE: u64 = 1024 * 1024;
+const MEDIA_EXPORT_CHUNKS_PER_FILE: u64 = MAX_MEDIA_FILE_SIZE as u64 / MEDIA_EXPORT_CHUNK_SIZE;
+const MAX_MEDIA_EXPORT_PAGE_SIZE: u64 = 1;
 
 fn generate_unique_id() -> String {
     let current_time = time();
@@ -300,23 +1119,28 @@ async fn call_vetkd_derive_key(
 
     let management_canister = Principal::management_canister();
 
-    // VetKD operations require cycles to be sent with the call
-    // Based on the error message, approximately 26 billion cycles are needed
-    let cycles = 30_000_000_000u64; // 30 billion cycles for safety margin
+    // VetKD operations require cycles to be sent with the call; whatever the
+    // derivation does not use is refunded.
+    let cycles = CYCLES_CONFIG.with(|c| c.borrow().get().vetkd_derive_cycles);
 
-    let (result,): (VetKDDeriveKeyReply,) =
-        call_with_payment(management_canister, "vetkd_derive_key", (args,), cycles)
-            .await
-            .map_err(|e| ChronoError::InternalError(format!("Call failed: {:?}", e)))?;
+    let result: Result<(VetKDDeriveKeyReply,), _> =
+        call_with_payment(management_canister, "vetkd_derive_key", (args,), cycles).await;
+    record_cycles_spent(
+        "vetkd_derive_key",
+        (cycles as u128).saturating_sub(msg_cycles_refunded128()),
+    );
+    let (result,) =
+        result.map_err(|e| ChronoError::InternalError(format!("Call failed: {:?}", e)))?;
 
     Ok(result)
 }
 
 #[init]
-fn init(admin: Principal, network: Option<String>) {
+fn init(admin: Principal, network: Option<String>, ledger_id: Option<Principal>) {
     ADMINS.with(|admins| {
-        admins.borrow_mut().insert(0, admin);
+        admins.borrow_mut().insert(OWNER_KEY, admin);
     });
+    set_schema_version(CURRENT_SCHEMA_VERSION);
 
     if let Some(net) = network {
         NETWORK.with(|n| {
@@ -326,19 +1150,178 @@ fn init(admin: Principal, network: Option<String>) {
         });
     }
 
+    if ledger_id.is_some() {
+        LEDGER_CANISTER_ID.with(|l| {
+            l.borrow_mut()
+                .set(ledger_id)
+                .expect("Failed to set LEDGER_CANISTER_ID")
+        });
+    }
+
     log_activity(format!("Canister initialized with admin: {}", admin));
+    start_cycles_monitor();
 }
 
-fn is_admin(caller: Principal) -> bool {
-    ADMINS.with(|admins| admins.borrow().get(&0) == Some(caller))
+// Keep the rate limiter buckets, which live on the heap, across the upgrade.
+#[pre_upgrade]
+fn pre_upgrade() {
+    save_rate_limit_snapshot();
 }
 
-fn get_network() -> Option<String> {
-    NETWORK.with(|n| n.borrow().get().clone())
+#[post_upgrade]
+fn post_upgrade(args: Option<UpgradeArgs>) {
+    index_quarantine();
+    migrate_schema();
+    quarantine_unknown_tags();
+    if let Some(args) = args {
+        apply_upgrade_args(args);
+    }
+    // Timers do not survive upgrades
+    start_cycles_monitor();
+    restore_rate_limit_snapshot();
 }
 
-// -------------------------
-// Authentication Helper Functions
+fn schema_version() -> u32 {
+    SCHEMA_VERSION.with(|v| *v.borrow().get())
+}
+
+fn set_schema_version(version: u32) {
+    SCHEMA_VERSION.with(|v| {
+        v.borrow_mut()
+            .set(version)
+            .expect("Failed to set SCHEMA_VERSION")
+    });
+}
+
+// Brings stable memory written by an older version up to CURRENT_SCHEMA_VERSION,
+// one version at a time. Memory from a newer version traps, so that a
+// downgrade is rolled back instead of misreading data.
+fn migrate_schema() {
+    let from = schema_version();
+    if from > CURRENT_SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "Stable memory has schema version {}, this canister supports up to {}",
+            from, CURRENT_SCHEMA_VERSION
+        ));
+    }
+    for version in from..CURRENT_SCHEMA_VERSION {
+        match version {
+            // Version 1 keeps the collection metadata in stable memory. It used
+            // to be fixed on the heap, and the stored defaults are the same values.
+            0 => {}
+            // Version 2 prefixes stored values with their encoding version. Values
+            // without the prefix are read as version 0 and rewritten when updated.
+            1 => {}
+            // Version 3 records who created a chronolock. Creators were not kept
+            // before, so the owners of the existing chronolocks stand in for them.
+            2 => OWNER_TO_TOKENS.with(|owners| {
+                for (owner, list) in owners.borrow().iter() {
+                    if !list.tokens.is_empty() {
+                        LEGACY_CREATORS.with(|c| c.borrow_mut().insert(owner, ()));
+                    }
+                }
+            }),
+            // Version 4 records when each creator created its first chronolock.
+            3 => migrate_creators(),
+            _ => unreachable!("No migration from schema version {}", version),
+        }
+        set_schema_version(version + 1);
+    }
+    if from < CURRENT_SCHEMA_VERSION {
+        log_activity(format!(
+            "Schema migrated from version {} to {}",
+            from, CURRENT_SCHEMA_VERSION
+        ));
+    }
+}
+
+// Gives each creator recorded without a time the creation time of the oldest
+// chronolock it still owns, or zero when it owns none, and empties the legacy
+// set. Referrals claimed later are not qualified by these creations.
+fn migrate_creators() {
+    let creators: Vec<Principal> =
+        LEGACY_CREATORS.with(|c| c.borrow().iter().map(|(creator, _)| creator).collect());
+    for creator in creators {
+        let tokens = OWNER_TO_TOKENS
+            .with(|owners| owners.borrow().get(&creator))
+            .map(|list| list.tokens)
+            .unwrap_or_default();
+        let first_created_at = CHRONOLOCKS.with(|locks| {
+            let locks = locks.borrow();
+            tokens
+                .iter()
+                .filter_map(|id| locks.get(id))
+                .map(|lock| lock.created_at.saturating_mul(1_000_000))
+                .min()
+                .unwrap_or(0)
+        });
+        CREATORS.with(|c| c.borrow_mut().insert(creator, first_created_at));
+    }
+    LEGACY_CREATORS.with(|c| {
+        *c.borrow_mut() =
+            StableBTreeMap::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))));
+    });
+}
+
+fn apply_upgrade_args(args: UpgradeArgs) {
+    if let Some(network) = args.network {
+        NETWORK.with(|n| {
+            n.borrow_mut()
+                .set(Some(network.clone()))
+                .expect("Failed to set NETWORK")
+        });
+        log_activity(format!("Network set to {}", network));
+    }
+    if let Some(ledger_id) = args.ledger_id {
+        LEDGER_CANISTER_ID.with(|l| {
+            l.borrow_mut()
+                .set(Some(ledger_id))
+                .expect("Failed to set LEDGER_CANISTER_ID")
+        });
+        log_activity(format!("Ledger canister set to {}", ledger_id));
+    }
+    if let Some(new_size) = args.max_metadata_size {
+        MAX_METADATA_SIZE.with(|size| {
+            size.borrow_mut()
+                .set(new_size)
+                .expect("Failed to set MAX_METADATA_SIZE")
+        });
+        log_activity(format!("MAX_METADATA_SIZE updated to {}", new_size));
+    }
+    if let Some(price) = args.mint_price {
+        MINT_PRICE.with(|p| p.borrow_mut().set(price).expect("Failed to set MINT_PRICE"));
+        log_activity(format!("Mint price set to {}", price));
+    }
+}
+
+#[query]
+fn get_schema_version() -> u32 {
+    schema_version()
+}
+
+fn owner_principal() -> Option<Principal> {
+    ADMINS.with(|admins| admins.borrow().get(&OWNER_KEY))
+}
+
+fn has_role(principal: Principal, role: Role) -> bool {
+    if owner_principal() == Some(principal) {
+        return true;
+    }
+    role != Role::Owner && ROLE_MEMBERS.with(|r| r.borrow().contains_key(&(role.tag(), principal)))
+}
+
+// Only the owner is exempt from rate limits; other roles, read-only ones
+// included, are treated like any caller.
+fn is_admin(caller: Principal) -> bool {
+    owner_principal() == Some(caller)
+}
+
+fn get_network() -> Option<String> {
+    NETWORK.with(|n| n.borrow().get().clone())
+}
+
+// -------------------------
+// Authentication Helper Functions
 // -------------------------
 
 // Check if a principal is a valid Internet Identity principal
@@ -441,22 +1424,191 @@ fn validate_caller_authentication() -> Result<Principal, ChronoError> {
     Ok(caller)
 }
 
-// Validate admin authentication (stricter requirements)
-fn validate_admin_authentication() -> Result<Principal, ChronoError> {
+// Validate that the caller holds the role required by an admin operation
+fn validate_role_authentication(role: Role) -> Result<Principal, ChronoError> {
     let caller = validate_caller_authentication()?;
 
-    if !is_admin(caller) {
-        log_activity(format!("Unauthorized admin access attempt: {}", caller));
+    if !has_role(caller, role) {
+        log_activity(format!(
+            "Unauthorized admin access attempt ({:?} role): {}",
+            role, caller
+        ));
         return Err(ChronoError::AdminRequired);
     }
 
     Ok(caller)
 }
 
+// -------------------------
+// Payments
+// -------------------------
+
+// Pulls a price from the caller's ICRC-2 allowance on the ledger into this
+// canister's account. Returns None when the price is zero.
+async fn collect_payment(
+    payer: Principal,
+    amount: u64,
+    memo: &'static [u8],
+) -> Result<Option<Payment>, ChronoError> {
+    if amount == 0 {
+        return Ok(None);
+    }
+    let ledger_id = LEDGER_CANISTER_ID
+        .with(|l| *l.borrow().get())
+        .ok_or_else(|| ChronoError::PaymentFailed("Ledger canister not configured".to_string()))?;
+
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: payer,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(memo.to_vec()),
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger_id, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|e| ChronoError::PaymentFailed(format!("Call failed: {:?}", e)))?;
+    let block_index =
+        result.map_err(|e| ChronoError::PaymentFailed(format!("Transfer failed: {:?}", e)))?;
+
+    log_activity(format!(
+        "Payment of {} from {} at block {}",
+        amount, payer, block_index
+    ));
+    Ok(Some(Payment {
+        ledger_id,
+        amount,
+        memo,
+    }))
+}
+
+// Returns a collected payment, minus the ledger fee of the refund transfer.
+async fn refund_payment(payer: Principal, payment: Payment) {
+    let fee: Result<(Nat,), _> = ic_cdk::call(payment.ledger_id, "icrc1_fee", ()).await;
+    let refund = match fee {
+        Ok((fee,)) if fee < Nat::from(payment.amount) => Nat::from(payment.amount) - fee,
+        Ok(_) => {
+            log_activity(format!("Payment of {} too small to refund", payment.amount));
+            return;
+        }
+        Err(e) => {
+            log_activity(format!("Refund to {} failed: {:?}", payer, e));
+            return;
+        }
+    };
+
+    let args = TransferArgs {
+        from_subaccount: None,
+        to: Account {
+            owner: payer,
+            subaccount: None,
+        },
+        amount: refund.clone(),
+        fee: None,
+        memo: Some(payment.memo.to_vec()),
+        created_at_time: None,
+    };
+    let result: Result<(Result<Nat, TransferError>,), _> =
+        ic_cdk::call(payment.ledger_id, "icrc1_transfer", (args,)).await;
+    match result {
+        Ok((Ok(block_index),)) => log_activity(format!(
+            "Refunded {} to {} at block {}",
+            refund, payer, block_index
+        )),
+        Ok((Err(e),)) => log_activity(format!("Refund to {} failed: {:?}", payer, e)),
+        Err(e) => log_activity(format!("Refund to {} failed: {:?}", payer, e)),
+    }
+}
+
+// Tells the ledger that the owner created their first chronolock, which
+// qualifies a pending referral of theirs for its rewards. The ledger only
+// accepts the report while this canister is the qualifier of the campaign. A
+// report that fails is logged; the referee can still collect the rewards with
+// the ledger's claim_referral_rewards, which asks has_qualifying_action.
+async fn report_qualifying_action(owner: Principal) {
+    let Some(ledger_id) = LEDGER_CANISTER_ID.with(|l| *l.borrow().get()) else {
+        return;
+    };
+    // The ledger records referrals for the referee's default account.
+    let referee = Account {
+        owner,
+        subaccount: None,
+    };
+    let result: Result<(Result<bool, LedgerError>,), _> =
+        ic_cdk::call(ledger_id, "record_qualifying_action", (referee,)).await;
+    match result {
+        Ok((Ok(true),)) => log_activity(format!("Referral of {} qualified", owner)),
+        Ok((Ok(false),)) => {}
+        Ok((Err(e),)) => log_activity(format!(
+            "Qualifying action of {} refused by the ledger: {:?}",
+            owner, e
+        )),
+        Err(e) => log_activity(format!(
+            "Qualifying action of {} not reported: {:?}",
+            owner, e
+        )),
+    }
+}
+
+fn is_creator(principal: Principal) -> bool {
+    CREATORS.with(|c| c.borrow().contains_key(&principal))
+}
+
+// Whether the principal created its first chronolock at or after since, in
+// nanoseconds. Asked by the ledger, with the time the referral was claimed,
+// when a referee claims the rewards of a referral qualified by this canister;
+// a chronolock created before the referral does not qualify it.
+#[query]
+fn has_qualifying_action(principal: Principal, since: u64) -> bool {
+    CREATORS
+        .with(|c| c.borrow().get(&principal))
+        .is_some_and(|first_created_at| first_created_at >= since)
+}
+
+#[update]
+fn set_ledger_canister_id(ledger_id: Principal) -> Result<(), ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    LEDGER_CANISTER_ID.with(|l| {
+        l.borrow_mut()
+            .set(Some(ledger_id))
+            .expect("Failed to set LEDGER_CANISTER_ID")
+    });
+    log_activity(format!("Ledger canister set to {}", ledger_id));
+    Ok(())
+}
+
+#[update]
+fn set_mint_price(price: u64) -> Result<(), ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    MINT_PRICE.with(|p| p.borrow_mut().set(price).expect("Failed to set MINT_PRICE"));
+    log_activity(format!("Mint price set to {}", price));
+    Ok(())
+}
+
+#[query]
+fn get_ledger_canister_id() -> Option<Principal> {
+    LEDGER_CANISTER_ID.with(|l| *l.borrow().get())
+}
+
+#[query]
+fn get_mint_price() -> u64 {
+    MINT_PRICE.with(|p| *p.borrow().get())
+}
+
 #[update]
 fn set_max_metadata_size(new_size: u64) -> Result<(), ChronoError> {
     // Validate admin authentication
-    let _authenticated_admin = validate_admin_authentication()?;
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
     MAX_METADATA_SIZE.with(|size| {
         size.borrow_mut()
             .set(new_size)
@@ -468,8 +1620,8 @@ fn set_max_metadata_size(new_size: u64) -> Result<(), ChronoError> {
 
 #[query]
 fn get_logs_paginated(offset: u64, limit: u64) -> Result<Vec<LogEntry>, ChronoError> {
-    // Validate admin authentication for log access
-    let _authenticated_admin = validate_admin_authentication()?;
+    // Validate auditor authentication for log access
+    let _authenticated_admin = validate_role_authentication(Role::Auditor)?;
     Ok(LOGS.with(|logs| {
         logs.borrow()
             .iter()
@@ -482,8 +1634,8 @@ fn get_logs_paginated(offset: u64, limit: u64) -> Result<Vec<LogEntry>, ChronoEr
 
 #[query]
 fn get_logs_by_range(start_time: u64, end_time: u64) -> Result<Vec<LogEntry>, ChronoError> {
-    // Validate admin authentication for log access
-    let _authenticated_admin = validate_admin_authentication()?;
+    // Validate auditor authentication for log access
+    let _authenticated_admin = validate_role_authentication(Role::Auditor)?;
     Ok(LOGS.with(|logs| {
         logs.borrow()
             .iter()
@@ -493,19 +1645,53 @@ fn get_logs_by_range(start_time: u64, end_time: u64) -> Result<Vec<LogEntry>, Ch
     }))
 }
 
+fn collection_metadata() -> CollectionMetadata {
+    COLLECTION_METADATA.with(|m| m.borrow().get().clone())
+}
+
+#[update]
+fn set_collection_metadata(metadata: CollectionMetadata) -> Result<(), ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    if metadata.symbol.trim().is_empty() || metadata.name.trim().is_empty() {
+        return Err(ChronoError::InvalidInput(
+            "Symbol and name cannot be empty".to_string(),
+        ));
+    }
+    if metadata.symbol.len() > MAX_COLLECTION_NAME_LENGTH
+        || metadata.name.len() > MAX_COLLECTION_NAME_LENGTH
+        || metadata.description.len() > MAX_COLLECTION_DESCRIPTION_LENGTH
+    {
+        return Err(ChronoError::InvalidInput(format!(
+            "Symbol and name are limited to {} bytes, the description to {}",
+            MAX_COLLECTION_NAME_LENGTH, MAX_COLLECTION_DESCRIPTION_LENGTH
+        )));
+    }
+    log_activity(format!(
+        "Collection metadata set: {} ({})",
+        metadata.name, metadata.symbol
+    ));
+    COLLECTION_METADATA.with(|m| {
+        m.borrow_mut()
+            .set(metadata)
+            .expect("Failed to set COLLECTION_METADATA")
+    });
+    Ok(())
+}
+
 #[query]
 fn icrc7_symbol() -> String {
-    SYMBOL.with(|s| s.borrow().clone())
+    collection_metadata().symbol
 }
 
 #[query]
 fn icrc7_name() -> String {
-    NAME.with(|n| n.borrow().clone())
+    collection_metadata().name
 }
 
 #[query]
 fn icrc7_description() -> String {
-    DESCRIPTION.with(|d| d.borrow().clone())
+    collection_metadata().description
 }
 
 #[query]
@@ -544,6 +1730,7 @@ fn icrc7_token_metadata(token_id: String) -> Option<Chronolock> {
 fn icrc7_transfer(token_id: String, to: Principal) -> Result<(), ChronoError> {
     // Validate caller authentication
     let authenticated_caller = validate_caller_authentication()?;
+    check_subsystem(Subsystem::Transfer)?;
     CHRONOLOCKS.with(|locks| {
         OWNER_TO_TOKENS.with(|owner_to_tokens| {
             let mut locks = locks.borrow_mut();
@@ -600,10 +1787,13 @@ async fn ibe_encryption_key() -> Result<VetKDPublicKeyReply, ChronoError> {
     Ok(result)
 }
 
+// Derivations may be priced; passing the lock's token id lets its owner and
+// recipients use their free derivations first.
 #[update]
 async fn get_time_decryption_key(
     unlock_time_hex: String,
     transport_public_key: Vec<u8>,
+    token_id: Option<String>,
 ) -> Result<VetKDDeriveKeyReply, ChronoError> {
     if transport_public_key.is_empty() {
         return Err(ChronoError::InvalidInput(
@@ -628,9 +1818,20 @@ async fn get_time_decryption_key(
     // Use IBE identity format for VetKD derivation to ensure compatibility
     // For public chronolocks, IBE identity is just the decimal time string
     let input = unlock_time.to_string().into_bytes();
-    let context = b"chronolock-encryption".to_vec();
 
-    call_vetkd_derive_key(input, context, transport_public_key).await
+    check_subsystem(Subsystem::KeyDerivation)?;
+    let caller = caller();
+    // Public time keys may be derived anonymously. Anonymous callers all share
+    // one principal and cannot pay, so they are not rate-limited per principal
+    // and are only served while derivations are free.
+    if caller == Principal::anonymous() {
+        if derivation_pricing().price > 0 {
+            return Err(ChronoError::NotAuthenticated);
+        }
+    } else {
+        consume_rate_limit(caller, "get_time_decryption_key")?;
+    }
+    charged_vetkd_derive_key(caller, token_id, unlock_time, input, transport_public_key).await
 }
 
 #[update]
@@ -638,6 +1839,7 @@ async fn get_user_time_decryption_key(
     unlock_time_hex: String,
     user_id: String,
     transport_public_key: Vec<u8>,
+    token_id: Option<String>,
 ) -> Result<VetKDDeriveKeyReply, ChronoError> {
     if transport_public_key.is_empty() {
         return Err(ChronoError::InvalidInput(
@@ -653,6 +1855,8 @@ async fn get_user_time_decryption_key(
     if authenticated_caller != authorized_principal {
         return Err(ChronoError::Unauthorized);
     }
+    check_subsystem(Subsystem::KeyDerivation)?;
+    consume_rate_limit(authenticated_caller, "get_user_time_decryption_key")?;
 
     let unlock_time = u64::from_str_radix(&unlock_time_hex, 16)
         .map_err(|e| ChronoError::InvalidInput(format!("Invalid hex: {}", e)))?;
@@ -666,13 +1870,37 @@ async fn get_user_time_decryption_key(
     // For private chronolocks, IBE identity is "user_id:decimal_time"
     let combined_id = format!("{}:{}", user_id, unlock_time);
     let input = combined_id.into_bytes();
-    let context = b"chronolock-encryption".to_vec();
 
-    call_vetkd_derive_key(input, context, transport_public_key).await
+    charged_vetkd_derive_key(
+        authenticated_caller,
+        token_id,
+        unlock_time,
+        input,
+        transport_public_key,
+    )
+    .await
+}
+
+fn validate_metadata_size(
+    user_keys: &[UserKey],
+    encrypted_metadata: &str,
+) -> Result<(), ChronoError> {
+    let metadata_size = encrypted_metadata.len()
+        + user_keys
+            .iter()
+            .map(|uk| uk.user.len() + uk.key.len())
+            .sum::<usize>();
+    let max_size = MAX_METADATA_SIZE.with(|size| *size.borrow().get());
+    if metadata_size as u64 > max_size {
+        return Err(ChronoError::MetadataTooLarge);
+    }
+    Ok(())
 }
 
+// Creating a chronolock charges the mint price to the caller's allowance first;
+// if the chronolock can't be stored afterwards, the payment is refunded.
 #[update]
-fn create_chronolock(
+async fn create_chronolock(
     title: String,
     unlock_time: u64,
     user_keys: Vec<UserKey>,
@@ -680,24 +1908,47 @@ fn create_chronolock(
 ) -> Result<String, ChronoError> {
     // Validate caller authentication
     let authenticated_caller = validate_caller_authentication()?;
+    check_subsystem(Subsystem::Creation)?;
+    consume_rate_limit(authenticated_caller, "create_chronolock")?;
 
     // Validate metadata size (encrypted_metadata + user_keys)
-    let metadata_size = encrypted_metadata.len()
-        + user_keys
-            .iter()
-            .map(|uk| uk.user.len() + uk.key.len())
-            .sum::<usize>();
-    let max_size = MAX_METADATA_SIZE.with(|size| *size.borrow().get());
-    if metadata_size as u64 > max_size {
-        return Err(ChronoError::MetadataTooLarge);
+    validate_metadata_size(&user_keys, &encrypted_metadata)?;
+
+    let price = MINT_PRICE.with(|p| *p.borrow().get());
+    let payment = collect_payment(authenticated_caller, price, MINT_PAYMENT_MEMO).await?;
+
+    let first_chronolock = !is_creator(authenticated_caller);
+    let result = store_chronolock(
+        authenticated_caller,
+        title,
+        unlock_time,
+        user_keys,
+        encrypted_metadata,
+    );
+    match (&result, payment) {
+        (Err(_), Some(payment)) => refund_payment(authenticated_caller, payment).await,
+        (Ok(_), _) if first_chronolock => report_qualifying_action(authenticated_caller).await,
+        _ => {}
     }
+    result
+}
+
+fn store_chronolock(
+    owner: Principal,
+    title: String,
+    unlock_time: u64,
+    user_keys: Vec<UserKey>,
+    encrypted_metadata: String,
+) -> Result<String, ChronoError> {
+    // The size limit may have changed while the payment was in flight
+    validate_metadata_size(&user_keys, &encrypted_metadata)?;
 
     let id = generate_unique_id();
     let created_at = time() / 1_000_000; // Convert nanoseconds to milliseconds
 
     let chronolock = Chronolock {
         id: id.clone(),
-        owner: authenticated_caller,
+        owner,
         title,
         unlock_time,
         created_at,
@@ -711,12 +1962,15 @@ fn create_chronolock(
     OWNER_TO_TOKENS.with(|owner_to_tokens| {
         let mut owner_to_tokens = owner_to_tokens.borrow_mut();
         let mut tokens = owner_to_tokens
-            .get(&authenticated_caller)
+            .get(&owner)
             .map(|t| t.clone())
             .unwrap_or(TokenList { tokens: vec![] });
         tokens.tokens.push(id.clone());
-        owner_to_tokens.insert(authenticated_caller, tokens);
+        owner_to_tokens.insert(owner, tokens);
     });
+    if !is_creator(owner) {
+        CREATORS.with(|c| c.borrow_mut().insert(owner, time()));
+    }
     log_activity(format!("Chronolock created with ID: {}", id));
     Ok(id)
 }
@@ -731,6 +1985,7 @@ fn update_chronolock(
 ) -> Result<(), ChronoError> {
     // Validate caller authentication
     let authenticated_caller = validate_caller_authentication()?;
+    check_writable()?;
     CHRONOLOCKS.with(|locks| {
         let mut locks = locks.borrow_mut();
         let mut lock = locks
@@ -776,15 +2031,16 @@ fn update_chronolock(
 fn burn_chronolock(token_id: String) -> Result<(), ChronoError> {
     // Validate caller authentication
     let authenticated_caller = validate_caller_authentication()?;
+    check_writable()?;
     CHRONOLOCKS.with(|locks| {
         OWNER_TO_TOKENS.with(|owner_to_tokens| {
             let mut locks = locks.borrow_mut();
             let mut owner_to_tokens = owner_to_tokens.borrow_mut();
             let lock = locks.get(&token_id).ok_or(ChronoError::TokenNotFound)?;
 
-            // Allow owner or admin to burn
+            // Allow owner or moderator to burn
             let is_owner = lock.owner == authenticated_caller;
-            let caller_is_admin = is_admin(authenticated_caller);
+            let caller_is_admin = has_role(authenticated_caller, Role::Moderator);
 
             if !is_owner && !caller_is_admin {
                 return Err(ChronoError::Unauthorized);
@@ -808,7 +2064,9 @@ fn burn_chronolock(token_id: String) -> Result<(), ChronoError> {
 #[update]
 fn start_media_upload(total_chunks: u32) -> Result<String, ChronoError> {
     // Validate caller authentication
-    let _authenticated_caller = validate_caller_authentication()?;
+    let authenticated_caller = validate_caller_authentication()?;
+    check_subsystem(Subsystem::MediaUpload)?;
+    consume_rate_limit(authenticated_caller, "start_media_upload")?;
 
     let media_id = generate_unique_id();
     MEDIA_UPLOADS.with(|uploads| {
@@ -836,6 +2094,7 @@ fn upload_media_chunk(
 ) -> Result<u32, ChronoError> {
     // Validate caller authentication
     let _authenticated_caller = validate_caller_authentication()?;
+    check_subsystem(Subsystem::MediaUpload)?;
 
     const MAX_CHUNK_SIZE: usize = 2 * 1024 * 1024; // 2MB
     if chunk.len() > MAX_CHUNK_SIZE {
@@ -871,8 +2130,8 @@ fn upload_media_chunk(
 fn finish_media_upload(media_id: String) -> Result<String, ChronoError> {
     // Validate caller authentication
     let _authenticated_caller = validate_caller_authentication()?;
+    check_subsystem(Subsystem::MediaUpload)?;
 
-    const MAX_FILE_SIZE: usize = 10 * 1024 * 1024; // 10MB
     MEDIA_UPLOADS.with(|uploads| {
         let mut uploads = uploads.borrow_mut();
         let MediaUploadState {
@@ -895,10 +2154,10 @@ fn finish_media_upload(media_id: String) -> Result<String, ChronoError> {
                 .ok_or_else(|| ChronoError::InvalidInput("Missing chunk in upload".to_string()))?;
             file_data.extend_from_slice(&chunk);
         }
-        if file_data.len() > MAX_FILE_SIZE {
+        if file_data.len() > MAX_MEDIA_FILE_SIZE {
             return Err(ChronoError::InvalidInput(format!(
                 "File size exceeds maximum of {} bytes",
-                MAX_FILE_SIZE
+                MAX_MEDIA_FILE_SIZE
             )));
         }
         MEDIA_FILES.with(|media| {
@@ -1145,6 +2404,1081 @@ fn get_user_accessible_chronolocks_paginated(
     Ok(accessible_chronolocks)
 }
 
+// -------------------------
+// Rate Limiting
+// -------------------------
+
+// Limit of a rate-limited method, None for methods that are not limited.
+fn configured_rate_limit(method: &str) -> Option<RateLimit> {
+    let (_, capacity, refill_interval) = DEFAULT_RATE_LIMITS
+        .iter()
+        .find(|(name, _, _)| *name == method)?;
+    Some(
+        RATE_LIMITS
+            .with(|limits| limits.borrow().get(&method.to_string()))
+            .unwrap_or(RateLimit {
+                capacity: *capacity,
+                refill_interval: *refill_interval,
+            }),
+    )
+}
+
+// Adds the calls regained since the bucket was last updated.
+fn refill_bucket(bucket: &mut TokenBucket, limit: &RateLimit, now: u64) {
+    let regained = now.saturating_sub(bucket.updated_at) / limit.refill_interval;
+    let tokens = (bucket.tokens as u64).saturating_add(regained);
+    if tokens >= limit.capacity as u64 {
+        bucket.tokens = limit.capacity;
+        bucket.updated_at = now;
+    } else {
+        bucket.tokens = tokens as u32;
+        bucket.updated_at += regained * limit.refill_interval;
+    }
+}
+
+// True for buckets that have refilled completely, as they hold no state.
+fn is_full_bucket(method: &str, bucket: &TokenBucket, now: u64) -> bool {
+    !configured_rate_limit(method)
+        .filter(|limit| limit.capacity > 0)
+        .is_some_and(|limit| {
+            let mut bucket = bucket.clone();
+            refill_bucket(&mut bucket, &limit, now);
+            bucket.tokens < limit.capacity
+        })
+}
+
+// Takes one call from the caller's bucket for the method. Admins are never
+// limited.
+fn consume_rate_limit(caller: Principal, method: &str) -> Result<(), ChronoError> {
+    let Some(limit) = configured_rate_limit(method).filter(|limit| limit.capacity > 0) else {
+        return Ok(());
+    };
+    if is_admin(caller) {
+        return Ok(());
+    }
+    let now = time() / 1_000_000_000;
+    let key = (caller, method.to_string());
+    RATE_BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        let mut bucket = buckets.get(&key).cloned().unwrap_or(TokenBucket {
+            tokens: limit.capacity,
+            updated_at: now,
+        });
+        refill_bucket(&mut bucket, &limit, now);
+        if bucket.tokens == 0 {
+            return Err(ChronoError::RateLimited {
+                retry_after: bucket.updated_at + limit.refill_interval - now,
+            });
+        }
+        bucket.tokens -= 1;
+        buckets.insert(key, bucket);
+        Ok(())
+    })
+}
+
+// Saves the buckets that are not full. The snapshot is bounded by
+// MAX_RATE_BUCKETS; if it cannot be written the buckets are dropped rather than
+// failing the upgrade.
+fn save_rate_limit_snapshot() {
+    let now = time() / 1_000_000_000;
+    let buckets = RATE_BUCKETS.with(|buckets| {
+        buckets
+            .borrow()
+            .buckets
+            .iter()
+            .filter(|((_, method), bucket)| !is_full_bucket(method, bucket, now))
+            .map(|((caller, method), bucket)| (*caller, method.clone(), bucket.clone()))
+            .collect()
+    });
+    let result = RATE_LIMIT_SNAPSHOT.with(|snapshot| {
+        snapshot
+            .borrow_mut()
+            .set(RateLimitSnapshot { buckets })
+            .map(|_| ())
+    });
+    if let Err(err) = result {
+        log_activity(format!("Failed to save rate limiter buckets: {:?}", err));
+    }
+}
+
+fn restore_rate_limit_snapshot() {
+    let snapshot = RATE_LIMIT_SNAPSHOT.with(|snapshot| snapshot.borrow().get().clone());
+    RATE_BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        *buckets = RateBuckets::new();
+        for (caller, method, bucket) in snapshot.buckets {
+            buckets.insert((caller, method), bucket);
+        }
+    });
+}
+
+// Largest accepted argument of an ingress call; None leaves it to the system
+// limit. Lock contents may grow with the configured metadata size.
+fn max_ingress_arg_bytes(method: &str) -> Option<u64> {
+    match method {
+        "upload_media_chunk" | "import_state" => None,
+        "create_chronolock" | "update_chronolock" => {
+            Some(MAX_METADATA_SIZE.with(|size| *size.borrow().get()) + MAX_INGRESS_ARG_BYTES)
+        }
+        _ => Some(MAX_INGRESS_ARG_BYTES),
+    }
+}
+
+// Rejects ingress that would fail anyway before it is executed and charged:
+// oversized arguments and anonymous calls to rate-limited methods that require
+// authentication. Public time keys may still be derived anonymously while
+// derivations are free. Callers that run out of calls get RateLimited from the
+// method itself.
+#[inspect_message]
+fn inspect_message() {
+    let method = method_name();
+    if max_ingress_arg_bytes(&method).is_some_and(|max| arg_data_raw_size() as u64 > max) {
+        return;
+    }
+    if configured_rate_limit(&method).is_some()
+        && (method != "get_time_decryption_key" || derivation_pricing().price > 0)
+        && caller() == Principal::anonymous()
+    {
+        return;
+    }
+    accept_message();
+}
+
+#[update]
+fn set_rate_limit(method: String, limit: Option<RateLimit>) -> Result<(), ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Moderator)?;
+    if configured_rate_limit(&method).is_none() {
+        return Err(ChronoError::InvalidInput(format!(
+            "{} is not rate-limited",
+            method
+        )));
+    }
+    match &limit {
+        Some(limit) if limit.refill_interval == 0 => {
+            return Err(ChronoError::InvalidInput(
+                "Refill interval must be positive".to_string(),
+            ));
+        }
+        Some(limit) => {
+            RATE_LIMITS.with(|limits| limits.borrow_mut().insert(method.clone(), limit.clone()));
+        }
+        None => {
+            RATE_LIMITS.with(|limits| limits.borrow_mut().remove(&method));
+        }
+    }
+    log_activity(match limit {
+        Some(limit) => format!(
+            "Rate limit of {}: {} per {}s",
+            method, limit.capacity, limit.refill_interval
+        ),
+        None => format!("Rate limit of {} reset", method),
+    });
+    Ok(())
+}
+
+// Limits in force for every rate-limited method
+#[query]
+fn get_rate_limits() -> Vec<(String, RateLimit)> {
+    DEFAULT_RATE_LIMITS
+        .iter()
+        .filter_map(|(method, _, _)| {
+            configured_rate_limit(method).map(|limit| (method.to_string(), limit))
+        })
+        .collect()
+}
+
+// -------------------------
+// Derivation Pricing
+// -------------------------
+
+fn derivation_pricing() -> DerivationPricing {
+    DERIVATION_PRICING.with(|p| p.borrow().get().clone())
+}
+
+// True if the principal owns the lock or is one of its listed recipients.
+fn is_lock_participant(lock: &Chronolock, principal: Principal) -> bool {
+    let user = principal.to_text();
+    let user_time_key = format!("{}:{}", user, lock.unlock_time);
+    lock.owner == principal
+        || lock
+            .user_keys
+            .iter()
+            .any(|key| key.user == user || key.user == user_time_key)
+}
+
+fn free_derivations_key(token_id: &str, user: Principal) -> String {
+    format!("{}:{}", token_id, user)
+}
+
+// Uses one of the caller's free derivations on the lock when it has any left
+// for that unlock time, otherwise collects the derivation price.
+async fn charge_derivation(
+    caller: Principal,
+    token_id: Option<String>,
+    unlock_time: u64,
+    pricing: &DerivationPricing,
+) -> Result<DerivationCharge, ChronoError> {
+    if pricing.price == 0 {
+        return Ok(DerivationCharge::Paid(None));
+    }
+    if let Some(token_id) = token_id {
+        let lock = CHRONOLOCKS
+            .with(|locks| locks.borrow().get(&token_id))
+            .ok_or(ChronoError::TokenNotFound)?;
+        if lock.unlock_time == unlock_time && is_lock_participant(&lock, caller) {
+            let usage_key = free_derivations_key(&token_id, caller);
+            let used = FREE_DERIVATIONS_USED.with(|u| u.borrow().get(&usage_key).unwrap_or(0));
+            if used < pricing.free_per_lock {
+                FREE_DERIVATIONS_USED.with(|u| u.borrow_mut().insert(usage_key.clone(), used + 1));
+                return Ok(DerivationCharge::Free { usage_key });
+            }
+        }
+    }
+    collect_payment(caller, pricing.price, DERIVATION_PAYMENT_MEMO)
+        .await
+        .map(DerivationCharge::Paid)
+}
+
+// Gives back the free derivation or the payment of a failed derivation.
+async fn release_derivation_charge(caller: Principal, charge: DerivationCharge) {
+    match charge {
+        DerivationCharge::Free { usage_key } => FREE_DERIVATIONS_USED.with(|u| {
+            let mut used = u.borrow_mut();
+            if let Some(count) = used.get(&usage_key) {
+                used.insert(usage_key, count.saturating_sub(1));
+            }
+        }),
+        DerivationCharge::Paid(Some(payment)) => refund_payment(caller, payment).await,
+        DerivationCharge::Paid(None) => {}
+    }
+}
+
+// Charges a derivation before deriving the key; the calling endpoint has
+// already taken it from the caller's rate limit.
+async fn charged_vetkd_derive_key(
+    caller: Principal,
+    token_id: Option<String>,
+    unlock_time: u64,
+    input: Vec<u8>,
+    transport_public_key: Vec<u8>,
+) -> Result<VetKDDeriveKeyReply, ChronoError> {
+    let pricing = derivation_pricing();
+    let charge = charge_derivation(caller, token_id, unlock_time, &pricing).await?;
+    let context = b"chronolock-encryption".to_vec();
+    let result = call_vetkd_derive_key(input, context, transport_public_key).await;
+    if result.is_err() {
+        release_derivation_charge(caller, charge).await;
+    }
+    result
+}
+
+#[update]
+fn set_derivation_pricing(pricing: DerivationPricing) -> Result<(), ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    DERIVATION_PRICING.with(|p| {
+        p.borrow_mut()
+            .set(pricing.clone())
+            .expect("Failed to set DERIVATION_PRICING")
+    });
+    log_activity(format!(
+        "Derivation price {}, {} free",
+        pricing.price, pricing.free_per_lock
+    ));
+    Ok(())
+}
+
+#[query]
+fn get_derivation_pricing() -> DerivationPricing {
+    derivation_pricing()
+}
+
+// Free derivations the user has left on a lock; zero for non-participants.
+#[query]
+fn get_free_derivations_remaining(token_id: String, user: Principal) -> u32 {
+    let Some(lock) = CHRONOLOCKS.with(|locks| locks.borrow().get(&token_id)) else {
+        return 0;
+    };
+    if !is_lock_participant(&lock, user) {
+        return 0;
+    }
+    let used = FREE_DERIVATIONS_USED.with(|u| {
+        u.borrow()
+            .get(&free_derivations_key(&token_id, user))
+            .unwrap_or(0)
+    });
+    derivation_pricing().free_per_lock.saturating_sub(used)
+}
+
+// -------------------------
+// Cycles Accounting
+// -------------------------
+
+fn cycles_config() -> CyclesConfig {
+    CYCLES_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn cycles_history() -> CyclesHistory {
+    CYCLES_HISTORY.with(|h| h.borrow().get().clone())
+}
+
+fn set_cycles_history(history: CyclesHistory) {
+    CYCLES_HISTORY.with(|h| {
+        h.borrow_mut()
+            .set(history)
+            .expect("Failed to set CYCLES_HISTORY")
+    });
+}
+
+fn record_cycles_spent(operation: &str, cycles: u128) {
+    CYCLES_SPENT.with(|spent| {
+        let mut spent = spent.borrow_mut();
+        let mut stats = spent.get(&operation.to_string()).unwrap_or_default();
+        stats.calls += 1;
+        stats.cycles = stats.cycles.saturating_add(cycles);
+        spent.insert(operation.to_string(), stats);
+    });
+}
+
+// Cycles burned per day between the oldest and newest sample. Increases from
+// top-ups are not counted as negative burn.
+fn burn_rate_per_day(samples: &[(u64, u128)]) -> u128 {
+    let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+        return 0;
+    };
+    let elapsed = last.0.saturating_sub(first.0);
+    if elapsed == 0 {
+        return 0;
+    }
+    let burned: u128 = samples
+        .windows(2)
+        .map(|pair| pair[0].1.saturating_sub(pair[1].1))
+        .sum();
+    burned.saturating_mul(24 * 60 * 60) / elapsed as u128
+}
+
+// (Re)starts the periodic balance check with the configured interval.
+fn start_cycles_monitor() {
+    let interval = Duration::from_secs(cycles_config().check_interval.max(1));
+    let timer_id = ic_cdk_timers::set_timer_interval(interval, || ic_cdk::spawn(check_cycles()));
+    if let Some(previous) = CYCLES_TIMER.with(|t| t.borrow_mut().replace(timer_id)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+// Records the balance and, below the low-water mark, raises an alert and asks
+// the ledger for a top-up.
+async fn check_cycles() {
+    let balance = canister_balance128();
+    let mut history = cycles_history();
+    history.samples.push((time() / 1_000_000_000, balance));
+    if history.samples.len() > MAX_CYCLES_SAMPLES {
+        history.samples.remove(0);
+    }
+    set_cycles_history(history);
+
+    let config = cycles_config();
+    if balance >= config.low_water_mark {
+        return;
+    }
+    log_activity(format!(
+        "Low cycles: {} below {}",
+        balance, config.low_water_mark
+    ));
+    if config.top_up_amount == 0 || TOP_UP_IN_FLIGHT.with(|f| f.replace(true)) {
+        return;
+    }
+    let outcome = request_cycles_top_up(config.top_up_amount).await;
+    TOP_UP_IN_FLIGHT.with(|f| f.set(false));
+    log_activity(format!("Cycles top-up: {}", outcome));
+    let mut history = cycles_history();
+    history.last_top_up = Some(TopUpRequest {
+        timestamp: time() / 1_000_000_000,
+        amount: Nat::from(config.top_up_amount),
+        outcome,
+    });
+    set_cycles_history(history);
+}
+
+// Asks the ledger to convert part of its dapp funds into cycles for this
+// canister. This canister needs the ledger's CyclesRequester role.
+async fn request_cycles_top_up(amount: u128) -> String {
+    let Some(ledger_id) = LEDGER_CANISTER_ID.with(|l| *l.borrow().get()) else {
+        return "Ledger canister not configured".to_string();
+    };
+    let top_ups = vec![CyclesTopUp {
+        canister_id: ic_cdk::id(),
+        amount: Nat::from(amount),
+    }];
+    let result: Result<(Result<Vec<CyclesConversion>, LedgerError>,), _> =
+        ic_cdk::call(ledger_id, "convert_dapp_funds_to_cycles", (top_ups,)).await;
+    match result {
+        Ok((Ok(conversions),)) => conversions
+            .first()
+            .map(|c| format!("conversion {} {:?}", c.id, c.status))
+            .unwrap_or_else(|| "no conversion".to_string()),
+        Ok((Err(e),)) => format!("{:?}", e),
+        Err(e) => format!("Call failed: {:?}", e),
+    }
+}
+
+#[update]
+fn set_cycles_config(config: CyclesConfig) -> Result<(), ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    if config.check_interval == 0 {
+        return Err(ChronoError::InvalidInput(
+            "Check interval must be positive".to_string(),
+        ));
+    }
+    let interval_changed = config.check_interval != cycles_config().check_interval;
+    CYCLES_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(config.clone())
+            .expect("Failed to set CYCLES_CONFIG")
+    });
+    if interval_changed {
+        start_cycles_monitor();
+    }
+    log_activity(format!(
+        "Cycles config: mark {}, top-up {}, every {}s",
+        config.low_water_mark, config.top_up_amount, config.check_interval
+    ));
+    Ok(())
+}
+
+#[query]
+fn get_cycles_config() -> CyclesConfig {
+    cycles_config()
+}
+
+#[query]
+fn get_cycles_stats() -> Result<CyclesStats, ChronoError> {
+    // Validate auditor authentication
+    let _authenticated_admin = validate_role_authentication(Role::Auditor)?;
+    let history = cycles_history();
+    Ok(CyclesStats {
+        balance: Nat::from(canister_balance128()),
+        burn_rate_per_day: Nat::from(burn_rate_per_day(&history.samples)),
+        low_water_mark: Nat::from(cycles_config().low_water_mark),
+        operations: CYCLES_SPENT.with(|spent| spent.borrow().iter().collect()),
+        last_top_up: history.last_top_up,
+    })
+}
+
+// -------------------------
+// Quarantine
+// -------------------------
+
+// Keeps the bytes of a stored value that could not be decoded. The same value
+// fails again on every read, so it is recorded once, looked up by the hash of
+// its type and bytes; the quarantine grows with the number of distinct
+// undecodable values, not with the number of reads.
+//
+// A query's changes are discarded, so a value first read by a query is not
+// kept; it is quarantined when an update call or an upgrade reads it.
+fn quarantine_value(type_name: &str, bytes: &[u8], error: String) {
+    let key = quarantine_key(type_name, bytes);
+    if QUARANTINE_INDEX.with(|i| i.borrow().contains_key(&key)) {
+        return;
+    }
+    let id = QUARANTINE.with(|q| {
+        let mut q = q.borrow_mut();
+        let id = q.last_key_value().map_or(0, |(id, _)| id + 1);
+        q.insert(
+            id,
+            QuarantinedValue {
+                id,
+                type_name: type_name.to_string(),
+                bytes: bytes.to_vec(),
+                error,
+                detected_at: time(),
+            },
+        );
+        id
+    });
+    QUARANTINE_INDEX.with(|i| i.borrow_mut().insert(key, id));
+}
+
+fn quarantine_key(type_name: &str, bytes: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((type_name.len() as u64).to_be_bytes());
+    hasher.update(type_name.as_bytes());
+    hasher.update(bytes);
+    hasher.finalize().into()
+}
+
+// Indexes quarantined values recorded before QUARANTINE_INDEX existed.
+fn index_quarantine() {
+    if QUARANTINE_INDEX.with(|i| i.borrow().len()) == QUARANTINE.with(|q| q.borrow().len()) {
+        return;
+    }
+    QUARANTINE.with(|q| {
+        QUARANTINE_INDEX.with(|i| {
+            let mut i = i.borrow_mut();
+            for (id, value) in q.borrow().iter() {
+                i.insert(quarantine_key(&value.type_name, &value.bytes), id);
+            }
+        })
+    });
+}
+
+// Moves role memberships and tripped breakers whose tag this version does not
+// know, e.g. written by a newer version before a downgrade, to the quarantine.
+// Unknown roles grant nothing, and unknown subsystems have no calls to halt.
+fn quarantine_unknown_tags() {
+    let members: Vec<(u8, Principal)> = ROLE_MEMBERS.with(|r| {
+        r.borrow()
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(tag, _)| Role::from_tag(*tag).is_none())
+            .collect()
+    });
+    for key in members {
+        ROLE_MEMBERS.with(|r| r.borrow_mut().remove(&key));
+        quarantine_value(
+            "Role",
+            &key.to_bytes(),
+            format!("Unknown role tag {}", key.0),
+        );
+    }
+    let breakers: Vec<u8> = TRIPPED_BREAKERS.with(|b| {
+        b.borrow()
+            .iter()
+            .map(|(tag, _)| tag)
+            .filter(|tag| Subsystem::from_tag(*tag).is_none())
+            .collect()
+    });
+    for tag in breakers {
+        TRIPPED_BREAKERS.with(|b| b.borrow_mut().remove(&tag));
+        quarantine_value(
+            "Subsystem",
+            &[tag],
+            format!("Unknown subsystem tag {}", tag),
+        );
+    }
+}
+
+#[query]
+fn get_quarantined_values(offset: u64, limit: u64) -> Result<Vec<QuarantinedValue>, ChronoError> {
+    // Validate auditor authentication for quarantine access
+    let _authenticated_admin = validate_role_authentication(Role::Auditor)?;
+    Ok(QUARANTINE.with(|q| {
+        q.borrow()
+            .iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(_, value)| value)
+            .collect()
+    }))
+}
+
+// Drops a quarantined value once it has been repaired or deemed lost.
+#[update]
+fn clear_quarantined_value(id: u64) -> Result<(), ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    let value =
+        QUARANTINE
+            .with(|q| q.borrow_mut().remove(&id))
+            .ok_or(ChronoError::InvalidInput(
+                "Quarantined value not found".to_string(),
+            ))?;
+    QUARANTINE_INDEX.with(|i| {
+        i.borrow_mut()
+            .remove(&quarantine_key(&value.type_name, &value.bytes))
+    });
+    log_activity(format!(
+        "Quarantined value {} cleared: {}",
+        id, value.type_name
+    ));
+    Ok(())
+}
+
+// -------------------------
+// Operating Mode & Circuit Breakers
+// -------------------------
+
+fn operating_mode() -> OperatingMode {
+    OPERATING_MODE.with(|m| *m.borrow().get())
+}
+
+// Fails unless the subsystem may run: the canister is in the Normal mode and
+// the subsystem's breaker is not tripped.
+fn check_subsystem(subsystem: Subsystem) -> Result<(), ChronoError> {
+    let mode = operating_mode();
+    if mode != OperatingMode::Normal {
+        return Err(ChronoError::Unavailable(format!(
+            "The canister is in the {:?} mode",
+            mode
+        )));
+    }
+    if TRIPPED_BREAKERS.with(|b| b.borrow().contains_key(&subsystem.tag())) {
+        return Err(ChronoError::Unavailable(format!(
+            "The {:?} subsystem is halted",
+            subsystem
+        )));
+    }
+    Ok(())
+}
+
+// Fails in the modes where only administration runs.
+fn check_writable() -> Result<(), ChronoError> {
+    match operating_mode() {
+        mode @ (OperatingMode::ReadOnly | OperatingMode::Restoring) => Err(
+            ChronoError::Unavailable(format!("The canister is in the {:?} mode", mode)),
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[query]
+fn get_operating_mode() -> OperatingMode {
+    operating_mode()
+}
+
+#[update]
+fn set_operating_mode(mode: OperatingMode) -> Result<(), ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    let previous = operating_mode();
+    OPERATING_MODE.with(|m| {
+        m.borrow_mut()
+            .set(mode)
+            .expect("Failed to set OPERATING_MODE")
+    });
+    log_activity(format!(
+        "Operating mode set to {:?} (was {:?})",
+        mode, previous
+    ));
+    Ok(())
+}
+
+// Subsystems whose breaker is tripped
+#[query]
+fn get_circuit_breakers() -> Vec<Subsystem> {
+    TRIPPED_BREAKERS.with(|b| {
+        b.borrow()
+            .iter()
+            .filter_map(|(tag, _)| Subsystem::from_tag(tag))
+            .collect()
+    })
+}
+
+// Trips the breaker of a subsystem, halting its update calls, or resets it.
+#[update]
+fn set_circuit_breaker(subsystem: Subsystem, tripped: bool) -> Result<(), ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    TRIPPED_BREAKERS.with(|b| {
+        let mut breakers = b.borrow_mut();
+        if tripped {
+            breakers.insert(subsystem.tag(), ());
+        } else {
+            breakers.remove(&subsystem.tag());
+        }
+    });
+    log_activity(format!(
+        "Circuit breaker of {:?} {}",
+        subsystem,
+        if tripped { "tripped" } else { "reset" }
+    ));
+    Ok(())
+}
+
+// -------------------------
+// State Export & Import
+// -------------------------
+
+fn export_checksum(records: &[ExportRecord]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for record in records {
+        let bytes = candid::encode_one(record).expect("Failed to encode export record");
+        hasher.update((bytes.len() as u64).to_be_bytes());
+        hasher.update(&bytes);
+    }
+    hasher.finalize().into()
+}
+
+fn section_of(record: &ExportRecord) -> ExportSection {
+    match record {
+        ExportRecord::Chronolock(_) => ExportSection::Chronolocks,
+        ExportRecord::OwnerTokens { .. } => ExportSection::OwnerIndex,
+        ExportRecord::Creator { .. } => ExportSection::Creators,
+        ExportRecord::MediaChunk { .. } => ExportSection::Media,
+    }
+}
+
+// Chunks of the media files from the given position on, and the position of
+// the next chunk if there is one. Empty files are exported as one empty chunk.
+fn export_media(offset: u64, limit: u64) -> (Vec<ExportRecord>, Option<u64>) {
+    let mut records = Vec::new();
+    let mut chunk = offset % MEDIA_EXPORT_CHUNKS_PER_FILE;
+    MEDIA_FILES.with(|media| {
+        let media = media.borrow();
+        let first_file = offset / MEDIA_EXPORT_CHUNKS_PER_FILE;
+        for (file_index, media_id) in media.keys().enumerate().skip(first_file as usize) {
+            let data = media.get(&media_id).unwrap_or_default();
+            let chunks = (data.len() as u64).div_ceil(MEDIA_EXPORT_CHUNK_SIZE).max(1);
+            while chunk < chunks {
+                if records.len() as u64 >= limit {
+                    return (
+                        records,
+                        Some(file_index as u64 * MEDIA_EXPORT_CHUNKS_PER_FILE + chunk),
+                    );
+                }
+                let start = (chunk * MEDIA_EXPORT_CHUNK_SIZE) as usize;
+                let end = (start + MEDIA_EXPORT_CHUNK_SIZE as usize).min(data.len());
+                records.push(ExportRecord::MediaChunk {
+                    media_id: media_id.clone(),
+                    offset: start as u64,
+                    total_size: data.len() as u64,
+                    bytes: data[start..end].to_vec(),
+                });
+                chunk += 1;
+            }
+            chunk = 0;
+        }
+        (records, None)
+    })
+}
+
+// Pages are read by offset from the live maps, so an entry inserted or removed
+// between two pages would shift the rest of the section. Exports are therefore
+// only served in the modes where no call writes to the canister.
+fn check_export_mode() -> Result<(), ChronoError> {
+    match operating_mode() {
+        OperatingMode::ReadOnly | OperatingMode::Restoring => Ok(()),
+        mode => Err(ChronoError::Unavailable(format!(
+            "Exports require the ReadOnly or Restoring mode, the canister is in the {:?} mode",
+            mode
+        ))),
+    }
+}
+
+fn export_page(section: ExportSection, offset: u64, limit: u64) -> ExportPage {
+    let (records, next_offset) = match section {
+        ExportSection::Chronolocks => CHRONOLOCKS.with(|locks| {
+            let locks = locks.borrow();
+            let records: Vec<ExportRecord> = locks
+                .iter()
+                .skip(offset as usize)
+                .take(limit.min(MAX_EXPORT_PAGE_SIZE) as usize)
+                .map(|(_, lock)| ExportRecord::Chronolock(lock))
+                .collect();
+            let end = offset + records.len() as u64;
+            (records, (end < locks.len()).then_some(end))
+        }),
+        ExportSection::OwnerIndex => OWNER_TO_TOKENS.with(|owners| {
+            let owners = owners.borrow();
+            let records: Vec<ExportRecord> = owners
+                .iter()
+                .skip(offset as usize)
+                .take(limit.min(MAX_EXPORT_PAGE_SIZE) as usize)
+                .map(|(owner, list)| ExportRecord::OwnerTokens {
+                    owner,
+                    tokens: list.tokens,
+                })
+                .collect();
+            let end = offset + records.len() as u64;
+            (records, (end < owners.len()).then_some(end))
+        }),
+        ExportSection::Media => export_media(offset, limit.min(MAX_MEDIA_EXPORT_PAGE_SIZE)),
+        ExportSection::Creators => CREATORS.with(|creators| {
+            let creators = creators.borrow();
+            let records: Vec<ExportRecord> = creators
+                .iter()
+                .skip(offset as usize)
+                .take(limit.min(MAX_EXPORT_PAGE_SIZE) as usize)
+                .map(|(creator, first_created_at)| ExportRecord::Creator {
+                    creator,
+                    first_created_at,
+                })
+                .collect();
+            let end = offset + records.len() as u64;
+            (records, (end < creators.len()).then_some(end))
+        }),
+    };
+    ExportPage {
+        version: EXPORT_FORMAT_VERSION,
+        section,
+        offset,
+        checksum: export_checksum(&records),
+        records,
+        next_offset,
+    }
+}
+
+// Checks that the media chunks of a page start a file or continue the part of
+// it imported so far.
+fn validate_imported_media(page: &ExportPage) -> Result<(), ChronoError> {
+    let mut lengths: BTreeMap<String, u64> = BTreeMap::new();
+    for record in &page.records {
+        if let ExportRecord::MediaChunk {
+            media_id,
+            offset,
+            total_size,
+            bytes,
+        } = record
+        {
+            if *total_size > MAX_MEDIA_FILE_SIZE as u64 {
+                return Err(ChronoError::InvalidInput(format!(
+                    "Media {} exceeds maximum of {} bytes",
+                    media_id, MAX_MEDIA_FILE_SIZE
+                )));
+            }
+            // A chunk at offset 0 starts the file over
+            let length = match lengths.get(media_id) {
+                _ if *offset == 0 => 0,
+                Some(length) => *length,
+                None => MEDIA_FILES.with(|media| {
+                    media
+                        .borrow()
+                        .get(media_id)
+                        .map_or(0, |data| data.len() as u64)
+                }),
+            };
+            if *offset != length || offset + bytes.len() as u64 > *total_size {
+                return Err(ChronoError::InvalidInput(format!(
+                    "Chunk at {} does not continue media {}",
+                    offset, media_id
+                )));
+            }
+            lengths.insert(media_id.clone(), offset + bytes.len() as u64);
+        }
+    }
+    Ok(())
+}
+
+fn import_record(record: ExportRecord) {
+    match record {
+        ExportRecord::Chronolock(lock) => {
+            CHRONOLOCKS.with(|locks| locks.borrow_mut().insert(lock.id.clone(), lock));
+        }
+        ExportRecord::OwnerTokens { owner, tokens } => {
+            OWNER_TO_TOKENS.with(|owners| owners.borrow_mut().insert(owner, TokenList { tokens }));
+        }
+        ExportRecord::MediaChunk {
+            media_id,
+            offset,
+            bytes,
+            ..
+        } => MEDIA_FILES.with(|media| {
+            let mut media = media.borrow_mut();
+            let mut data = if offset == 0 {
+                Vec::new()
+            } else {
+                media.get(&media_id).unwrap_or_default()
+            };
+            data.extend_from_slice(&bytes);
+            media.insert(media_id, data);
+        }),
+        ExportRecord::Creator {
+            creator,
+            first_created_at,
+        } => {
+            CREATORS.with(|c| c.borrow_mut().insert(creator, first_created_at));
+        }
+    }
+}
+
+// Page of the export stream of a section. Pass next_offset back as offset to
+// read the following page.
+#[query]
+fn export_state(
+    section: ExportSection,
+    offset: u64,
+    limit: u64,
+) -> Result<ExportPage, ChronoError> {
+    // Validate auditor authentication for state export
+    let _authenticated_admin = validate_role_authentication(Role::Auditor)?;
+    check_export_mode()?;
+    Ok(export_page(section, offset, limit))
+}
+
+// Checksum of the page export_state would return, so that a restored canister
+// can be compared with its source without transferring the records again.
+#[query]
+fn get_export_checksum(
+    section: ExportSection,
+    offset: u64,
+    limit: u64,
+) -> Result<ExportChecksum, ChronoError> {
+    // Validate auditor authentication for state export
+    let _authenticated_admin = validate_role_authentication(Role::Auditor)?;
+    check_export_mode()?;
+    let page = export_page(section, offset, limit);
+    Ok(ExportChecksum {
+        section: page.section,
+        offset: page.offset,
+        records: page.records.len() as u64,
+        checksum: page.checksum,
+        next_offset: page.next_offset,
+    })
+}
+
+// Applies a page exported by another canister. Records overwrite the entries
+// with the same key; the page is checked in full before anything is written.
+#[update]
+fn import_state(page: ExportPage) -> Result<u64, ChronoError> {
+    // Validate admin authentication
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    if operating_mode() != OperatingMode::Restoring {
+        return Err(ChronoError::InvalidInput(
+            "Imports require the Restoring mode".to_string(),
+        ));
+    }
+    if page.version != EXPORT_FORMAT_VERSION {
+        return Err(ChronoError::InvalidInput(format!(
+            "Unsupported export version {}",
+            page.version
+        )));
+    }
+    if export_checksum(&page.records) != page.checksum {
+        return Err(ChronoError::InvalidInput("Checksum mismatch".to_string()));
+    }
+    if page
+        .records
+        .iter()
+        .any(|record| section_of(record) != page.section)
+    {
+        return Err(ChronoError::InvalidInput(format!(
+            "Page mixes records of other sections into {:?}",
+            page.section
+        )));
+    }
+    validate_imported_media(&page)?;
+
+    let count = page.records.len() as u64;
+    for record in page.records {
+        import_record(record);
+    }
+    log_activity(format!(
+        "Imported {} records of {:?} at {}",
+        count, page.section, page.offset
+    ));
+    Ok(count)
+}
+
+// -------------------------
+// Role Management Functions (Owner Only)
+// -------------------------
+
+#[update]
+fn grant_role(role: Role, principal: Principal) -> Result<(), ChronoError> {
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    if role == Role::Owner {
+        return Err(ChronoError::InvalidRoleChange(
+            "Ownership is changed with transfer_ownership".to_string(),
+        ));
+    }
+    if principal == Principal::anonymous() {
+        return Err(ChronoError::InvalidPrincipal);
+    }
+
+    ROLE_MEMBERS.with(|r| r.borrow_mut().insert((role.tag(), principal), ()));
+
+    log_activity(format!("Granted {:?} role to {}", role, principal));
+    Ok(())
+}
+
+#[update]
+fn revoke_role(role: Role, principal: Principal) -> Result<(), ChronoError> {
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    if role == Role::Owner {
+        return Err(ChronoError::InvalidRoleChange(
+            "Ownership is changed with transfer_ownership".to_string(),
+        ));
+    }
+    if ROLE_MEMBERS
+        .with(|r| r.borrow_mut().remove(&(role.tag(), principal)))
+        .is_none()
+    {
+        return Err(ChronoError::InvalidRoleChange(format!(
+            "{} does not hold the {:?} role",
+            principal, role
+        )));
+    }
+
+    log_activity(format!("Revoked {:?} role from {}", role, principal));
+    Ok(())
+}
+
+// Members explicitly granted a role. The owner holds every role but is only
+// listed as the member of the Owner role.
+#[query]
+fn list_role_members(role: Role) -> Vec<Principal> {
+    if role == Role::Owner {
+        return owner_principal().into_iter().collect();
+    }
+    ROLE_MEMBERS.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|((tag, _), _)| *tag == role.tag())
+            .map(|((_, member), _)| member)
+            .collect()
+    })
+}
+
+// First step of an ownership transfer; the new owner has to accept it.
+#[update]
+fn transfer_ownership(new_owner: Principal) -> Result<(), ChronoError> {
+    let authenticated_admin = validate_role_authentication(Role::Owner)?;
+    if new_owner == Principal::anonymous() {
+        return Err(ChronoError::InvalidPrincipal);
+    }
+    if new_owner == authenticated_admin {
+        return Err(ChronoError::InvalidRoleChange(
+            "The new owner must differ from the current owner".to_string(),
+        ));
+    }
+
+    ADMINS.with(|admins| admins.borrow_mut().insert(PENDING_OWNER_KEY, new_owner));
+
+    log_activity(format!("Ownership transfer proposed to {}", new_owner));
+    Ok(())
+}
+
+#[update]
+fn cancel_ownership_transfer() -> Result<(), ChronoError> {
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
+    let pending = ADMINS.with(|admins| admins.borrow_mut().remove(&PENDING_OWNER_KEY));
+    if let Some(pending) = pending {
+        log_activity(format!("Ownership transfer to {} cancelled", pending));
+    }
+    Ok(())
+}
+
+// Second step of an ownership transfer, called by the proposed owner.
+#[update]
+fn accept_ownership() -> Result<(), ChronoError> {
+    let caller = caller();
+    let pending = ADMINS.with(|admins| admins.borrow().get(&PENDING_OWNER_KEY));
+    if pending != Some(caller) {
+        return Err(ChronoError::Unauthorized);
+    }
+
+    ADMINS.with(|admins| {
+        let mut admins = admins.borrow_mut();
+        admins.insert(OWNER_KEY, caller);
+        admins.remove(&PENDING_OWNER_KEY);
+    });
+
+    log_activity(format!("Ownership transferred to {}", caller));
+    Ok(())
+}
+
+#[query]
+fn get_owner() -> Option<Principal> {
+    owner_principal()
+}
+
+#[query]
+fn get_pending_owner() -> Option<Principal> {
+    ADMINS.with(|admins| admins.borrow().get(&PENDING_OWNER_KEY))
+}
+
 // -------------------------
 // Authentication Management Functions (Admin Only)
 // -------------------------
@@ -1152,7 +3486,7 @@ fn get_user_accessible_chronolocks_paginated(
 #[update]
 fn add_trusted_principal(principal: Principal) -> Result<(), ChronoError> {
     // Validate admin authentication
-    let _authenticated_admin = validate_admin_authentication()?;
+    let _authenticated_admin = validate_role_authentication(Role::Moderator)?;
 
     TRUSTED_PRINCIPALS.with(|tp| {
         tp.borrow_mut().insert(principal, true);
@@ -1165,7 +3499,7 @@ fn add_trusted_principal(principal: Principal) -> Result<(), ChronoError> {
 #[update]
 fn remove_trusted_principal(principal: Principal) -> Result<(), ChronoError> {
     // Validate admin authentication
-    let _authenticated_admin = validate_admin_authentication()?;
+    let _authenticated_admin = validate_role_authentication(Role::Moderator)?;
 
     TRUSTED_PRINCIPALS.with(|tp| {
         tp.borrow_mut().remove(&principal);
@@ -1178,7 +3512,7 @@ fn remove_trusted_principal(principal: Principal) -> Result<(), ChronoError> {
 #[update]
 fn set_admin_bypass(enabled: bool) -> Result<(), ChronoError> {
     // Validate admin authentication
-    let _authenticated_admin = validate_admin_authentication()?;
+    let _authenticated_admin = validate_role_authentication(Role::Owner)?;
 
     ADMIN_BYPASS_ENABLED.with(|ab| {
         ab.borrow_mut()
@@ -1211,8 +3545,8 @@ fn is_valid_ii_principal(principal: Principal) -> bool {
 
 #[query]
 fn get_trusted_principals() -> Vec<Principal> {
-    // Only admin can view the full list of trusted principals
-    if validate_admin_authentication().is_err() {
+    // Only moderators can view the full list of trusted principals
+    if validate_role_authentication(Role::Moderator).is_err() {
         return vec![];
     }
 
@@ -29,6 +29,10 @@ enum ChronoError {
     AdminRequired,
     InvalidPrincipal,
     UnauthorizedCaller,
+    PaymentFailed(String),
+    InvalidRoleChange(String),
+    RateLimited { retry_after: u64 },
+    Unavailable(String),
 }
 
 // Helper function to create an Internet Identity principal
@@ -471,3 +475,168 @@ fn test_admin_bypass() {
     );
     assert_eq!(result.unwrap_err(), ChronoError::NotAuthenticated);
 }
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Owner,
+    Minter,
+    PoolManager,
+    Moderator,
+    Auditor,
+}
+
+#[test]
+fn test_roles_and_ownership_transfer() {
+    let (pic, backend_canister, admin, ii_user, regular_user) = setup();
+    let moderator = create_ii_principal(3);
+    let new_owner = create_ii_principal(4);
+
+    let call = |caller: Principal, method: &str, args: Vec<u8>| -> Result<(), ChronoError> {
+        decode_one(
+            &pic.update_call(backend_canister, caller, method, args)
+                .expect("Update call failed"),
+        )
+        .unwrap()
+    };
+    let owner = || -> Option<Principal> {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "get_owner",
+                encode_args(()).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+
+    // Moderators manage trusted principals and may burn any chronolock, but
+    // cannot change owner-only settings.
+    assert_eq!(
+        call(
+            moderator,
+            "add_trusted_principal",
+            encode_args((regular_user,)).unwrap()
+        ),
+        Err(ChronoError::AdminRequired)
+    );
+    assert_eq!(
+        call(
+            admin,
+            "grant_role",
+            encode_args((Role::Moderator, moderator)).unwrap()
+        ),
+        Ok(())
+    );
+    let members: Vec<Principal> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "list_role_members",
+            encode_args((Role::Moderator,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(members, vec![moderator]);
+    assert_eq!(
+        call(
+            moderator,
+            "add_trusted_principal",
+            encode_args((regular_user,)).unwrap()
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        call(
+            moderator,
+            "set_max_metadata_size",
+            encode_args((1u64,)).unwrap()
+        ),
+        Err(ChronoError::AdminRequired)
+    );
+
+    let response = pic
+        .update_call(
+            backend_canister,
+            ii_user,
+            "create_chronolock",
+            encode_args((
+                "Moderated chronolock".to_string(),
+                1000000u64,
+                vec![UserKey {
+                    user: "public".to_string(),
+                    key: "test_key".to_string(),
+                }],
+                "encrypted_data".to_string(),
+            ))
+            .unwrap(),
+        )
+        .expect("Failed to call create_chronolock");
+    let token_id = decode_one::<Result<String, ChronoError>>(&response)
+        .unwrap()
+        .expect("Chronolock should be created");
+    assert_eq!(
+        call(
+            moderator,
+            "burn_chronolock",
+            encode_args((token_id,)).unwrap()
+        ),
+        Ok(())
+    );
+
+    assert_eq!(
+        call(
+            admin,
+            "revoke_role",
+            encode_args((Role::Moderator, moderator)).unwrap()
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        call(
+            moderator,
+            "remove_trusted_principal",
+            encode_args((regular_user,)).unwrap()
+        ),
+        Err(ChronoError::AdminRequired)
+    );
+
+    // Ownership moves only once the proposed owner accepts it.
+    assert_eq!(
+        call(
+            admin,
+            "transfer_ownership",
+            encode_args((new_owner,)).unwrap()
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        call(ii_user, "accept_ownership", encode_args(()).unwrap()),
+        Err(ChronoError::Unauthorized)
+    );
+    assert_eq!(owner(), Some(admin));
+    assert_eq!(
+        call(new_owner, "accept_ownership", encode_args(()).unwrap()),
+        Ok(())
+    );
+    assert_eq!(owner(), Some(new_owner));
+
+    assert_eq!(
+        call(
+            admin,
+            "set_max_metadata_size",
+            encode_args((1024u64,)).unwrap()
+        ),
+        Err(ChronoError::AdminRequired)
+    );
+    assert_eq!(
+        call(
+            new_owner,
+            "set_max_metadata_size",
+            encode_args((1024u64,)).unwrap()
+        ),
+        Ok(())
+    );
+}
@@ -46,6 +46,10 @@ enum ChronoError {
     AdminRequired,
     InvalidPrincipal,
     UnauthorizedCaller,
+    PaymentFailed(String),
+    InvalidRoleChange(String),
+    RateLimited { retry_after: u64 },
+    Unavailable(String),
 }
 
 #[derive(CandidType, Deserialize)]
@@ -1157,3 +1161,721 @@ fn test_pagination_edge_cases() {
     let chronolocks = result.expect("Failed to get chronolocks");
     assert_eq!(chronolocks.len(), 0, "Should return empty when limit is 0");
 }
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct RateLimit {
+    capacity: u32,
+    refill_interval: u64,
+}
+
+#[test]
+fn test_start_media_upload_rate_limit() {
+    let (pic, backend_canister, admin) = setup();
+    let user = create_ii_principal(30);
+    let start_upload = |caller: Principal| {
+        decode_one::<Result<String, ChronoError>>(
+            &pic.update_call(
+                backend_canister,
+                caller,
+                "start_media_upload",
+                encode_args((1u32,)).unwrap(),
+            )
+            .expect("Failed to call start_media_upload"),
+        )
+        .unwrap()
+    };
+    let set_rate_limit = |caller: Principal, method: &str, limit: Option<RateLimit>| {
+        decode_one::<Result<(), ChronoError>>(
+            &pic.update_call(
+                backend_canister,
+                caller,
+                "set_rate_limit",
+                encode_args((method.to_string(), limit)).unwrap(),
+            )
+            .expect("Failed to call set_rate_limit"),
+        )
+        .unwrap()
+    };
+
+    let limit = RateLimit {
+        capacity: 2,
+        refill_interval: 30,
+    };
+    assert_eq!(
+        set_rate_limit(user, "start_media_upload", Some(limit.clone())),
+        Err(ChronoError::AdminRequired)
+    );
+    assert!(matches!(
+        set_rate_limit(admin, "icrc7_transfer", Some(limit.clone())),
+        Err(ChronoError::InvalidInput(_))
+    ));
+    assert_eq!(
+        set_rate_limit(admin, "start_media_upload", Some(limit.clone())),
+        Ok(())
+    );
+    let limits: Vec<(String, RateLimit)> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_rate_limits",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(limits.len(), 4);
+    assert!(limits.contains(&("start_media_upload".to_string(), limit)));
+
+    assert!(start_upload(user).is_ok());
+    assert!(start_upload(user).is_ok());
+    assert!(matches!(
+        start_upload(user),
+        Err(ChronoError::RateLimited { retry_after }) if retry_after > 0 && retry_after <= 30
+    ));
+    // Admins are not limited.
+    for _ in 0..3 {
+        assert!(start_upload(admin).is_ok());
+    }
+
+    // The bucket survives an upgrade and refills over time.
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'cargo build'.");
+    pic.upgrade_canister(backend_canister, wasm, encode_args(()).unwrap(), None)
+        .expect("Failed to upgrade the canister");
+    assert!(matches!(
+        start_upload(user),
+        Err(ChronoError::RateLimited { .. })
+    ));
+    pic.advance_time(std::time::Duration::from_secs(31));
+    assert!(start_upload(user).is_ok());
+
+    // Resetting restores the default limit.
+    assert_eq!(set_rate_limit(admin, "start_media_upload", None), Ok(()));
+    let limits: Vec<(String, RateLimit)> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_rate_limits",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(limits.contains(&(
+        "start_media_upload".to_string(),
+        RateLimit {
+            capacity: 10,
+            refill_interval: 60,
+        }
+    )));
+}
+
+#[test]
+fn test_inspect_message_rejects_invalid_ingress() {
+    let (pic, backend_canister, admin) = setup();
+
+    // Anonymous callers cannot start uploads.
+    assert!(pic
+        .update_call(
+            backend_canister,
+            Principal::anonymous(),
+            "start_media_upload",
+            encode_args((1u32,)).unwrap(),
+        )
+        .is_err());
+
+    // Oversized arguments are dropped before execution.
+    assert!(pic
+        .update_call(
+            backend_canister,
+            admin,
+            "add_trusted_principal",
+            vec![0u8; 100 * 1024],
+        )
+        .is_err());
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CollectionMetadata {
+    symbol: String,
+    name: String,
+    description: String,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct UpgradeArgs {
+    network: Option<String>,
+    ledger_id: Option<Principal>,
+    max_metadata_size: Option<u64>,
+    mint_price: Option<u64>,
+}
+
+fn query_text(pic: &PocketIc, backend_canister: Principal, method: &str) -> String {
+    decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            method,
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_collection_metadata_and_upgrade_args() {
+    let (pic, backend_canister, admin) = setup();
+    let set_metadata = |caller: Principal, metadata: CollectionMetadata| {
+        decode_one::<Result<(), ChronoError>>(
+            &pic.update_call(
+                backend_canister,
+                caller,
+                "set_collection_metadata",
+                encode_args((metadata,)).unwrap(),
+            )
+            .expect("Failed to call set_collection_metadata"),
+        )
+        .unwrap()
+    };
+    let metadata = CollectionMetadata {
+        symbol: "TIME".to_string(),
+        name: "Time Capsules".to_string(),
+        description: "Letters to the future".to_string(),
+    };
+    assert_eq!(
+        set_metadata(create_ii_principal(2), metadata.clone()),
+        Err(ChronoError::AdminRequired)
+    );
+    assert!(matches!(
+        set_metadata(
+            admin,
+            CollectionMetadata {
+                symbol: " ".to_string(),
+                ..metadata.clone()
+            }
+        ),
+        Err(ChronoError::InvalidInput(_))
+    ));
+    assert_eq!(set_metadata(admin, metadata), Ok(()));
+
+    let schema_version: u32 = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_schema_version",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(schema_version, 3);
+
+    // Upgrade arguments replace the stored settings; the metadata is kept.
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'cargo build'.");
+    let args = UpgradeArgs {
+        max_metadata_size: Some(1_000),
+        mint_price: Some(0),
+        ..Default::default()
+    };
+    pic.upgrade_canister(
+        backend_canister,
+        wasm.clone(),
+        encode_args((Some(args),)).unwrap(),
+        None,
+    )
+    .expect("Failed to upgrade with upgrade arguments");
+    assert_eq!(query_text(&pic, backend_canister, "icrc7_symbol"), "TIME");
+    assert_eq!(
+        query_text(&pic, backend_canister, "icrc7_name"),
+        "Time Capsules"
+    );
+    assert_eq!(
+        query_text(&pic, backend_canister, "icrc7_description"),
+        "Letters to the future"
+    );
+    let result = create_test_chronolock(
+        &pic,
+        backend_canister,
+        admin,
+        1_000_000,
+        "Too big".to_string(),
+        vec![UserKey {
+            user: "public".to_string(),
+            key: "k".repeat(2_000),
+        }],
+    );
+    assert_eq!(result, Err(ChronoError::MetadataTooLarge));
+
+    // Upgrades without arguments, or with the install arguments, still succeed.
+    pic.upgrade_canister(
+        backend_canister,
+        wasm.clone(),
+        encode_args(()).unwrap(),
+        None,
+    )
+    .expect("Failed to upgrade without arguments");
+    pic.upgrade_canister(
+        backend_canister,
+        wasm,
+        encode_args((admin, Some("local".to_string()))).unwrap(),
+        None,
+    )
+    .expect("Failed to upgrade with the install arguments");
+    assert_eq!(query_text(&pic, backend_canister, "icrc7_symbol"), "TIME");
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum OperatingMode {
+    Normal,
+    ReadOnly,
+    Paused,
+    Restoring,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum Subsystem {
+    Creation,
+    Transfer,
+    MediaUpload,
+    KeyDerivation,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum ExportSection {
+    Chronolocks,
+    OwnerIndex,
+    Media,
+    Creators,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum ExportRecord {
+    Chronolock(Chronolock),
+    OwnerTokens {
+        owner: Principal,
+        tokens: Vec<String>,
+    },
+    MediaChunk {
+        media_id: String,
+        offset: u64,
+        total_size: u64,
+        bytes: Vec<u8>,
+    },
+    Creator {
+        creator: Principal,
+        first_created_at: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ExportPage {
+    version: u8,
+    section: ExportSection,
+    offset: u64,
+    records: Vec<ExportRecord>,
+    checksum: [u8; 32],
+    next_offset: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+struct ExportChecksum {
+    section: ExportSection,
+    offset: u64,
+    records: u64,
+    checksum: [u8; 32],
+    next_offset: Option<u64>,
+}
+
+const EXPORT_SECTIONS: [ExportSection; 4] = [
+    ExportSection::Chronolocks,
+    ExportSection::OwnerIndex,
+    ExportSection::Media,
+    ExportSection::Creators,
+];
+
+fn upload_media(
+    pic: &PocketIc,
+    backend_canister: Principal,
+    caller: Principal,
+    data: Vec<u8>,
+) -> String {
+    let response = pic
+        .update_call(
+            backend_canister,
+            caller,
+            "start_media_upload",
+            encode_args((1u32,)).unwrap(),
+        )
+        .expect("Failed to call start_media_upload");
+    let started: Result<String, ChronoError> = decode_one(&response).unwrap();
+    let media_id = started.expect("Failed to start media upload");
+    pic.update_call(
+        backend_canister,
+        caller,
+        "upload_media_chunk",
+        encode_args((media_id.clone(), 0u32, data)).unwrap(),
+    )
+    .expect("Failed to call upload_media_chunk");
+    let response = pic
+        .update_call(
+            backend_canister,
+            caller,
+            "finish_media_upload",
+            encode_args((media_id.clone(),)).unwrap(),
+        )
+        .expect("Failed to call finish_media_upload");
+    let finished: Result<String, ChronoError> = decode_one(&response).unwrap();
+    finished.expect("Failed to finish media upload");
+    media_id
+}
+
+fn export_checksums(
+    pic: &PocketIc,
+    backend_canister: Principal,
+    admin: Principal,
+) -> Vec<ExportChecksum> {
+    let mut checksums = Vec::new();
+    for section in EXPORT_SECTIONS {
+        let mut offset = Some(0u64);
+        while let Some(current) = offset {
+            let response = pic
+                .query_call(
+                    backend_canister,
+                    admin,
+                    "get_export_checksum",
+                    encode_args((section, current, 100u64)).unwrap(),
+                )
+                .expect("Failed to call get_export_checksum");
+            let result: Result<ExportChecksum, ChronoError> = decode_one(&response).unwrap();
+            let checksum = result.unwrap();
+            offset = checksum.next_offset;
+            checksums.push(checksum);
+        }
+    }
+    checksums
+}
+
+#[test]
+fn test_export_and_import_state() {
+    let (pic, source, admin) = setup();
+    let user = create_ii_principal(40);
+    let user_keys = vec![UserKey {
+        user: user.to_text(),
+        key: "encrypted_key".to_string(),
+    }];
+    let token_id = create_test_chronolock(
+        &pic,
+        source,
+        user,
+        4_102_444_800,
+        "Exported".to_string(),
+        user_keys,
+    )
+    .unwrap();
+    let first_media = upload_media(&pic, source, user, vec![1, 2, 3, 4, 5]);
+    let second_media = upload_media(&pic, source, user, vec![6, 7, 8]);
+
+    // A fresh canister installed with the same arguments
+    let target = pic.create_canister();
+    pic.add_cycles(target, 2_000_000_000_000);
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'cargo build'.");
+    pic.install_canister(
+        target,
+        wasm,
+        encode_args((admin, Some("local".to_string()))).unwrap(),
+        None,
+    );
+    pic.update_call(
+        target,
+        admin,
+        "set_admin_bypass",
+        encode_args((true,)).unwrap(),
+    )
+    .expect("Failed to enable admin bypass");
+
+    let set_mode = |canister: Principal, mode: OperatingMode| -> Result<(), ChronoError> {
+        decode_one(
+            &pic.update_call(
+                canister,
+                admin,
+                "set_operating_mode",
+                encode_args((mode,)).unwrap(),
+            )
+            .expect("Failed to call set_operating_mode"),
+        )
+        .unwrap()
+    };
+    let import = |page: &ExportPage| -> Result<u64, ChronoError> {
+        decode_one(
+            &pic.update_call(
+                target,
+                admin,
+                "import_state",
+                encode_args((page.clone(),)).unwrap(),
+            )
+            .expect("Failed to call import_state"),
+        )
+        .unwrap()
+    };
+    let try_export = |section: ExportSection, offset: u64| -> Result<ExportPage, ChronoError> {
+        decode_one(
+            &pic.query_call(
+                source,
+                admin,
+                "export_state",
+                encode_args((section, offset, 1u64)).unwrap(),
+            )
+            .expect("Failed to call export_state"),
+        )
+        .unwrap()
+    };
+    let export = |section: ExportSection, offset: u64| -> ExportPage {
+        try_export(section, offset).unwrap()
+    };
+
+    // Exports are refused while the source can still change.
+    assert!(matches!(
+        try_export(ExportSection::Chronolocks, 0),
+        Err(ChronoError::Unavailable(_))
+    ));
+    assert_eq!(set_mode(source, OperatingMode::ReadOnly), Ok(()));
+
+    // Imports are refused outside the Restoring mode.
+    let first = export(ExportSection::Chronolocks, 0);
+    assert!(matches!(import(&first), Err(ChronoError::InvalidInput(_))));
+    assert_eq!(set_mode(target, OperatingMode::Restoring), Ok(()));
+
+    let mut media_offsets = Vec::new();
+    for section in EXPORT_SECTIONS {
+        let mut offset = Some(0u64);
+        while let Some(current) = offset {
+            let page = export(section, current);
+            if section == ExportSection::Media {
+                media_offsets.push(current);
+            }
+            assert_eq!(import(&page), Ok(page.records.len() as u64));
+            offset = page.next_offset;
+        }
+    }
+    // Each media file starts at its own block of positions.
+    assert_eq!(media_offsets, vec![0, 10]);
+
+    // A page whose records were altered after export is rejected.
+    let mut page = export(ExportSection::Media, 0);
+    if let Some(ExportRecord::MediaChunk { offset, .. }) = page.records.first_mut() {
+        *offset = 1;
+    }
+    assert_eq!(
+        import(&page),
+        Err(ChronoError::InvalidInput("Checksum mismatch".to_string()))
+    );
+
+    assert_eq!(
+        export_checksums(&pic, source, admin),
+        export_checksums(&pic, target, admin)
+    );
+    let lock: Result<Chronolock, ChronoError> = decode_one(
+        &pic.query_call(
+            target,
+            user,
+            "get_chronolock",
+            encode_args((token_id,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(lock.unwrap().title, "Exported");
+    let balance: u64 = decode_one(
+        &pic.query_call(
+            target,
+            Principal::anonymous(),
+            "icrc7_balance_of",
+            encode_args((user,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(balance, 1);
+    // The creator still qualifies the referrals it claimed before creating its
+    // first chronolock, and only those, on the new canister.
+    let qualified = |since: u64| -> bool {
+        decode_one(
+            &pic.query_call(
+                target,
+                Principal::anonymous(),
+                "has_qualifying_action",
+                encode_args((user, since)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    assert!(qualified(0));
+    assert!(!qualified(pic.get_time().as_nanos_since_unix_epoch() + 1));
+    for (media_id, data) in [
+        (first_media, vec![1, 2, 3, 4, 5]),
+        (second_media, vec![6, 7, 8]),
+    ] {
+        let chunk: Result<Vec<u8>, ChronoError> = decode_one(
+            &pic.query_call(
+                target,
+                Principal::anonymous(),
+                "get_media_chunk",
+                encode_args((media_id, 0u32, 100u32)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(chunk.unwrap(), data);
+    }
+
+    assert_eq!(set_mode(target, OperatingMode::Normal), Ok(()));
+}
+
+#[test]
+fn test_operating_modes_and_circuit_breakers() {
+    let (pic, backend_canister, admin) = setup();
+    let user = create_ii_principal(50);
+    let user_keys = vec![UserKey {
+        user: user.to_text(),
+        key: "encrypted_key".to_string(),
+    }];
+    let create = || {
+        create_test_chronolock(
+            &pic,
+            backend_canister,
+            user,
+            4_102_444_800,
+            "Guarded".to_string(),
+            user_keys.clone(),
+        )
+    };
+    let update_title = |token_id: &str| -> Result<(), ChronoError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                user,
+                "update_chronolock",
+                encode_args((
+                    token_id.to_string(),
+                    Some("Renamed".to_string()),
+                    None::<u64>,
+                    None::<Vec<UserKey>>,
+                    None::<String>,
+                ))
+                .unwrap(),
+            )
+            .expect("Failed to call update_chronolock"),
+        )
+        .unwrap()
+    };
+    let start_upload = || -> Result<String, ChronoError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                user,
+                "start_media_upload",
+                encode_args((1u32,)).unwrap(),
+            )
+            .expect("Failed to call start_media_upload"),
+        )
+        .unwrap()
+    };
+    let set_mode = |mode: OperatingMode| -> Result<(), ChronoError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "set_operating_mode",
+                encode_args((mode,)).unwrap(),
+            )
+            .expect("Failed to call set_operating_mode"),
+        )
+        .unwrap()
+    };
+    let set_breaker = |subsystem: Subsystem, tripped: bool| -> Result<(), ChronoError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "set_circuit_breaker",
+                encode_args((subsystem, tripped)).unwrap(),
+            )
+            .expect("Failed to call set_circuit_breaker"),
+        )
+        .unwrap()
+    };
+
+    let token_id = create().unwrap();
+
+    // A tripped breaker halts its subsystem only; queries keep working.
+    assert_eq!(set_breaker(Subsystem::Creation, true), Ok(()));
+    let breakers: Vec<Subsystem> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_circuit_breakers",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(breakers, vec![Subsystem::Creation]);
+    assert!(matches!(create(), Err(ChronoError::Unavailable(_))));
+    assert!(start_upload().is_ok());
+    let lock: Result<Chronolock, ChronoError> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            user,
+            "get_chronolock",
+            encode_args((token_id.clone(),)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(lock.is_ok());
+    assert_eq!(set_breaker(Subsystem::Creation, false), Ok(()));
+
+    // Paused halts every subsystem; chronolocks can still be updated.
+    assert_eq!(set_mode(OperatingMode::Paused), Ok(()));
+    assert!(matches!(create(), Err(ChronoError::Unavailable(_))));
+    assert!(matches!(start_upload(), Err(ChronoError::Unavailable(_))));
+    assert_eq!(update_title(&token_id), Ok(()));
+
+    // ReadOnly refuses updates as well, while administration continues.
+    assert_eq!(set_mode(OperatingMode::ReadOnly), Ok(()));
+    assert!(matches!(
+        update_title(&token_id),
+        Err(ChronoError::Unavailable(_))
+    ));
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "set_max_metadata_size",
+            encode_args((102_400u64,)).unwrap(),
+        )
+        .expect("Failed to call set_max_metadata_size");
+    let result: Result<(), ChronoError> = decode_one(&response).unwrap();
+    assert_eq!(result, Ok(()));
+
+    assert_eq!(set_mode(OperatingMode::Normal), Ok(()));
+    assert!(create().is_ok());
+
+    let response = pic
+        .query_call(
+            backend_canister,
+            admin,
+            "get_logs_paginated",
+            encode_args((0u64, 1000u64)).unwrap(),
+        )
+        .expect("Failed to get logs");
+    let logs: Result<Vec<LogEntry>, ChronoError> = decode_one(&response).unwrap();
+    let logs = logs.unwrap();
+    assert!(logs
+        .iter()
+        .any(|entry| entry.activity.starts_with("Circuit breaker of Creation")));
+    assert!(logs
+        .iter()
+        .any(|entry| entry.activity == "Operating mode set to ReadOnly (was Paused)"));
+}
@@ -0,0 +1,348 @@
+// src/backend/chronolock/tests/cycles_canister_tests.rs
+
+use candid::{decode_one, encode_args, CandidType, Nat, Principal};
+use pocket_ic::PocketIc;
+use serde::Deserialize;
+use std::fs;
+use std::time::Duration;
+
+const BACKEND_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/chronolock_canister.wasm";
+const LEDGER_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/crnl_ledger_canister.wasm";
+const MOCK_SWAP_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/mock_swap_canister.wasm";
+const MOCK_CMC_WASM: &str = "../../../target/wasm32-unknown-unknown/release/mock_cmc_canister.wasm";
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum ChronoError {
+    Unauthorized,
+    TokenNotFound,
+    MetadataTooLarge,
+    TimeLocked,
+    InvalidInput(String),
+    InternalError(String),
+    NotAuthenticated,
+    AdminRequired,
+    InvalidPrincipal,
+    UnauthorizedCaller,
+    PaymentFailed(String),
+    InvalidRoleChange(String),
+    RateLimited { retry_after: u64 },
+    Unavailable(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CyclesConfig {
+    low_water_mark: u128,
+    top_up_amount: u128,
+    check_interval: u64,
+    vetkd_derive_cycles: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct OperationCycles {
+    calls: u64,
+    cycles: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TopUpRequest {
+    timestamp: u64,
+    amount: Nat,
+    outcome: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CyclesStats {
+    balance: Nat,
+    burn_rate_per_day: Nat,
+    low_water_mark: Nat,
+    operations: Vec<(String, OperationCycles)>,
+    last_top_up: Option<TopUpRequest>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CyclesConversionConfig {
+    swap_canister: Option<Principal>,
+    cmc_canister: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<[u8; 32]>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct PoolTransferArgs {
+    from_pool: String,
+    to_pool: Option<String>,
+    to_principal: Option<Account>,
+    amount: Nat,
+    description: String,
+    memo: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+enum Role {
+    Owner,
+    Minter,
+    PoolManager,
+    Moderator,
+    Auditor,
+    CyclesRequester,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LogEntry {
+    id: String,
+    timestamp: u64,
+    activity: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct NotifyTopUpArg {
+    block_index: u64,
+    canister_id: Principal,
+}
+
+fn create_ii_principal(seed: u8) -> Principal {
+    let mut bytes = [0u8; 10];
+    bytes[0] = seed;
+    bytes[9] = 0x01;
+    Principal::from_slice(&bytes)
+}
+
+fn install(pic: &PocketIc, wasm_path: &str, args: Vec<u8>) -> Principal {
+    let canister = pic.create_canister();
+    pic.add_cycles(canister, 2_000_000_000_000);
+    let wasm = fs::read(wasm_path).expect("Wasm file not found, run 'cargo build'.");
+    pic.install_canister(canister, wasm, args, None);
+    canister
+}
+
+// Deploys the ledger with mock swap and cycles minting canisters, funds its
+// dapp pool and lets the chronolock canister request top-ups from it.
+fn setup() -> (PocketIc, Principal, Principal, Principal, Principal) {
+    std::env::set_var("POCKET_IC_BIN", "/usr/local/bin/pocket-ic");
+    let pic = PocketIc::new();
+    let admin = create_ii_principal(1);
+
+    let ledger_args = encode_args((
+        "Chronolock".to_string(),
+        "CRNL".to_string(),
+        100_000_000_000_000_000_000_u128,
+        31_536_000_u64,
+        100_000_u128,
+        admin,
+    ))
+    .expect("Failed to encode ledger init arguments");
+    let ledger = install(&pic, LEDGER_WASM, ledger_args);
+    let swap_canister = install(&pic, MOCK_SWAP_WASM, encode_args(()).unwrap());
+    let cmc_canister = install(&pic, MOCK_CMC_WASM, encode_args(()).unwrap());
+    let init_args = encode_args((admin, Some("local".to_string()), Some(ledger)))
+        .expect("Failed to encode init arguments");
+    let chronolock = install(&pic, BACKEND_WASM, init_args);
+
+    for canister in [ledger, chronolock] {
+        pic.update_call(
+            canister,
+            admin,
+            "set_admin_bypass",
+            encode_args((true,)).unwrap(),
+        )
+        .expect("Failed to enable admin bypass");
+    }
+
+    let config = CyclesConversionConfig {
+        swap_canister: Some(swap_canister),
+        cmc_canister,
+    };
+    let pool_transfer = PoolTransferArgs {
+        from_pool: "reserve".to_string(),
+        to_pool: Some("dapp".to_string()),
+        to_principal: None,
+        amount: Nat::from(1_000_000u64),
+        description: "Fund cycles".to_string(),
+        memo: None,
+    };
+    for (method, args) in [
+        (
+            "set_cycles_conversion_config",
+            encode_args((config,)).unwrap(),
+        ),
+        ("admin_transfer", encode_args((pool_transfer,)).unwrap()),
+        (
+            "grant_role",
+            encode_args((Role::CyclesRequester, chronolock)).unwrap(),
+        ),
+    ] {
+        pic.update_call(ledger, admin, method, args)
+            .unwrap_or_else(|e| panic!("Failed to call {}: {:?}", method, e));
+    }
+
+    (pic, ledger, chronolock, cmc_canister, admin)
+}
+
+fn cycles_stats(
+    pic: &PocketIc,
+    chronolock: Principal,
+    caller: Principal,
+) -> Result<CyclesStats, ChronoError> {
+    decode_one(
+        &pic.query_call(
+            chronolock,
+            caller,
+            "get_cycles_stats",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap()
+}
+
+fn set_cycles_config(
+    pic: &PocketIc,
+    chronolock: Principal,
+    caller: Principal,
+    config: CyclesConfig,
+) -> Result<(), ChronoError> {
+    decode_one(
+        &pic.update_call(
+            chronolock,
+            caller,
+            "set_cycles_config",
+            encode_args((config,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_cycles_config_and_stats_are_restricted() {
+    let (pic, _ledger, chronolock, _cmc, admin) = setup();
+    let stranger = create_ii_principal(2);
+
+    let config: CyclesConfig = decode_one(
+        &pic.query_call(
+            chronolock,
+            Principal::anonymous(),
+            "get_cycles_config",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(config.top_up_amount, 0);
+    assert_eq!(config.vetkd_derive_cycles, 30_000_000_000);
+
+    assert_eq!(
+        set_cycles_config(&pic, chronolock, stranger, config.clone()),
+        Err(ChronoError::AdminRequired)
+    );
+    assert_eq!(
+        set_cycles_config(
+            &pic,
+            chronolock,
+            admin,
+            CyclesConfig {
+                check_interval: 0,
+                ..config
+            }
+        ),
+        Err(ChronoError::InvalidInput(
+            "Check interval must be positive".to_string()
+        ))
+    );
+    assert!(matches!(
+        cycles_stats(&pic, chronolock, stranger),
+        Err(ChronoError::AdminRequired)
+    ));
+
+    let stats = cycles_stats(&pic, chronolock, admin).unwrap();
+    assert!(stats.balance > Nat::from(0u64));
+    assert_eq!(stats.low_water_mark, Nat::from(1_000_000_000_000u64));
+    assert!(stats.operations.is_empty());
+    assert!(stats.last_top_up.is_none());
+}
+
+#[test]
+fn test_low_balance_requests_top_up_from_ledger() {
+    let (pic, ledger, chronolock, cmc_canister, admin) = setup();
+
+    // A low-water mark above the balance makes the next check request a top-up.
+    let config = CyclesConfig {
+        low_water_mark: 1_000_000_000_000_000,
+        top_up_amount: 50_000,
+        check_interval: 60,
+        vetkd_derive_cycles: 30_000_000_000,
+    };
+    assert_eq!(set_cycles_config(&pic, chronolock, admin, config), Ok(()));
+    let dapp_funds_before: Nat = decode_one(
+        &pic.query_call(
+            ledger,
+            Principal::anonymous(),
+            "get_dapp_funds",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    pic.advance_time(Duration::from_secs(61));
+    for _ in 0..10 {
+        pic.tick();
+    }
+
+    let top_ups: Vec<NotifyTopUpArg> = decode_one(
+        &pic.query_call(
+            cmc_canister,
+            Principal::anonymous(),
+            "get_top_ups",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(top_ups.len(), 1);
+    assert_eq!(top_ups[0].canister_id, chronolock);
+
+    let dapp_funds: Nat = decode_one(
+        &pic.query_call(
+            ledger,
+            Principal::anonymous(),
+            "get_dapp_funds",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(dapp_funds, dapp_funds_before - Nat::from(50_000u64));
+
+    let stats = cycles_stats(&pic, chronolock, admin).unwrap();
+    let last_top_up = stats.last_top_up.expect("A top-up should be recorded");
+    assert_eq!(last_top_up.amount, Nat::from(50_000u64));
+    assert!(
+        last_top_up.outcome.contains("Completed"),
+        "Unexpected outcome: {}",
+        last_top_up.outcome
+    );
+
+    // The alert is written to the activity log.
+    let logs: Result<Vec<LogEntry>, ChronoError> = decode_one(
+        &pic.query_call(
+            chronolock,
+            admin,
+            "get_logs_paginated",
+            encode_args((0u64, 100u64)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(logs
+        .unwrap()
+        .iter()
+        .any(|entry| entry.activity.starts_with("Low cycles")));
+}
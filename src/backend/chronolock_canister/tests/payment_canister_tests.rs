@@ -0,0 +1,520 @@
+// src/backend/chronolock/tests/payment_canister_tests.rs
+
+use candid::{decode_one, encode_args, CandidType, Nat, Principal};
+use pocket_ic::PocketIc;
+use serde::Deserialize;
+use std::fs;
+
+const BACKEND_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/chronolock_canister.wasm";
+const LEDGER_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/crnl_ledger_canister.wasm";
+
+const COMMUNITY_POOL_SUBACCOUNT: [u8; 32] = [1u8; 32];
+const TRANSFER_FEE: u128 = 100_000;
+const MINT_PRICE: u64 = 20_000_000_000; // 200 CRNL
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct UserKey {
+    user: String,
+    key: String,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum ChronoError {
+    Unauthorized,
+    TokenNotFound,
+    MetadataTooLarge,
+    TimeLocked,
+    InvalidInput(String),
+    InternalError(String),
+    NotAuthenticated,
+    AdminRequired,
+    InvalidPrincipal,
+    UnauthorizedCaller,
+    PaymentFailed(String),
+    InvalidRoleChange(String),
+    RateLimited { retry_after: u64 },
+    Unavailable(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<[u8; 32]>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferArgs {
+    from_subaccount: Option<[u8; 32]>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ApproveArgs {
+    from_subaccount: Option<[u8; 32]>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+fn create_ii_principal(seed: u8) -> Principal {
+    let mut bytes = [0u8; 10];
+    bytes[0] = seed;
+    bytes[9] = 0x01;
+    Principal::from_slice(&bytes)
+}
+
+// Deploys the ledger and a chronolock canister wired to it with a mint price.
+fn setup() -> (PocketIc, Principal, Principal, Principal) {
+    let pic = PocketIc::new();
+    let admin = create_ii_principal(1);
+
+    let ledger = pic.create_canister();
+    pic.add_cycles(ledger, 2_000_000_000_000);
+    let ledger_wasm = fs::read(LEDGER_WASM).expect("Ledger wasm not found, run 'cargo build'.");
+    let ledger_args = encode_args((
+        "Chronolock".to_string(),
+        "CRNL".to_string(),
+        100_000_000_000_000_000_000_u128,
+        31_536_000_u64,
+        TRANSFER_FEE,
+        admin,
+    ))
+    .expect("Failed to encode ledger init arguments");
+    pic.install_canister(ledger, ledger_wasm, ledger_args, None);
+
+    let chronolock = pic.create_canister();
+    pic.add_cycles(chronolock, 2_000_000_000_000);
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'cargo build'.");
+    let init_args = encode_args((admin, Some("local".to_string()), Some(ledger)))
+        .expect("Failed to encode init arguments");
+    pic.install_canister(chronolock, wasm, init_args, None);
+
+    for canister in [ledger, chronolock] {
+        pic.update_call(
+            canister,
+            admin,
+            "set_admin_bypass",
+            encode_args((true,)).unwrap(),
+        )
+        .expect("Failed to enable admin bypass");
+    }
+
+    let result: Result<(), ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            admin,
+            "set_mint_price",
+            encode_args((MINT_PRICE,)).unwrap(),
+        )
+        .expect("Failed to set mint price"),
+    )
+    .unwrap();
+    assert!(result.is_ok());
+
+    (pic, ledger, chronolock, admin)
+}
+
+fn balance_of(pic: &PocketIc, ledger: Principal, owner: Principal) -> Nat {
+    let account = Account {
+        owner,
+        subaccount: None,
+    };
+    decode_one(
+        &pic.query_call(
+            ledger,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            encode_args((account,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap()
+}
+
+// Funds the user from the community pool and approves the chronolock canister.
+fn fund_and_approve(
+    pic: &PocketIc,
+    ledger: Principal,
+    chronolock: Principal,
+    admin: Principal,
+    user: Principal,
+    allowance: u128,
+) {
+    let transfer_args = TransferArgs {
+        from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+        to: Account {
+            owner: user,
+            subaccount: None,
+        },
+        amount: Nat::from(1_000_000_000_000_u128),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    pic.update_call(
+        ledger,
+        admin,
+        "icrc1_transfer",
+        encode_args((transfer_args,)).unwrap(),
+    )
+    .expect("Failed to fund user");
+
+    let approve_args = ApproveArgs {
+        from_subaccount: None,
+        spender: Account {
+            owner: chronolock,
+            subaccount: None,
+        },
+        amount: Nat::from(allowance),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    pic.update_call(
+        ledger,
+        user,
+        "icrc2_approve",
+        encode_args((approve_args,)).unwrap(),
+    )
+    .expect("Failed to approve chronolock canister");
+}
+
+fn create_args(encrypted_metadata: &str) -> Vec<u8> {
+    let user_keys = vec![UserKey {
+        user: "public".to_string(),
+        key: "key".to_string(),
+    }];
+    encode_args((
+        "Paid lock".to_string(),
+        1_700_000_000_u64,
+        user_keys,
+        encrypted_metadata.to_string(),
+    ))
+    .unwrap()
+}
+
+fn total_chronolocks(pic: &PocketIc, chronolock: Principal) -> u64 {
+    decode_one(
+        &pic.query_call(
+            chronolock,
+            Principal::anonymous(),
+            "get_total_chronolocks_count",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_create_chronolock_pulls_mint_price() {
+    let (pic, ledger, chronolock, admin) = setup();
+    let user = create_ii_principal(2);
+    fund_and_approve(
+        &pic,
+        ledger,
+        chronolock,
+        admin,
+        user,
+        MINT_PRICE as u128 + TRANSFER_FEE,
+    );
+    let balance_before = balance_of(&pic, ledger, user);
+
+    let result: Result<String, ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            user,
+            "create_chronolock",
+            create_args("metadata"),
+        )
+        .expect("Failed to call create_chronolock"),
+    )
+    .unwrap();
+    assert!(result.is_ok(), "Paid mint should succeed: {:?}", result);
+    assert_eq!(total_chronolocks(&pic, chronolock), 1);
+
+    // The user paid the price plus the ledger fee; the canister received the price.
+    assert_eq!(
+        balance_of(&pic, ledger, user),
+        balance_before - Nat::from(MINT_PRICE as u128 + TRANSFER_FEE)
+    );
+    assert_eq!(balance_of(&pic, ledger, chronolock), Nat::from(MINT_PRICE));
+
+    // The allowance is used up, so a second mint is rejected without creating a lock.
+    let result: Result<String, ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            user,
+            "create_chronolock",
+            create_args("metadata"),
+        )
+        .expect("Failed to call create_chronolock"),
+    )
+    .unwrap();
+    assert!(matches!(result, Err(ChronoError::PaymentFailed(_))));
+    assert_eq!(total_chronolocks(&pic, chronolock), 1);
+}
+
+#[test]
+fn test_create_chronolock_without_allowance_is_rejected() {
+    let (pic, _ledger, chronolock, _admin) = setup();
+    let user = create_ii_principal(3);
+
+    let result: Result<String, ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            user,
+            "create_chronolock",
+            create_args("metadata"),
+        )
+        .expect("Failed to call create_chronolock"),
+    )
+    .unwrap();
+    assert!(matches!(result, Err(ChronoError::PaymentFailed(_))));
+    assert_eq!(total_chronolocks(&pic, chronolock), 0);
+}
+
+#[test]
+fn test_create_chronolock_refunds_on_failure() {
+    let (pic, ledger, chronolock, admin) = setup();
+    let user = create_ii_principal(4);
+    fund_and_approve(
+        &pic,
+        ledger,
+        chronolock,
+        admin,
+        user,
+        MINT_PRICE as u128 + TRANSFER_FEE,
+    );
+    let balance_before = balance_of(&pic, ledger, user);
+
+    // Lower the metadata limit while the payment is in flight so that storing
+    // the chronolock fails after the price was collected.
+    let message_id = pic
+        .submit_call(
+            chronolock,
+            user,
+            "create_chronolock",
+            create_args("metadata"),
+        )
+        .expect("Failed to submit create_chronolock");
+    pic.update_call(
+        chronolock,
+        admin,
+        "set_max_metadata_size",
+        encode_args((1u64,)).unwrap(),
+    )
+    .expect("Failed to set max metadata size");
+    let result: Result<String, ChronoError> = decode_one(
+        &pic.await_call(message_id)
+            .expect("create_chronolock failed"),
+    )
+    .unwrap();
+    assert_eq!(result, Err(ChronoError::MetadataTooLarge));
+    assert_eq!(total_chronolocks(&pic, chronolock), 0);
+
+    // The price came back minus the fees of the payment and of the refund.
+    assert_eq!(balance_of(&pic, ledger, chronolock), Nat::from(0u64));
+    assert_eq!(
+        balance_of(&pic, ledger, user),
+        balance_before - Nat::from(2 * TRANSFER_FEE)
+    );
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct DerivationPricing {
+    price: u64,
+    free_per_lock: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct RateLimit {
+    capacity: u32,
+    refill_interval: u64,
+}
+
+fn free_derivations_remaining(
+    pic: &PocketIc,
+    chronolock: Principal,
+    token_id: &str,
+    user: Principal,
+) -> u32 {
+    decode_one(
+        &pic.query_call(
+            chronolock,
+            Principal::anonymous(),
+            "get_free_derivations_remaining",
+            encode_args((token_id.to_string(), user)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap()
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct VetKDDeriveKeyReply {
+    encrypted_key: Vec<u8>,
+}
+
+#[test]
+fn test_derivation_pricing_and_rate_limit() {
+    let (pic, ledger, chronolock, admin) = setup();
+    let user = create_ii_principal(5);
+    let stranger = create_ii_principal(6);
+    fund_and_approve(
+        &pic,
+        ledger,
+        chronolock,
+        admin,
+        user,
+        MINT_PRICE as u128 + TRANSFER_FEE,
+    );
+    let token_id: String = decode_one::<Result<String, ChronoError>>(
+        &pic.update_call(
+            chronolock,
+            user,
+            "create_chronolock",
+            create_args("metadata"),
+        )
+        .expect("Failed to call create_chronolock"),
+    )
+    .unwrap()
+    .expect("Paid mint should succeed");
+
+    let pricing = DerivationPricing {
+        price: 1_000_000,
+        free_per_lock: 1,
+    };
+    let result: Result<(), ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            user,
+            "set_derivation_pricing",
+            encode_args((pricing.clone(),)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(result, Err(ChronoError::AdminRequired));
+    let result: Result<(), ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            admin,
+            "set_derivation_pricing",
+            encode_args((pricing,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(result, Ok(()));
+    let limit = RateLimit {
+        capacity: 3,
+        refill_interval: 60,
+    };
+    let result: Result<(), ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            admin,
+            "set_rate_limit",
+            encode_args(("get_time_decryption_key".to_string(), Some(limit))).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(result, Ok(()));
+
+    // Only the owner and the recipients of a lock get free derivations.
+    assert_eq!(
+        free_derivations_remaining(&pic, chronolock, &token_id, user),
+        1
+    );
+    assert_eq!(
+        free_derivations_remaining(&pic, chronolock, &token_id, stranger),
+        0
+    );
+
+    // Everyone else pays through an allowance, which the stranger does not have.
+    let unlock_time_hex = format!("{:016x}", 1_700_000_000_u64);
+    let derive = |caller: Principal, token_id: Option<String>| {
+        decode_one::<Result<VetKDDeriveKeyReply, ChronoError>>(
+            &pic.update_call(
+                chronolock,
+                caller,
+                "get_time_decryption_key",
+                encode_args((unlock_time_hex.clone(), vec![1u8; 48], token_id)).unwrap(),
+            )
+            .expect("Failed to call get_time_decryption_key"),
+        )
+        .unwrap()
+    };
+    for _ in 0..3 {
+        assert!(matches!(
+            derive(stranger, Some(token_id.clone())),
+            Err(ChronoError::PaymentFailed(_))
+        ));
+    }
+
+    // The stranger's bucket is empty; other principals are unaffected.
+    assert!(matches!(
+        derive(stranger, None),
+        Err(ChronoError::RateLimited { retry_after }) if retry_after > 0 && retry_after <= 60
+    ));
+    assert!(!matches!(
+        derive(user, Some(token_id.clone())),
+        Err(ChronoError::PaymentFailed(_)) | Err(ChronoError::RateLimited { .. })
+    ));
+
+    pic.advance_time(std::time::Duration::from_secs(61));
+    assert!(matches!(
+        derive(stranger, None),
+        Err(ChronoError::PaymentFailed(_))
+    ));
+
+    // Anonymous callers cannot pay, so their ingress is refused while
+    // derivations have a price.
+    assert!(pic
+        .update_call(
+            chronolock,
+            Principal::anonymous(),
+            "get_time_decryption_key",
+            encode_args((unlock_time_hex.clone(), vec![1u8; 48], None::<String>)).unwrap(),
+        )
+        .is_err());
+
+    // Free derivations are open to anonymous callers, who do not share a bucket.
+    let result: Result<(), ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            admin,
+            "set_derivation_pricing",
+            encode_args((DerivationPricing {
+                price: 0,
+                free_per_lock: 1,
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(result, Ok(()));
+    for _ in 0..4 {
+        assert!(!matches!(
+            derive(Principal::anonymous(), None),
+            Err(ChronoError::PaymentFailed(_))
+                | Err(ChronoError::RateLimited { .. })
+                | Err(ChronoError::NotAuthenticated)
+        ));
+    }
+}
@@ -0,0 +1,242 @@
+// src/backend/chronolock/tests/upgrade_canister_tests.rs
+
+use candid::{decode_one, encode_args, CandidType, Principal};
+use pocket_ic::PocketIc;
+use serde::Deserialize;
+use std::fs;
+
+const BACKEND_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/chronolock_canister.wasm";
+// Built from an earlier revision by build_legacy_wasm.sh
+const LEGACY_WASM: &str = "../../../target/legacy/chronolock_canister.wasm";
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum ChronoError {
+    Unauthorized,
+    TokenNotFound,
+    MetadataTooLarge,
+    TimeLocked,
+    InvalidInput(String),
+    InternalError(String),
+    NotAuthenticated,
+    AdminRequired,
+    InvalidPrincipal,
+    UnauthorizedCaller,
+    PaymentFailed(String),
+    InvalidRoleChange(String),
+    RateLimited { retry_after: u64 },
+    Unavailable(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct UserKey {
+    user: String,
+    key: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Chronolock {
+    id: String,
+    owner: Principal,
+    title: String,
+    unlock_time: u64,
+    created_at: u64,
+    user_keys: Vec<UserKey>,
+    encrypted_metadata: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct QuarantinedValue {
+    id: u64,
+    type_name: String,
+    bytes: Vec<u8>,
+    error: String,
+    detected_at: u64,
+}
+
+fn create_ii_principal(seed: u8) -> Principal {
+    let mut bytes = [0u8; 10];
+    bytes[0] = seed;
+    bytes[9] = 0x01;
+    Principal::from_slice(&bytes)
+}
+
+fn read_wasm(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|_| {
+        panic!(
+            "{} not found, run 'bash build_legacy_wasm.sh' and 'cargo build --target wasm32-unknown-unknown --release'.",
+            path
+        )
+    })
+}
+
+fn query<T: CandidType + for<'de> Deserialize<'de>>(
+    pic: &PocketIc,
+    canister: Principal,
+    caller: Principal,
+    method: &str,
+    args: Vec<u8>,
+) -> T {
+    decode_one(
+        &pic.query_call(canister, caller, method, args)
+            .unwrap_or_else(|e| panic!("Failed to query {}: {:?}", method, e)),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_upgrade_from_legacy_chronolock_keeps_state() {
+    std::env::set_var("POCKET_IC_BIN", "/usr/local/bin/pocket-ic");
+    let pic = PocketIc::new();
+    let admin = create_ii_principal(1);
+    let user = create_ii_principal(2);
+
+    // The legacy canister stores values as bare Candid and token lists as a
+    // plain vector of ids.
+    let chronolock = pic.create_canister();
+    pic.add_cycles(chronolock, 2_000_000_000_000);
+    pic.install_canister(
+        chronolock,
+        read_wasm(LEGACY_WASM),
+        encode_args((admin, Some("local".to_string()))).unwrap(),
+        None,
+    );
+    pic.update_call(
+        chronolock,
+        admin,
+        "set_admin_bypass",
+        encode_args((true,)).unwrap(),
+    )
+    .expect("Failed to enable admin bypass");
+
+    let user_keys = vec![UserKey {
+        user: user.to_text(),
+        key: "encrypted_key".to_string(),
+    }];
+    let created: Result<String, ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            user,
+            "create_chronolock",
+            encode_args((
+                "Before upgrade".to_string(),
+                4_102_444_800u64,
+                user_keys.clone(),
+                "encrypted_metadata".to_string(),
+            ))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let token_id = created.unwrap();
+
+    pic.upgrade_canister(
+        chronolock,
+        read_wasm(BACKEND_WASM),
+        encode_args(()).unwrap(),
+        None,
+    )
+    .expect("Upgrade from the legacy canister failed");
+
+    let lock: Result<Chronolock, ChronoError> = query(
+        &pic,
+        chronolock,
+        user,
+        "get_chronolock",
+        encode_args((token_id.clone(),)).unwrap(),
+    );
+    let lock = lock.unwrap();
+    assert_eq!(lock.owner, user);
+    assert_eq!(lock.title, "Before upgrade");
+    assert_eq!(lock.user_keys, user_keys);
+
+    let balance: u64 = query(
+        &pic,
+        chronolock,
+        Principal::anonymous(),
+        "icrc7_balance_of",
+        encode_args((user,)).unwrap(),
+    );
+    assert_eq!(balance, 1);
+    let owned: Result<Vec<Chronolock>, ChronoError> = query(
+        &pic,
+        chronolock,
+        user,
+        "get_owner_chronolocks_paginated",
+        encode_args((user, 0u64, 10u64)).unwrap(),
+    );
+    assert_eq!(owned.unwrap()[0].id, token_id);
+
+    let symbol: String = query(
+        &pic,
+        chronolock,
+        Principal::anonymous(),
+        "icrc7_symbol",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(symbol, "CHRONOLOCK");
+    let schema_version: u32 = query(
+        &pic,
+        chronolock,
+        Principal::anonymous(),
+        "get_schema_version",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(schema_version, 4);
+    // Creators from before the upgrade qualify referrals claimed before their
+    // chronolocks were created, and none claimed since.
+    let qualified: bool = query(
+        &pic,
+        chronolock,
+        Principal::anonymous(),
+        "has_qualifying_action",
+        encode_args((user, 0u64)).unwrap(),
+    );
+    assert!(qualified);
+    let qualified: bool = query(
+        &pic,
+        chronolock,
+        Principal::anonymous(),
+        "has_qualifying_action",
+        encode_args((user, pic.get_time().as_nanos_since_unix_epoch() + 1)).unwrap(),
+    );
+    assert!(!qualified);
+    let quarantined: Result<Vec<QuarantinedValue>, ChronoError> = query(
+        &pic,
+        chronolock,
+        admin,
+        "get_quarantined_values",
+        encode_args((0u64, 100u64)).unwrap(),
+    );
+    assert!(quarantined.unwrap().is_empty());
+
+    // Burning rewrites the token list with the version prefix; it still reads
+    // back after another upgrade.
+    let burned: Result<(), ChronoError> = decode_one(
+        &pic.update_call(
+            chronolock,
+            user,
+            "burn_chronolock",
+            encode_args((token_id,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(burned, Ok(()));
+    pic.upgrade_canister(
+        chronolock,
+        read_wasm(BACKEND_WASM),
+        encode_args(()).unwrap(),
+        None,
+    )
+    .expect("Second upgrade failed");
+    let balance: u64 = query(
+        &pic,
+        chronolock,
+        Principal::anonymous(),
+        "icrc7_balance_of",
+        encode_args((user,)).unwrap(),
+    );
+    assert_eq!(balance, 0);
+}
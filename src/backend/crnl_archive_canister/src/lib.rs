@@ -0,0 +1,470 @@
+// src/backend/crnl_archive_canister/src/lib.rs
+
+use candid::{CandidType, Int, Nat, Principal};
+use ic_cdk::api::{is_controller, time};
+use ic_cdk::caller;
+use ic_cdk_macros::{init, post_upgrade, query, update};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Define memory type for stable structures
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// -------------------------
+// Data Structures
+// -------------------------
+
+// ICRC-3 generic value, as produced by the ledger for each block
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Storable for Value {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Log entry moved out of the ledger's administrative log
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct LogEntry {
+    timestamp: u64,
+    event_type: String,
+    details: String,
+}
+
+impl Storable for LogEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
+    };
+}
+
+// Ledger that owns this archive and the global index of the first archived block
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct ArchiveConfig {
+    ledger_id: Principal,
+    block_offset: u64,
+}
+
+impl Storable for ArchiveConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct BlockWithId {
+    id: Nat,
+    block: Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: GetBlocksCallback,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>,
+}
+
+// Range of blocks held by this archive
+#[derive(CandidType, Serialize, Deserialize)]
+struct ArchiveInfo {
+    ledger_id: Principal,
+    start: u64,
+    end: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+enum ArchiveError {
+    Unauthorized,
+}
+
+// -------------------------
+// Versioned Encoding
+// -------------------------
+
+// Values in stable memory are written as a version byte followed by their
+// Candid encoding. Values written before the version byte was introduced start
+// with the Candid magic bytes and are read as version 0.
+const CANDID_MAGIC: &[u8] = b"DIDL";
+
+trait Versioned: CandidType + for<'de> Deserialize<'de> {
+    // Version written by this code. Bump it and handle the previous version in
+    // decode_version when a change cannot be absorbed by Candid subtyping.
+    const VERSION: u8 = 1;
+
+    // Decodes a payload written with the given version.
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, String> {
+        if version > Self::VERSION {
+            return Err(format!("Unknown version {}", version));
+        }
+        candid::decode_one(payload).map_err(|e| e.to_string())
+    }
+
+    // Stand-in returned for a value that cannot be decoded. Its bytes are kept
+    // in the quarantine so that the value can be repaired.
+    fn quarantined() -> Self;
+}
+
+fn encode_versioned<T: Versioned>(value: &T) -> Cow<'static, [u8]> {
+    let mut bytes = vec![T::VERSION];
+    bytes.extend(candid::encode_one(value).expect("Failed to encode stable value"));
+    Cow::Owned(bytes)
+}
+
+fn decode_versioned<T: Versioned>(bytes: &[u8]) -> T {
+    let decoded = if bytes.starts_with(CANDID_MAGIC) {
+        T::decode_version(0, bytes)
+    } else {
+        match bytes.split_first() {
+            Some((version, payload)) => T::decode_version(*version, payload),
+            None => Err("Empty value".to_string()),
+        }
+    };
+    decoded.unwrap_or_else(|error| {
+        quarantine_value(std::any::type_name::<T>(), bytes, error);
+        T::quarantined()
+    })
+}
+
+// Stored value that could not be decoded, kept for inspection and repair
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct QuarantinedValue {
+    id: u64,
+    type_name: String,
+    bytes: Vec<u8>,
+    error: String,
+    detected_at: u64,
+}
+
+// The quarantine itself is plain Candid: it is the sink for decode failures.
+// An entry that cannot be decoded is returned with its own raw bytes.
+impl Storable for QuarantinedValue {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode QuarantinedValue"))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap_or_else(|e| QuarantinedValue {
+            id: 0,
+            type_name: "QuarantinedValue".to_string(),
+            bytes: bytes.to_vec(),
+            error: e.to_string(),
+            detected_at: 0,
+        })
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// A stand-in block would not hash to the parent hash of its successor, so a
+// block that cannot be decoded traps, rejecting the get_blocks call that read it.
+impl Versioned for Value {
+    fn quarantined() -> Self {
+        ic_cdk::trap("Stored block cannot be decoded; refusing to serve a stand-in")
+    }
+}
+
+impl Versioned for LogEntry {
+    fn quarantined() -> Self {
+        LogEntry {
+            timestamp: 0,
+            event_type: "Quarantined".to_string(),
+            details: "Log entry could not be decoded".to_string(),
+        }
+    }
+}
+
+// Without its ledger and offset the archive cannot serve or accept blocks.
+impl Versioned for ArchiveConfig {
+    fn quarantined() -> Self {
+        ic_cdk::trap("Stored ArchiveConfig cannot be decoded; refusing to continue without it")
+    }
+}
+
+// -------------------------
+// Stable Storage
+// -------------------------
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static CONFIG: RefCell<StableCell<ArchiveConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(0))),
+            ArchiveConfig {
+                ledger_id: Principal::anonymous(),
+                block_offset: 0,
+            },
+        )
+        .expect("Failed to initialize CONFIG")
+    );
+
+    static BLOCKS: RefCell<StableLog<Value, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(1))),
+            MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(2))),
+        )
+        .expect("Failed to initialize BLOCKS")
+    );
+
+    static LOGS: RefCell<StableBTreeMap<u64, LogEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(3))))
+    );
+
+    static QUARANTINE: RefCell<StableBTreeMap<u64, QuarantinedValue, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(4))))
+    );
+    // Quarantine ids by the hash of the value's type and bytes
+    static QUARANTINE_INDEX: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(5))))
+    );
+}
+
+// Maximum number of blocks returned by a single get_blocks call.
+const MAX_BLOCKS_PER_QUERY: u64 = 1_000;
+
+// -------------------------
+// Helper Functions
+// -------------------------
+
+fn config() -> ArchiveConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Only the ledger that spawned the archive may append to it.
+fn ensure_ledger() {
+    if caller() != config().ledger_id {
+        ic_cdk::trap("Only the ledger can append to the archive");
+    }
+}
+
+fn archive_end() -> u64 {
+    config().block_offset + BLOCKS.with(|b| b.borrow().len())
+}
+
+// Keeps the bytes of a stored value that could not be decoded. The same value
+// fails again on every read, so it is recorded once, looked up by the hash of
+// its type and bytes; the quarantine grows with the number of distinct
+// undecodable values, not with the number of reads.
+//
+// A query's changes are discarded, so a value first read by a query is not
+// kept; it is quarantined when an update call or an upgrade reads it.
+fn quarantine_value(type_name: &str, bytes: &[u8], error: String) {
+    let key = quarantine_key(type_name, bytes);
+    if QUARANTINE_INDEX.with(|i| i.borrow().contains_key(&key)) {
+        return;
+    }
+    let id = QUARANTINE.with(|q| {
+        let mut q = q.borrow_mut();
+        let id = q.last_key_value().map_or(0, |(id, _)| id + 1);
+        q.insert(
+            id,
+            QuarantinedValue {
+                id,
+                type_name: type_name.to_string(),
+                bytes: bytes.to_vec(),
+                error,
+                detected_at: time(),
+            },
+        );
+        id
+    });
+    QUARANTINE_INDEX.with(|i| i.borrow_mut().insert(key, id));
+}
+
+fn quarantine_key(type_name: &str, bytes: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((type_name.len() as u64).to_be_bytes());
+    hasher.update(type_name.as_bytes());
+    hasher.update(bytes);
+    hasher.finalize().into()
+}
+
+// Indexes quarantined values recorded before QUARANTINE_INDEX existed.
+fn index_quarantine() {
+    if QUARANTINE_INDEX.with(|i| i.borrow().len()) == QUARANTINE.with(|q| q.borrow().len()) {
+        return;
+    }
+    QUARANTINE.with(|q| {
+        QUARANTINE_INDEX.with(|i| {
+            let mut i = i.borrow_mut();
+            for (id, value) in q.borrow().iter() {
+                i.insert(quarantine_key(&value.type_name, &value.bytes), id);
+            }
+        })
+    });
+}
+
+// -------------------------
+// Canister Initialization
+// -------------------------
+
+#[init]
+fn init(ledger_id: Principal, block_offset: u64) {
+    CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(ArchiveConfig {
+                ledger_id,
+                block_offset,
+            })
+            .expect("Failed to set CONFIG")
+    });
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    index_quarantine();
+}
+
+// -------------------------
+// Update Functions
+// -------------------------
+
+// Appends blocks starting at ledger index start and returns the new end of the
+// archived range. Blocks the archive already holds are skipped, so the ledger can
+// retry a call whose reply was lost; a start beyond the archived range traps.
+#[update]
+fn append_blocks(start: u64, blocks: Vec<Value>) -> u64 {
+    ensure_ledger();
+    let end = archive_end();
+    if start > end {
+        ic_cdk::trap(&format!(
+            "Blocks must start at {} or earlier, got {}",
+            end, start
+        ));
+    }
+    let held = (end - start) as usize;
+    BLOCKS.with(|log| {
+        let log = log.borrow_mut();
+        for block in blocks.iter().skip(held) {
+            log.append(block).expect("Failed to append block");
+        }
+    });
+    archive_end()
+}
+
+#[update]
+fn append_logs(entries: Vec<(u64, LogEntry)>) {
+    ensure_ledger();
+    LOGS.with(|logs| {
+        let mut logs = logs.borrow_mut();
+        for (key, entry) in entries {
+            logs.insert(key, entry);
+        }
+    });
+}
+
+// -------------------------
+// Query Functions
+// -------------------------
+
+#[query]
+fn get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let offset = config().block_offset;
+    let end = archive_end();
+    let mut blocks = Vec::new();
+    for range in args {
+        let (Some(start), Some(length)) = (range.start.0.to_u64(), range.length.0.to_u64()) else {
+            continue;
+        };
+        let remaining = MAX_BLOCKS_PER_QUERY.saturating_sub(blocks.len() as u64);
+        let range_end = start.saturating_add(length.min(remaining)).min(end);
+        for id in start.max(offset)..range_end {
+            if let Some(block) = BLOCKS.with(|b| b.borrow().get(id - offset)) {
+                blocks.push(BlockWithId {
+                    id: Nat::from(id),
+                    block,
+                });
+            }
+        }
+    }
+    GetBlocksResult {
+        log_length: Nat::from(end),
+        blocks,
+        archived_blocks: Vec::new(),
+    }
+}
+
+#[query]
+fn get_archive_info() -> ArchiveInfo {
+    ArchiveInfo {
+        ledger_id: config().ledger_id,
+        start: config().block_offset,
+        end: archive_end(),
+    }
+}
+
+// Archived log entries are restricted to the controllers (the ledger and its admin).
+#[query]
+fn get_logs_paginated(offset: u64, limit: u64) -> Result<Vec<LogEntry>, ArchiveError> {
+    if !is_controller(&caller()) {
+        return Err(ArchiveError::Unauthorized);
+    }
+    Ok(LOGS.with(|logs| {
+        logs.borrow()
+            .iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(_, entry)| entry)
+            .collect()
+    }))
+}
+
+// Values that could not be decoded, restricted to the controllers.
+#[query]
+fn get_quarantined_values(offset: u64, limit: u64) -> Result<Vec<QuarantinedValue>, ArchiveError> {
+    if !is_controller(&caller()) {
+        return Err(ArchiveError::Unauthorized);
+    }
+    Ok(QUARANTINE.with(|q| {
+        q.borrow()
+            .iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(_, value)| value)
+            .collect()
+    }))
+}
+
+// Export Candid interface
+ic_cdk::export_candid!();
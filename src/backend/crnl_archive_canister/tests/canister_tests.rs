@@ -0,0 +1,251 @@
+// src/backend/crnl_archive_canister/tests/canister_tests.rs
+
+use candid::{decode_one, encode_args, CandidType, Nat, Principal};
+use pocket_ic::PocketIc;
+use std::fs;
+
+const ARCHIVE_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/crnl_archive_canister.wasm";
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct BlockWithId {
+    id: Nat,
+    block: Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: GetBlocksCallback,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ArchiveInfo {
+    ledger_id: Principal,
+    start: u64,
+    end: u64,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct LogEntry {
+    timestamp: u64,
+    event_type: String,
+    details: String,
+}
+
+#[derive(CandidType, serde::Deserialize, Debug, PartialEq)]
+enum ArchiveError {
+    Unauthorized,
+}
+
+fn setup(block_offset: u64) -> (PocketIc, Principal, Principal) {
+    std::env::set_var("POCKET_IC_BIN", "/usr/local/bin/pocket-ic");
+    let pic = PocketIc::new();
+
+    let ledger = Principal::from_slice(&[7u8; 10]);
+    let archive_canister = pic.create_canister_with_settings(Some(ledger), None);
+    pic.add_cycles(archive_canister, 2_000_000_000_000);
+    let wasm = fs::read(ARCHIVE_WASM).expect("Wasm file not found, run 'cargo build'.");
+
+    let init_args =
+        encode_args((ledger, block_offset)).expect("Failed to encode init arguments");
+    pic.install_canister(archive_canister, wasm, init_args, Some(ledger));
+
+    (pic, archive_canister, ledger)
+}
+
+fn text_block(text: &str) -> Value {
+    Value::Map(vec![("btype".to_string(), Value::Text(text.to_string()))])
+}
+
+#[test]
+fn test_append_and_get_blocks() {
+    let (pic, archive_canister, ledger) = setup(10);
+
+    let blocks = vec![text_block("a"), text_block("b"), text_block("c")];
+    let response = pic
+        .update_call(
+            archive_canister,
+            ledger,
+            "append_blocks",
+            encode_args((10u64, blocks)).unwrap(),
+        )
+        .expect("Failed to append blocks");
+    let end: u64 = decode_one(&response).unwrap();
+    assert_eq!(end, 13);
+
+    let info: ArchiveInfo = decode_one(
+        &pic.query_call(
+            archive_canister,
+            Principal::anonymous(),
+            "get_archive_info",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(info.ledger_id, ledger);
+    assert_eq!((info.start, info.end), (10, 13));
+
+    // Ranges are expressed in ledger indices and clipped to the archived range.
+    let args = vec![GetBlocksArgs {
+        start: Nat::from(9u64),
+        length: Nat::from(3u64),
+    }];
+    let result: GetBlocksResult = decode_one(
+        &pic.query_call(
+            archive_canister,
+            Principal::anonymous(),
+            "get_blocks",
+            encode_args((args,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let ids: Vec<Nat> = result.blocks.iter().map(|b| b.id.clone()).collect();
+    assert_eq!(ids, vec![Nat::from(10u64), Nat::from(11u64)]);
+    assert_eq!(result.blocks[1].block, text_block("b"));
+    assert!(result.archived_blocks.is_empty());
+}
+
+#[test]
+fn test_append_blocks_skips_held_blocks() {
+    let (pic, archive_canister, ledger) = setup(10);
+
+    let append = |start: u64, blocks: Vec<Value>| {
+        pic.update_call(
+            archive_canister,
+            ledger,
+            "append_blocks",
+            encode_args((start, blocks)).unwrap(),
+        )
+        .map(|response| decode_one::<u64>(&response).unwrap())
+    };
+    assert_eq!(
+        append(10, vec![text_block("a"), text_block("b")]).unwrap(),
+        12
+    );
+
+    // A retried chunk overlapping the archived range only appends the new blocks.
+    assert_eq!(
+        append(10, vec![text_block("a"), text_block("b"), text_block("c")]).unwrap(),
+        13
+    );
+    assert_eq!(append(12, vec![text_block("c")]).unwrap(), 13);
+    assert!(
+        append(14, vec![text_block("e")]).is_err(),
+        "A chunk leaving a gap should trap"
+    );
+
+    let args = vec![GetBlocksArgs {
+        start: Nat::from(10u64),
+        length: Nat::from(10u64),
+    }];
+    let result: GetBlocksResult = decode_one(
+        &pic.query_call(
+            archive_canister,
+            Principal::anonymous(),
+            "get_blocks",
+            encode_args((args,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let blocks: Vec<Value> = result.blocks.into_iter().map(|b| b.block).collect();
+    assert_eq!(
+        blocks,
+        vec![text_block("a"), text_block("b"), text_block("c")]
+    );
+}
+
+#[test]
+fn test_only_ledger_can_append() {
+    let (pic, archive_canister, _ledger) = setup(0);
+
+    let stranger = Principal::from_slice(&[9u8; 10]);
+    let result = pic.update_call(
+        archive_canister,
+        stranger,
+        "append_blocks",
+        encode_args((0u64, vec![text_block("a")])).unwrap(),
+    );
+    assert!(result.is_err(), "Appending from a non-ledger caller should trap");
+
+    let result = pic.update_call(
+        archive_canister,
+        stranger,
+        "append_logs",
+        encode_args((Vec::<(u64, LogEntry)>::new(),)).unwrap(),
+    );
+    assert!(result.is_err(), "Appending logs from a non-ledger caller should trap");
+}
+
+#[test]
+fn test_archived_logs_restricted_to_controllers() {
+    let (pic, archive_canister, ledger) = setup(0);
+
+    let entries = vec![(
+        1u64,
+        LogEntry {
+            timestamp: 1,
+            event_type: "Init".to_string(),
+            details: "archived".to_string(),
+        },
+    )];
+    pic.update_call(
+        archive_canister,
+        ledger,
+        "append_logs",
+        encode_args((entries,)).unwrap(),
+    )
+    .expect("Failed to append logs");
+
+    let result: Result<Vec<LogEntry>, ArchiveError> = decode_one(
+        &pic.query_call(
+            archive_canister,
+            ledger,
+            "get_logs_paginated",
+            encode_args((0u64, 10u64)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(result.unwrap().len(), 1);
+
+    let result: Result<Vec<LogEntry>, ArchiveError> = decode_one(
+        &pic.query_call(
+            archive_canister,
+            Principal::anonymous(),
+            "get_logs_paginated",
+            encode_args((0u64, 10u64)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(result.unwrap_err(), ArchiveError::Unauthorized);
+}
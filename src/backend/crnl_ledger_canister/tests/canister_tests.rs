@@ -3,6 +3,7 @@
 use candid::{decode_one, encode_args, CandidType, Nat, Principal};
 use pocket_ic::PocketIc;
 use std::fs;
+use std::time::Duration;
 
 const COMMUNITY_POOL_SUBACCOUNT: [u8; 32] = [1u8; 32];
 const TEAM_VESTING_POOL_SUBACCOUNT: [u8; 32] = [2u8; 32];
@@ -10,6 +11,11 @@ const RESERVE_POOL_SUBACCOUNT: [u8; 32] = [3u8; 32];
 
 const BACKEND_WASM: &str =
     "../../../target/wasm32-unknown-unknown/release/crnl_ledger_canister.wasm";
+const ARCHIVE_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/crnl_archive_canister.wasm";
+const MOCK_SWAP_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/mock_swap_canister.wasm";
+const MOCK_CMC_WASM: &str = "../../../target/wasm32-unknown-unknown/release/mock_cmc_canister.wasm";
 
 #[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
 struct Account {
@@ -22,6 +28,9 @@ struct TransferArgs {
     from_subaccount: Option<[u8; 32]>,
     to: Account,
     amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
 }
 
 #[derive(CandidType, serde::Deserialize, Clone, Debug)]
@@ -34,6 +43,7 @@ struct DeductBalanceArgs {
     caller: Account,
     amount: Nat,
     description: String,
+    memo: Option<Vec<u8>>,
 }
 
 #[derive(CandidType, serde::Deserialize, Clone, Debug)]
@@ -43,6 +53,7 @@ struct PoolTransferArgs {
     to_principal: Option<Account>,
     amount: Nat,
     description: String,
+    memo: Option<Vec<u8>>,
 }
 
 #[derive(CandidType, serde::Deserialize, Clone, Debug)]
@@ -50,18 +61,83 @@ struct ApproveArgs {
     from_subaccount: Option<[u8; 32]>,
     spender: Account,
     amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
 }
 
 #[derive(CandidType, serde::Deserialize, Clone, Debug)]
 struct TransferFromArgs {
-    spender: Account,
+    spender_subaccount: Option<[u8; 32]>,
     from: Account,
     to: Account,
     amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct AllowanceArgs {
+    account: Account,
+    spender: Account,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+struct Allowance {
+    allowance: Nat,
+    expires_at: Option<u64>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct SupportedStandard {
+    name: String,
+    url: String,
+}
+
+#[derive(CandidType, serde::Deserialize, Debug, PartialEq)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, serde::Deserialize, Debug, PartialEq)]
+enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, serde::Deserialize, Debug, PartialEq)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
 }
 
 #[derive(CandidType, serde::Deserialize, Clone)]
 struct TransactionEvent {
+    index: u64,
     tx_id: [u8; 32],
     timestamp: u64,
     event_type: String,
@@ -70,6 +146,199 @@ struct TransactionEvent {
     spender: Option<Account>,
     amount: Nat,
     fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct GetAccountTransactionsArgs {
+    account: Account,
+    start: Option<Nat>,
+    max_results: Nat,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone)]
+struct TransactionWithId {
+    id: Nat,
+    transaction: TransactionEvent,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone)]
+struct GetTransactions {
+    balance: Nat,
+    transactions: Vec<TransactionWithId>,
+    oldest_tx_id: Option<Nat>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct GetTransactionsErr {
+    message: String,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct BlockWithId {
+    id: Nat,
+    block: Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: GetBlocksCallback,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct GetArchivesArgs {
+    from: Option<Principal>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ICRC3ArchiveInfo {
+    canister_id: Principal,
+    start: Nat,
+    end: Nat,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ICRC3DataCertificate {
+    certificate: Vec<u8>,
+    hash_tree: Vec<u8>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ArchiveOptions {
+    trigger_threshold: u64,
+    num_blocks_to_archive: u64,
+    max_log_entries: u64,
+    cycles_for_archive_creation: u64,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ConsentMessageMetadata {
+    language: String,
+    utc_offset_minutes: Option<i16>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+enum DisplayMessageType {
+    GenericDisplay,
+    LineDisplay {
+        characters_per_line: u16,
+        lines_per_page: u16,
+    },
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ConsentMessageSpec {
+    metadata: ConsentMessageMetadata,
+    device_spec: Option<DisplayMessageType>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ConsentMessageRequest {
+    method: String,
+    arg: Vec<u8>,
+    user_preferences: ConsentMessageSpec,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct LineDisplayPage {
+    lines: Vec<String>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+enum ConsentMessage {
+    GenericDisplayMessage(String),
+    LineDisplayMessage { pages: Vec<LineDisplayPage> },
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ConsentInfo {
+    consent_message: ConsentMessage,
+    metadata: ConsentMessageMetadata,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ErrorInfo {
+    description: String,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+enum Icrc21Error {
+    UnsupportedCanisterCall(ErrorInfo),
+    ConsentMessageUnavailable(ErrorInfo),
+    InsufficientPayment(ErrorInfo),
+    GenericError {
+        error_code: Nat,
+        description: String,
+    },
+}
+
+fn consent_message(
+    pic: &PocketIc,
+    canister: Principal,
+    method: &str,
+    arg: Vec<u8>,
+    device_spec: Option<DisplayMessageType>,
+) -> Result<ConsentInfo, Icrc21Error> {
+    let request = ConsentMessageRequest {
+        method: method.to_string(),
+        arg,
+        user_preferences: ConsentMessageSpec {
+            metadata: ConsentMessageMetadata {
+                language: "en".to_string(),
+                utc_offset_minutes: None,
+            },
+            device_spec,
+        },
+    };
+    decode_one(
+        &pic.update_call(
+            canister,
+            Principal::anonymous(),
+            "icrc21_canister_call_consent_message",
+            encode_args((request,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap()
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct SupportedBlockType {
+    block_type: String,
+    url: String,
+}
+
+fn map_field<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+        _ => None,
+    }
 }
 
 #[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
@@ -98,6 +367,199 @@ enum LedgerError {
     InvalidPrincipal,
     UnauthorizedCaller,
     AdminRequired,
+    // Archiving errors
+    ArchiveError(String),
+    MemoTooLong { max_length: u64 },
+    InvalidFeeDistribution(String),
+    InvalidVestingSchedule(String),
+    InvalidRoleChange(String),
+    ProposalPending { proposal_id: u64 },
+    InvalidProposal(String),
+    InvalidMultisigConfig(String),
+    MintLimitExceeded { headroom: Nat },
+    InvalidMintBudget(String),
+    CyclesConversionError(String),
+    RateLimited { retry_after: u64 },
+    InvalidRateLimit(String),
+    QuarantinedValueNotFound,
+    ImportError(String),
+    Unavailable(String),
+    InvalidReferralCampaign(String),
+    ReferralLimitReached(String),
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+struct MintBudget {
+    amount: u128,
+    window: u64,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct MintHeadroom {
+    max_supply: Option<Nat>,
+    supply_headroom: Option<Nat>,
+    budget: Option<MintBudget>,
+    budget_headroom: Option<Nat>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct CyclesConversionConfig {
+    swap_canister: Option<Principal>,
+    cmc_canister: Principal,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct CyclesTopUp {
+    canister_id: Principal,
+    amount: Nat,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+enum CyclesConversionStatus {
+    Pending,
+    Completed,
+    Failed(String),
+    Refunded(String),
+    RefundFailed(String),
+    SwapUnknown(String),
+    TopUpFailed(String),
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+struct CyclesConversion {
+    id: u64,
+    canister_id: Principal,
+    amount: Nat,
+    icp_amount: Option<Nat>,
+    icp_block_index: Option<u64>,
+    cycles: Option<Nat>,
+    status: CyclesConversionStatus,
+    created_at: u64,
+    updated_at: u64,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct SwapArgs {
+    amount_in: Nat,
+    to: Account,
+    memo: u64,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<u64>,
+    },
+    InvalidTransaction(String),
+    TransactionTooOld(u64),
+    Processing,
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct MultisigConfig {
+    signers: Vec<Principal>,
+    threshold: u32,
+    proposal_ttl: u64,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+enum ProposalAction {
+    AdminMint {
+        to: Account,
+        amount: Nat,
+        description: Option<String>,
+    },
+    AdminTransfer(PoolTransferArgs),
+    SetTransferFee(u128),
+    SetMultisigConfig(MultisigConfig),
+    CreateVestingSchedule(CreateVestingScheduleArgs),
+    SetFeeDistribution {
+        shares: Vec<FeeShare>,
+        dust_destination: FeeDestination,
+    },
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+enum ProposalStatus {
+    Pending,
+    Executed(String),
+    Failed(String),
+    Rejected,
+    Expired,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct Proposal {
+    id: u64,
+    action: ProposalAction,
+    proposer: Principal,
+    created_at: u64,
+    expires_at: u64,
+    approvals: Vec<Principal>,
+    rejections: Vec<Principal>,
+    status: ProposalStatus,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Owner,
+    Minter,
+    PoolManager,
+    Moderator,
+    Auditor,
+    CyclesRequester,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct CreateVestingScheduleArgs {
+    beneficiary: Account,
+    total: Nat,
+    start: Option<u64>,
+    cliff: u64,
+    duration: u64,
+    period: u64,
+    revocable: bool,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct VestingScheduleInfo {
+    id: u64,
+    beneficiary: Account,
+    total: Nat,
+    start: u64,
+    cliff: u64,
+    duration: u64,
+    period: u64,
+    revocable: bool,
+    revoked_at: Option<u64>,
+    vested: Nat,
+    claimed: Nat,
+    remaining: Nat,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+enum FeeDestination {
+    Burn,
+    Pool(String),
+    Account(Account),
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+struct FeeShare {
+    destination: FeeDestination,
+    basis_points: u16,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct FeeDistribution {
+    fee: Nat,
+    shares: Vec<FeeShare>,
+    dust_destination: FeeDestination,
 }
 
 // Helper function to create a mock Internet Identity principal (10 bytes ending with 0x01)
@@ -143,6 +605,57 @@ fn setup() -> (PocketIc, Principal, Principal) {
     (pic, backend_canister, admin)
 }
 
+// Arguments of a day-long campaign paying 20 CRNL to the referrer and 10 CRNL
+// to the referee
+fn referral_campaign_args(pic: &PocketIc, qualifier: Principal) -> CreateReferralCampaignArgs {
+    let now = pic.get_time().as_nanos_since_unix_epoch() / 1_000_000_000;
+    CreateReferralCampaignArgs {
+        name: "Launch".to_string(),
+        start_time: None,
+        end_time: now + 86_400,
+        referrer_reward: Nat::from(2_000_000_000_u128),
+        referee_reward: Nat::from(1_000_000_000_u128),
+        second_tier_reward: None,
+        max_referrals_per_referrer: 10,
+        budget: Nat::from(1_000_000_000_000_u128),
+        qualifier,
+    }
+}
+
+fn create_referral_campaign(
+    pic: &PocketIc,
+    canister: Principal,
+    admin: Principal,
+    args: CreateReferralCampaignArgs,
+) -> Result<u64, LedgerError> {
+    let response = pic
+        .update_call(
+            canister,
+            admin,
+            "create_referral_campaign",
+            encode_args((args,)).unwrap(),
+        )
+        .expect("Failed to call create_referral_campaign");
+    decode_one(&response).unwrap()
+}
+
+fn record_qualifying_action(
+    pic: &PocketIc,
+    canister: Principal,
+    qualifier: Principal,
+    referee: &Account,
+) -> Result<bool, LedgerError> {
+    let response = pic
+        .update_call(
+            canister,
+            qualifier,
+            "record_qualifying_action",
+            encode_args((referee.clone(),)).unwrap(),
+        )
+        .expect("Failed to call record_qualifying_action");
+    decode_one(&response).unwrap()
+}
+
 // Query Tests
 #[test]
 fn test_icrc1_name() {
@@ -318,7 +831,7 @@ fn test_get_logs_by_range() {
 }
 
 #[test]
-fn test_icrc1_allowance() {
+fn test_icrc2_allowance() {
     let (pic, backend_canister, admin) = setup();
     let owner = Account {
         owner: admin,
@@ -328,17 +841,27 @@ fn test_icrc1_allowance() {
         owner: Principal::anonymous(),
         subaccount: None,
     };
-    let args = encode_args((owner, spender)).expect("Failed to encode args");
+    let args = encode_args((AllowanceArgs {
+        account: owner,
+        spender,
+    },))
+    .expect("Failed to encode args");
     let response = pic
         .query_call(
             backend_canister,
             Principal::anonymous(),
-            "icrc1_allowance",
+            "icrc2_allowance",
             args,
         )
-        .expect("Failed to query icrc1_allowance");
-    let result: Nat = decode_one(&response).unwrap();
-    assert_eq!(result, Nat::from(0_u128));
+        .expect("Failed to query icrc2_allowance");
+    let result: Allowance = decode_one(&response).unwrap();
+    assert_eq!(
+        result,
+        Allowance {
+            allowance: Nat::from(0_u128),
+            expires_at: None,
+        }
+    );
 }
 
 // Update Tests
@@ -364,7 +887,15 @@ fn test_register_user() {
 
 #[test]
 fn test_claim_referral() {
-    let (pic, backend_canister, _) = setup();
+    let (pic, backend_canister, admin) = setup();
+    let qualifier = create_mock_ii_principal(9);
+    create_referral_campaign(
+        &pic,
+        backend_canister,
+        admin,
+        referral_campaign_args(&pic, qualifier),
+    )
+    .expect("Failed to create campaign");
     let referrer = Account {
         owner: create_mock_ii_principal(2),
         subaccount: None,
@@ -394,7 +925,10 @@ fn test_claim_referral() {
         .update_call(backend_canister, referee.owner, "claim_referral", args)
         .expect("Failed to call claim_referral");
     let result: Result<String, LedgerError> = decode_one(&response).unwrap();
-    assert_eq!(result.unwrap(), "Referral reward of 20 CRNL credited");
+    assert_eq!(
+        result.unwrap(),
+        "Referral recorded in campaign Launch; rewards are paid after your first qualifying action"
+    );
 }
 
 #[test]
@@ -427,12 +961,15 @@ fn test_icrc1_transfer() {
         from_subaccount: from.subaccount,
         to: to.clone(),
         amount: amount.clone(),
+        fee: None,
+        memo: None,
+        created_at_time: None,
     },))
     .expect("Failed to encode args");
     let response = pic
         .update_call(backend_canister, admin, "icrc1_transfer", args)
         .expect("Failed to call icrc1_transfer");
-    let result: Result<Nat, LedgerError> = decode_one(&response).unwrap();
+    let result: Result<Nat, TransferError> = decode_one(&response).unwrap();
     if let Err(e) = &result {
         println!("Transfer failed with: {:?}", e);
     }
@@ -450,13 +987,127 @@ fn test_icrc1_transfer() {
     .unwrap();
     assert_eq!(
         to_balance,
-        amount - Nat::from(transfer_fee),
-        "Recipient should receive amount minus fee"
+        amount.clone(),
+        "Recipient should receive the full amount"
+    );
+
+    let from_balance_after: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            encode_args((from,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        from_balance_after,
+        from_balance - amount - Nat::from(transfer_fee),
+        "Sender should pay the amount plus the fee"
+    );
+}
+
+#[test]
+fn test_icrc1_transfer_bad_fee() {
+    let (pic, backend_canister, admin) = setup();
+    let to = Account {
+        owner: Principal::from_text("2vxsx-fae").unwrap(),
+        subaccount: None,
+    };
+
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "icrc1_transfer",
+            encode_args((TransferArgs {
+                from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+                to,
+                amount: Nat::from(1_000_000_000_u128),
+                fee: Some(Nat::from(1_u128)),
+                memo: None,
+                created_at_time: None,
+            },))
+            .unwrap(),
+        )
+        .expect("Failed to call icrc1_transfer");
+    let result: Result<Nat, TransferError> = decode_one(&response).unwrap();
+    assert_eq!(
+        result,
+        Err(TransferError::BadFee {
+            expected_fee: Nat::from(100_000_u128)
+        })
+    );
+}
+
+#[test]
+fn test_icrc1_transfer_deduplication() {
+    let (pic, backend_canister, admin) = setup();
+    let to = Account {
+        owner: Principal::from_text("2vxsx-fae").unwrap(),
+        subaccount: None,
+    };
+    let now = pic.get_time().as_nanos_since_unix_epoch();
+    let transfer = |created_at_time: Option<u64>| -> Result<Nat, TransferError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "icrc1_transfer",
+                encode_args((TransferArgs {
+                    from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+                    to: to.clone(),
+                    amount: Nat::from(1_000_000_u128),
+                    fee: None,
+                    memo: Some(b"order-1".to_vec()),
+                    created_at_time,
+                },))
+                .unwrap(),
+            )
+            .expect("Failed to call icrc1_transfer"),
+        )
+        .unwrap()
+    };
+
+    let first = transfer(Some(now)).expect("First transfer should succeed");
+    assert_eq!(
+        transfer(Some(now)),
+        Err(TransferError::Duplicate {
+            duplicate_of: first
+        }),
+        "Identical resubmission should be rejected as a duplicate"
+    );
+
+    // Transfers without created_at_time are never deduplicated.
+    assert!(transfer(None).is_ok());
+    assert!(transfer(None).is_ok());
+
+    let day_nanos = 24 * 60 * 60 * 1_000_000_000_u64;
+    assert_eq!(
+        transfer(Some(now - 2 * day_nanos)),
+        Err(TransferError::TooOld)
     );
+    assert!(matches!(
+        transfer(Some(now + day_nanos)),
+        Err(TransferError::CreatedInFuture { .. })
+    ));
+
+    let window: u64 = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transaction_window",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(window, day_nanos);
 }
 
 #[test]
-fn test_icrc1_approve_and_transfer_from() {
+fn test_icrc2_approve_and_transfer_from() {
     let (pic, backend_canister, admin) = setup();
     let owner = Account {
         owner: admin,
@@ -488,31 +1139,39 @@ fn test_icrc1_approve_and_transfer_from() {
     let approve_args = encode_args(((ApproveArgs {
         from_subaccount: owner.subaccount,
         spender: spender.clone(),
-        amount: amount.clone(),
+        amount: amount.clone() + transfer_fee.clone(),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
     }),))
     .expect("Failed to encode args");
     let approve_response = pic
-        .update_call(backend_canister, admin, "icrc1_approve", approve_args)
-        .expect("Failed to call icrc1_approve");
-    let approve_result: Result<Nat, LedgerError> = decode_one(&approve_response).unwrap();
+        .update_call(backend_canister, admin, "icrc2_approve", approve_args)
+        .expect("Failed to call icrc2_approve");
+    let approve_result: Result<Nat, ApproveError> = decode_one(&approve_response).unwrap();
     assert!(approve_result.is_ok());
 
     let transfer_args = encode_args((TransferFromArgs {
-        spender: spender.clone(),
+        spender_subaccount: spender.subaccount,
         from: owner.clone(),
         to: to.clone(),
         amount: amount.clone(),
+        fee: None,
+        memo: None,
+        created_at_time: None,
     },))
     .expect("Failed to encode args");
     let transfer_response = pic
         .update_call(
             backend_canister,
             spender.owner,
-            "icrc1_transfer_from",
+            "icrc2_transfer_from",
             transfer_args,
         )
-        .expect("Failed to call icrc1_transfer_from");
-    let transfer_result: Result<Nat, LedgerError> = decode_one(&transfer_response).unwrap();
+        .expect("Failed to call icrc2_transfer_from");
+    let transfer_result: Result<Nat, TransferFromError> = decode_one(&transfer_response).unwrap();
     assert!(transfer_result.is_ok());
 
     let to_balance: Nat = decode_one(
@@ -526,15 +1185,148 @@ fn test_icrc1_approve_and_transfer_from() {
     )
     .unwrap();
     assert_eq!(
-        to_balance,
-        amount - transfer_fee,
-        "Recipient should receive amount minus fee"
+        to_balance, amount,
+        "Recipient should receive the full amount"
     );
-}
 
-#[test]
-fn test_deduct_from_balance() {
-    let (pic, backend_canister, admin) = setup();
+    let owner_balance_after: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            encode_args((owner,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        owner_balance_after,
+        owner_balance - amount - transfer_fee.clone() - transfer_fee,
+        "Owner should pay the approval fee and the transfer amount plus fee"
+    );
+}
+
+#[test]
+fn test_icrc2_approve_errors() {
+    let (pic, backend_canister, admin) = setup();
+    let owner = Account {
+        owner: admin,
+        subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+    };
+    let spender = Account {
+        owner: create_mock_ii_principal(2),
+        subaccount: None,
+    };
+    let approve = |args: ApproveArgs| -> Result<Nat, ApproveError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "icrc2_approve",
+                encode_args((args,)).unwrap(),
+            )
+            .expect("Failed to call icrc2_approve"),
+        )
+        .unwrap()
+    };
+    let base_args = ApproveArgs {
+        from_subaccount: owner.subaccount,
+        spender: spender.clone(),
+        amount: Nat::from(1_000_u128),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let bad_fee = approve(ApproveArgs {
+        fee: Some(Nat::from(1_u128)),
+        ..base_args.clone()
+    });
+    assert_eq!(
+        bad_fee,
+        Err(ApproveError::BadFee {
+            expected_fee: Nat::from(100_000_u128)
+        })
+    );
+
+    let changed = approve(ApproveArgs {
+        expected_allowance: Some(Nat::from(5_u128)),
+        ..base_args.clone()
+    });
+    assert_eq!(
+        changed,
+        Err(ApproveError::AllowanceChanged {
+            current_allowance: Nat::from(0_u128)
+        })
+    );
+
+    let expired = approve(ApproveArgs {
+        expires_at: Some(1),
+        ..base_args.clone()
+    });
+    assert!(matches!(expired, Err(ApproveError::Expired { .. })));
+
+    let expires_at = u64::MAX - 1;
+    assert!(approve(ApproveArgs {
+        expires_at: Some(expires_at),
+        ..base_args.clone()
+    })
+    .is_ok());
+
+    let allowance: Allowance = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc2_allowance",
+            encode_args((AllowanceArgs {
+                account: owner.clone(),
+                spender: spender.clone(),
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        allowance,
+        Allowance {
+            allowance: Nat::from(1_000_u128),
+            expires_at: Some(expires_at),
+        }
+    );
+
+    // Transferring more than the allowance covers must fail.
+    let transfer_result: Result<Nat, TransferFromError> = decode_one(
+        &pic.update_call(
+            backend_canister,
+            spender.owner,
+            "icrc2_transfer_from",
+            encode_args((TransferFromArgs {
+                spender_subaccount: None,
+                from: owner,
+                to: spender,
+                amount: Nat::from(1_000_u128),
+                fee: None,
+                memo: None,
+                created_at_time: None,
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        transfer_result,
+        Err(TransferFromError::InsufficientAllowance {
+            allowance: Nat::from(1_000_u128)
+        })
+    );
+}
+#[test]
+fn test_deduct_from_balance() {
+    let (pic, backend_canister, admin) = setup();
     let caller = Account {
         owner: admin,
         subaccount: Some(RESERVE_POOL_SUBACCOUNT),
@@ -558,6 +1350,7 @@ fn test_deduct_from_balance() {
         caller: caller.clone(),
         amount: deduction_amount.clone(),
         description: "Media ChronoLock Creation".to_string(),
+        memo: None,
     },))
     .expect("Failed to encode args");
     let response = pic
@@ -687,7 +1480,6 @@ fn test_admin_mint() {
         recipient_account.clone(),
         mint_amount.clone(),
         Some("test mint".to_string()),
-        None::<Vec<u8>>,
     ))
     .expect("Failed to encode args");
 
@@ -786,7 +1578,15 @@ fn test_unique_referral_codes() {
 
 #[test]
 fn test_referral_reward() {
-    let (pic, backend_canister, _admin) = setup();
+    let (pic, backend_canister, admin) = setup();
+    let qualifier = create_mock_ii_principal(9);
+    create_referral_campaign(
+        &pic,
+        backend_canister,
+        admin,
+        referral_campaign_args(&pic, qualifier),
+    )
+    .expect("Failed to create campaign");
     let referrer = Account {
         owner: create_mock_ii_principal(2),
         subaccount: None,
@@ -822,7 +1622,21 @@ fn test_referral_reward() {
         .unwrap(),
     )
     .expect("Failed to claim referral");
+    assert_eq!(
+        record_qualifying_action(&pic, backend_canister, qualifier, &referee),
+        Ok(true)
+    );
 
+    let referee_balance: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            encode_args((referee.clone(),)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
     let referrer_balance: Nat = decode_one(
         &pic.query_call(
             backend_canister,
@@ -849,9 +1663,19 @@ fn test_referral_reward() {
         Nat::from(20_000_000_000_u128 + 2_000_000_000_u128),
         "Referrer should receive reward"
     );
+    assert_eq!(
+        referee_balance,
+        Nat::from(1_000_000_000_u128),
+        "Referee should receive reward"
+    );
     assert_eq!(
         community_pool,
-        Nat::from(50_000_000_000_000_000_000_u128 - 20_000_000_000_u128 - 2_000_000_000_u128),
+        Nat::from(
+            50_000_000_000_000_000_000_u128
+                - 20_000_000_000_u128
+                - 2_000_000_000_u128
+                - 1_000_000_000_u128
+        ),
         "Community pool should be deducted"
     );
 }
@@ -878,11 +1702,14 @@ fn test_transfer_insufficient_balance() {
                 from_subaccount: from.subaccount,
                 to,
                 amount,
+                fee: None,
+                memo: None,
+                created_at_time: None,
             }),))
             .unwrap(),
         )
         .expect("Failed to call icrc1_transfer");
-    let result: Result<Nat, LedgerError> =
+    let result: Result<Nat, TransferError> =
         decode_one(&response).expect("Failed to decode response");
     assert!(
         result.is_err(),
@@ -891,8 +1718,10 @@ fn test_transfer_insufficient_balance() {
     if let Err(e) = result {
         assert_eq!(
             e,
-            LedgerError::InsufficientBalance,
-            "Error should be InsufficientBalance"
+            TransferError::InsufficientFunds {
+                balance: Nat::from(50_000_000_000_000_000_000_u128)
+            },
+            "Error should be InsufficientFunds"
         );
     }
 }
@@ -914,41 +1743,57 @@ fn test_icrc1_functions() {
     };
     let amount = Nat::from(1_000_000_000_u128);
     let transfer_fee = Nat::from(100_000_u128);
+    let approved = amount.clone() + transfer_fee.clone();
 
     pic.update_call(
         backend_canister,
         admin,
-        "icrc1_approve",
+        "icrc2_approve",
         encode_args(((ApproveArgs {
             from_subaccount: owner.subaccount,
             spender: spender.clone(),
-            amount: amount.clone(),
+            amount: approved.clone(),
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
         }),))
         .unwrap(),
     )
     .expect("Failed to approve");
 
-    let allowance: Nat = decode_one(
+    let allowance: Allowance = decode_one(
         &pic.query_call(
             backend_canister,
             Principal::anonymous(),
-            "icrc1_allowance",
-            encode_args((owner.clone(), spender.clone())).unwrap(),
+            "icrc2_allowance",
+            encode_args((AllowanceArgs {
+                account: owner.clone(),
+                spender: spender.clone(),
+            },))
+            .unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
-    assert_eq!(allowance, amount, "Allowance should match approved amount");
+    assert_eq!(
+        allowance.allowance, approved,
+        "Allowance should match approved amount"
+    );
 
     pic.update_call(
         backend_canister,
         spender.owner,
-        "icrc1_transfer_from",
+        "icrc2_transfer_from",
         encode_args((TransferFromArgs {
-            spender: spender.clone(),
+            spender_subaccount: spender.subaccount,
             from: owner.clone(),
             to: to.clone(),
             amount: amount.clone(),
+            fee: Some(transfer_fee),
+            memo: None,
+            created_at_time: None,
         },))
         .unwrap(),
     )
@@ -965,12 +1810,10 @@ fn test_icrc1_functions() {
     )
     .unwrap();
     assert_eq!(
-        to_balance,
-        amount - transfer_fee,
-        "Transferred amount should be received minus fee"
+        to_balance, amount,
+        "Transferred amount should be received in full"
     );
 }
-
 #[test]
 fn test_arithmetic_overflow() {
     let (pic, backend_canister, admin) = setup();
@@ -988,23 +1831,25 @@ fn test_arithmetic_overflow() {
         from_subaccount: from.subaccount,
         to,
         amount,
+        fee: None,
+        memo: None,
+        created_at_time: None,
     }),))
     .expect("Failed to encode args");
     let response = pic.update_call(backend_canister, admin, "icrc1_transfer", args);
 
     match response {
         Ok(resp) => {
-            let result: Result<Nat, LedgerError> =
+            let result: Result<Nat, TransferError> =
                 decode_one(&resp).expect("Failed to decode response");
             assert!(
                 result.is_err(),
                 "Transfer with overflow amount should return an error"
             );
             if let Err(e) = result {
-                assert_eq!(
-                    e,
-                    LedgerError::ArithmeticError,
-                    "Error should be ArithmeticError"
+                assert!(
+                    matches!(e, TransferError::InsufficientFunds { .. }),
+                    "Error should be InsufficientFunds"
                 );
             }
         }
@@ -1031,7 +1876,6 @@ fn test_concurrent_transfers() {
         subaccount: None,
     };
     let amount = Nat::from(1_000_000_000_u128);
-    let transfer_fee = Nat::from(100_000_u128);
 
     pic.update_call(
         backend_canister,
@@ -1041,6 +1885,9 @@ fn test_concurrent_transfers() {
             from_subaccount: from.subaccount,
             to: to1.clone(),
             amount: amount.clone(), // Clone here to avoid move in first transfer
+            fee: None,
+            memo: None,
+            created_at_time: None,
         }),))
         .unwrap(),
     )
@@ -1053,6 +1900,9 @@ fn test_concurrent_transfers() {
             from_subaccount: from.subaccount,
             to: to2.clone(),
             amount: amount.clone(), // Clone here to avoid move in second transfer
+            fee: None,
+            memo: None,
+            created_at_time: None,
         }),))
         .unwrap(),
     )
@@ -1081,13 +1931,12 @@ fn test_concurrent_transfers() {
 
     assert_eq!(
         to1_balance,
-        amount.clone() - transfer_fee.clone(), // Clone here to avoid move
-        "First recipient should receive amount minus fee"
+        amount.clone(), // Clone here to avoid move
+        "First recipient should receive the full amount"
     );
     assert_eq!(
-        to2_balance,
-        amount - transfer_fee,
-        "Second recipient should receive amount minus fee"
+        to2_balance, amount,
+        "Second recipient should receive the full amount"
     );
 }
 
@@ -1112,23 +1961,19 @@ fn test_boundary_values() {
                 from_subaccount: from.subaccount,
                 to: to.clone(),
                 amount: Nat::from(0_u128),
+                fee: None,
+                memo: None,
+                created_at_time: None,
             }),))
             .unwrap(),
         )
         .expect("Failed to call icrc1_transfer with zero amount");
-    let result_zero: Result<Nat, LedgerError> =
+    let result_zero: Result<Nat, TransferError> =
         decode_one(&response_zero).expect("Failed to decode zero response");
     assert!(
-        result_zero.is_err(),
-        "Transfer with zero amount should fail due to fee"
+        result_zero.is_ok(),
+        "Transfer with zero amount should succeed and only charge the fee"
     );
-    if let Err(e) = result_zero {
-        assert_eq!(
-            e,
-            LedgerError::InsufficientFee,
-            "Error should be InsufficientFee"
-        );
-    }
 
     let response_max = pic
         .update_call(
@@ -1139,21 +1984,23 @@ fn test_boundary_values() {
                 from_subaccount: from.subaccount,
                 to: to.clone(),
                 amount: Nat::from(u128::MAX),
+                fee: None,
+                memo: None,
+                created_at_time: None,
             }),))
             .unwrap(),
         )
         .expect("Failed to call icrc1_transfer with max amount");
-    let result_max: Result<Nat, LedgerError> =
+    let result_max: Result<Nat, TransferError> =
         decode_one(&response_max).expect("Failed to decode max response");
     assert!(
         result_max.is_err(),
         "Transfer with maximum amount should fail"
     );
     if let Err(e) = result_max {
-        assert_eq!(
-            e,
-            LedgerError::ArithmeticError,
-            "Error should be ArithmeticError"
+        assert!(
+            matches!(e, TransferError::InsufficientFunds { .. }),
+            "Error should be InsufficientFunds"
         );
     }
 }
@@ -1227,7 +2074,7 @@ fn test_process_fee() {
     )
     .unwrap();
 
-    let transfer_result: Result<Nat, LedgerError> = decode_one(
+    let transfer_result: Result<Nat, TransferError> = decode_one(
         &pic.update_call(
             backend_canister,
             admin,
@@ -1236,6 +2083,9 @@ fn test_process_fee() {
                 from_subaccount: from.subaccount,
                 to: to.clone(),
                 amount: amount.clone(),
+                fee: None,
+                memo: None,
+                created_at_time: None,
             }),))
             .unwrap(),
         )
@@ -1247,7 +2097,6 @@ fn test_process_fee() {
     let burn_amount = Nat::from(transfer_fee.clone() * Nat::from(20_u128) / Nat::from(100_u128));
     let pool_amount = Nat::from(transfer_fee.clone() * Nat::from(10_u128) / Nat::from(100_u128));
     let dapp_amount = Nat::from(transfer_fee.clone() * Nat::from(70_u128) / Nat::from(100_u128));
-    let amount_after_fee = amount.clone() - transfer_fee.clone();
 
     let final_total_supply: Nat = decode_one(
         &pic.query_call(
@@ -1337,390 +2186,536 @@ fn test_process_fee() {
     );
     assert_eq!(
         final_from_balance,
-        initial_from_balance - amount,
-        "Sender balance should decrease by amount only"
+        initial_from_balance - amount.clone() - transfer_fee,
+        "Sender balance should decrease by amount plus fee"
     );
     assert_eq!(
-        final_to_balance, amount_after_fee,
-        "Recipient balance should equal amount minus fee"
+        final_to_balance, amount,
+        "Recipient balance should equal the full amount"
     );
 }
 
 #[test]
-fn test_icrc1_and_icrc2_compliance() {
+fn test_fee_distribution_policy() {
     let (pic, backend_canister, admin) = setup();
-    let user = Account {
-        owner: create_mock_ii_principal(2),
-        subaccount: None,
+    let query_nat = |method: &str| -> Nat {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                method,
+                encode_args(()).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
     };
-    let spender = Account {
-        owner: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
-        subaccount: None,
+    let set_policy = |caller: Principal, shares: Vec<FeeShare>, dust: FeeDestination| {
+        let result: Result<(), LedgerError> = decode_one(
+            &pic.update_call(
+                backend_canister,
+                caller,
+                "set_fee_distribution",
+                encode_args((shares, dust)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        result
+    };
+    let share = |destination: FeeDestination, basis_points: u16| FeeShare {
+        destination,
+        basis_points,
     };
 
-    // ICRC-1 Compliance Checks
-    let supported_standards: Vec<String> = decode_one(
+    // The default policy is the historical 20% burn, 10% community, 70% dapp split.
+    let distribution: FeeDistribution = decode_one(
         &pic.query_call(
             backend_canister,
             Principal::anonymous(),
-            "icrc1_supported_standards",
+            "get_fee_distribution",
             encode_args(()).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
-    assert!(
-        supported_standards.contains(&"ICRC-1".to_string()),
-        "Canister must support ICRC-1"
-    );
-    assert!(
-        supported_standards.contains(&"ICRC-2".to_string()),
-        "Canister must support ICRC-2"
+    assert_eq!(
+        distribution.shares,
+        vec![
+            share(FeeDestination::Burn, 2_000),
+            share(FeeDestination::Pool("community".to_string()), 1_000),
+            share(FeeDestination::Pool("dapp".to_string()), 7_000),
+        ]
     );
 
-    let name: String = decode_one(
-        &pic.query_call(
-            backend_canister,
-            Principal::anonymous(),
-            "icrc1_name",
-            encode_args(()).unwrap(),
-        )
-        .unwrap(),
+    // Shares must sum to 10,000 basis points and name known pools.
+    let result = set_policy(
+        admin,
+        vec![share(FeeDestination::Burn, 5_000)],
+        FeeDestination::Burn,
+    );
+    assert!(matches!(
+        result,
+        Err(LedgerError::InvalidFeeDistribution(_))
+    ));
+    let result = set_policy(
+        admin,
+        vec![share(FeeDestination::Pool("treasury".to_string()), 10_000)],
+        FeeDestination::Burn,
+    );
+    assert!(matches!(
+        result,
+        Err(LedgerError::InvalidFeeDistribution(_))
+    ));
+
+    let non_admin = create_mock_ii_principal(9);
+    let result = set_policy(
+        non_admin,
+        vec![share(FeeDestination::Burn, 10_000)],
+        FeeDestination::Burn,
+    );
+    assert_eq!(result, Err(LedgerError::AdminRequired));
+
+    let shares = vec![
+        share(FeeDestination::Burn, 5_000),
+        share(FeeDestination::Pool("community".to_string()), 3_333),
+        share(FeeDestination::Pool("dapp".to_string()), 1_667),
+    ];
+    set_policy(
+        admin,
+        shares.clone(),
+        FeeDestination::Pool("community".to_string()),
     )
-    .unwrap();
-    assert_eq!(name, "Chronolock", "ICRC-1 name must match");
+    .expect("Valid policy should be accepted");
 
-    let symbol: String = decode_one(
-        &pic.query_call(
+    // A fee of 10,001 splits into 5,000 + 3,333 + 1,667; the unit of dust goes
+    // to the community pool.
+    let result: Result<(), LedgerError> = decode_one(
+        &pic.update_call(
             backend_canister,
-            Principal::anonymous(),
-            "icrc1_symbol",
-            encode_args(()).unwrap(),
+            admin,
+            "set_transfer_fee",
+            encode_args((10_001_u128,)).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
-    assert_eq!(symbol, "CRNL", "ICRC-1 symbol must match");
+    assert!(result.is_ok());
 
-    let decimals: u8 = decode_one(
-        &pic.query_call(
+    let burned_before = query_nat("get_total_burned");
+    let community_before = query_nat("get_community_pool_balance");
+    let dapp_before = query_nat("get_dapp_funds");
+
+    let transfer_args = TransferArgs {
+        from_subaccount: Some(RESERVE_POOL_SUBACCOUNT),
+        to: Account {
+            owner: non_admin,
+            subaccount: None,
+        },
+        amount: Nat::from(1_000_000_u128),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let result: Result<Nat, TransferError> = decode_one(
+        &pic.update_call(
             backend_canister,
-            Principal::anonymous(),
-            "icrc1_decimals",
-            encode_args(()).unwrap(),
+            admin,
+            "icrc1_transfer",
+            encode_args((transfer_args,)).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
-    assert_eq!(decimals, 8, "ICRC-1 decimals must match");
+    assert!(result.is_ok());
 
-    let total_supply: Nat = decode_one(
+    assert_eq!(
+        query_nat("get_total_burned"),
+        burned_before + Nat::from(5_000_u64)
+    );
+    assert_eq!(
+        query_nat("get_community_pool_balance"),
+        community_before + Nat::from(3_334_u64)
+    );
+    assert_eq!(
+        query_nat("get_dapp_funds"),
+        dapp_before + Nat::from(1_667_u64)
+    );
+
+    let distribution: FeeDistribution = decode_one(
         &pic.query_call(
             backend_canister,
             Principal::anonymous(),
-            "icrc1_total_supply",
+            "get_fee_distribution",
             encode_args(()).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
+    assert_eq!(distribution.fee, Nat::from(10_001_u64));
+    assert_eq!(distribution.shares, shares);
     assert_eq!(
-        total_supply,
-        Nat::from(100_000_000_000_000_000_000_u128),
-        "ICRC-1 total supply must match"
+        distribution.dust_destination,
+        FeeDestination::Pool("community".to_string())
     );
+}
 
-    let fee: Nat = decode_one(
-        &pic.query_call(
-            backend_canister,
-            Principal::anonymous(),
-            "icrc1_fee",
-            encode_args(()).unwrap(),
+#[test]
+fn test_vesting_schedules() {
+    let (pic, backend_canister, admin) = setup();
+    let beneficiary = Account {
+        owner: create_mock_ii_principal(7),
+        subaccount: None,
+    };
+    let query_nat = |method: &str| -> Nat {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                method,
+                encode_args(()).unwrap(),
+            )
+            .unwrap(),
         )
-        .unwrap(),
-    )
-    .unwrap();
-    assert_eq!(fee, Nat::from(100_000_u128), "ICRC-1 fee must match");
+        .unwrap()
+    };
+    let create = |revocable: bool| -> Result<u64, LedgerError> {
+        let args = CreateVestingScheduleArgs {
+            beneficiary: beneficiary.clone(),
+            total: Nat::from(1_000_000_u64),
+            start: None,
+            cliff: 100,
+            duration: 1_000,
+            period: 100,
+            revocable,
+        };
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "create_vesting_schedule",
+                encode_args((args,)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    let claim = || -> Result<Nat, LedgerError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                beneficiary.owner,
+                "claim_vested",
+                encode_args((None::<[u8; 32]>,)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    let schedules = || -> Vec<VestingScheduleInfo> {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "get_vesting_schedules",
+                encode_args((beneficiary.clone(),)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    let revoke = |id: u64| -> Result<Nat, LedgerError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "revoke_vesting_schedule",
+                encode_args((id,)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
 
-    // Register a user to give them some tokens
-    pic.update_call(
-        backend_canister,
-        user.owner, // Use the actual user's principal for authentication
-        "register_user",
-        encode_args((user.clone(),)).unwrap(),
-    )
-    .expect("Failed to register user");
+    let revocable_id = create(true).expect("Schedule should be created");
+    let fixed_id = create(false).expect("Schedule should be created");
 
-    let user_balance: Nat = decode_one(
-        &pic.query_call(
-            backend_canister,
-            Principal::anonymous(),
-            "icrc1_balance_of",
-            encode_args((user.clone(),)).unwrap(),
-        )
-        .unwrap(),
-    )
-    .unwrap();
+    // Nothing unlocks before the cliff.
+    assert_eq!(claim(), Ok(Nat::from(0u64)));
+
+    // After 250 seconds two periods of 100 seconds have vested on each schedule.
+    pic.advance_time(Duration::from_secs(250));
+    pic.tick();
+    let team_before = query_nat("get_team_pool_balance");
+    assert_eq!(claim(), Ok(Nat::from(400_000u64)));
     assert_eq!(
-        user_balance,
-        Nat::from(20_000_000_000_u128),
-        "ICRC-1 balance_of should reflect registration"
+        query_nat("get_team_pool_balance"),
+        team_before - Nat::from(400_000u64)
     );
 
-    let transfer_amount = Nat::from(1_000_000_000_u128);
-    let transfer_fee = Nat::from(100_000_u128);
-    let transfer_result: Result<Nat, LedgerError> = decode_one(
+    let info = schedules();
+    assert_eq!(info.len(), 2);
+    assert_eq!(info[0].vested, Nat::from(200_000u64));
+    assert_eq!(info[0].claimed, Nat::from(200_000u64));
+    assert_eq!(info[0].remaining, Nat::from(800_000u64));
+
+    // Revoking returns the unvested part to the reserve pool.
+    assert!(matches!(
+        revoke(fixed_id),
+        Err(LedgerError::InvalidVestingSchedule(_))
+    ));
+    let reserve_before = query_nat("get_reserve_pool_balance");
+    assert_eq!(revoke(revocable_id), Ok(Nat::from(800_000u64)));
+    assert_eq!(
+        query_nat("get_reserve_pool_balance"),
+        reserve_before + Nat::from(800_000u64)
+    );
+
+    // Only the schedule that was not revoked keeps vesting.
+    pic.advance_time(Duration::from_secs(1_000));
+    pic.tick();
+    assert_eq!(claim(), Ok(Nat::from(800_000u64)));
+    let info = schedules();
+    let revoked = info.iter().find(|s| s.id == revocable_id).unwrap();
+    assert!(revoked.revoked_at.is_some());
+    assert_eq!(revoked.remaining, Nat::from(0u64));
+    let fixed = info.iter().find(|s| s.id == fixed_id).unwrap();
+    assert_eq!(fixed.claimed, Nat::from(1_000_000u64));
+
+    // Schedules must be backed by the team pool.
+    let args = CreateVestingScheduleArgs {
+        beneficiary: beneficiary.clone(),
+        total: query_nat("get_team_pool_balance") + Nat::from(1u64),
+        start: None,
+        cliff: 0,
+        duration: 1_000,
+        period: 100,
+        revocable: false,
+    };
+    let result: Result<u64, LedgerError> = decode_one(
         &pic.update_call(
             backend_canister,
-            user.owner,
-            "icrc1_transfer",
-            encode_args((TransferArgs {
-                from_subaccount: user.subaccount,
-                to: spender.clone(),
-                amount: transfer_amount.clone(),
-            },))
-            .unwrap(),
+            admin,
+            "create_vesting_schedule",
+            encode_args((args,)).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
-    assert!(transfer_result.is_ok(), "ICRC-1 transfer should succeed");
-
-    let spender_balance: Nat = decode_one(
-        &pic.query_call(
+    assert_eq!(result, Err(LedgerError::InsufficientPoolFunds));
+
+    // Once the team pool unlocks, pool transfers cannot dip into the tokens
+    // still owed to beneficiaries.
+    let team_balance = query_nat("get_team_pool_balance");
+    let committed = Nat::from(5_000_000u64);
+    let args = CreateVestingScheduleArgs {
+        beneficiary: beneficiary.clone(),
+        total: committed.clone(),
+        start: None,
+        cliff: 0,
+        duration: 1_000,
+        period: 100,
+        revocable: false,
+    };
+    let result: Result<u64, LedgerError> = decode_one(
+        &pic.update_call(
             backend_canister,
-            Principal::anonymous(),
-            "icrc1_balance_of",
-            encode_args((spender.clone(),)).unwrap(),
+            admin,
+            "create_vesting_schedule",
+            encode_args((args,)).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
-    assert_eq!(
-        spender_balance,
-        transfer_amount - transfer_fee,
-        "ICRC-1 transfer should deduct fee"
-    );
-
-    // ICRC-2 Compliance Checks
-    let approve_amount = Nat::from(1_000_000_000_u128);
-    let approve_result: Result<Nat, LedgerError> = decode_one(
-        &pic.update_call(
-            backend_canister,
-            user.owner,
-            "icrc1_approve",
-            encode_args((ApproveArgs {
-                from_subaccount: spender.subaccount,
-                spender: spender.clone(),
-                amount: approve_amount.clone(),
-            },))
+    assert!(result.is_ok());
+    pic.advance_time(Duration::from_secs(31_536_000));
+    pic.tick();
+    let team_transfer = |amount: Nat| -> Result<String, LedgerError> {
+        let args = PoolTransferArgs {
+            from_pool: "team".to_string(),
+            to_pool: Some("reserve".to_string()),
+            to_principal: None,
+            amount,
+            description: "Team pool withdrawal".to_string(),
+            memo: None,
+        };
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "admin_transfer",
+                encode_args((args,)).unwrap(),
+            )
             .unwrap(),
         )
-        .unwrap(),
-    )
-    .unwrap();
-    assert!(approve_result.is_ok(), "ICRC-2 approve should succeed");
-
-    let allowance: Nat = decode_one(
-        &pic.query_call(
-            backend_canister,
-            Principal::anonymous(),
-            "icrc1_allowance",
-            encode_args((user.clone(), spender.clone())).unwrap(),
-        )
-        .unwrap(),
-    )
-    .unwrap();
+        .unwrap()
+    };
+    let free = team_balance - committed.clone();
     assert_eq!(
-        allowance, approve_amount,
-        "ICRC-2 allowance should reflect approval"
+        team_transfer(free.clone() + Nat::from(1u64)),
+        Err(LedgerError::InsufficientPoolFunds)
     );
+    assert!(team_transfer(free).is_ok());
+    assert_eq!(query_nat("get_team_pool_balance"), committed);
+}
 
-    let transfer_from_amount = Nat::from(500_000_000_u128);
-    let transfer_from_result: Result<Nat, LedgerError> = decode_one(
-        &pic.update_call(
-            backend_canister,
-            spender.owner,
-            "icrc1_transfer_from",
-            encode_args((TransferFromArgs {
-                spender: spender.clone(),
-                from: user.clone(),
-                to: Account {
-                    owner: admin,
-                    subaccount: None,
-                },
-                amount: transfer_from_amount.clone(),
-            },))
+#[test]
+fn test_roles_and_ownership_transfer() {
+    let (pic, backend_canister, admin) = setup();
+    let minter = create_mock_ii_principal(11);
+    let new_owner = create_mock_ii_principal(12);
+    let stranger = create_mock_ii_principal(13);
+
+    let call = |caller: Principal, method: &str, args: Vec<u8>| -> Result<(), LedgerError> {
+        decode_one(
+            &pic.update_call(backend_canister, caller, method, args)
+                .expect("Update call failed"),
+        )
+        .unwrap()
+    };
+    let mint = |caller: Principal| -> Result<Nat, LedgerError> {
+        let to = Account {
+            owner: stranger,
+            subaccount: None,
+        };
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                caller,
+                "admin_mint",
+                encode_args((to, Nat::from(1_000u64), None::<String>)).unwrap(),
+            )
             .unwrap(),
         )
-        .unwrap(),
-    )
-    .unwrap();
-    assert!(
-        transfer_from_result.is_ok(),
-        "ICRC-2 transfer_from should succeed"
+        .unwrap()
+    };
+    let members = |role: Role| -> Vec<Principal> {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "list_role_members",
+                encode_args((role,)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+
+    // A minter can mint but cannot change owner-only settings or grant roles.
+    assert_eq!(mint(minter), Err(LedgerError::AdminRequired));
+    assert_eq!(
+        call(
+            admin,
+            "grant_role",
+            encode_args((Role::Minter, minter)).unwrap()
+        ),
+        Ok(())
+    );
+    assert_eq!(members(Role::Minter), vec![minter]);
+    assert!(mint(minter).is_ok());
+    assert_eq!(
+        call(minter, "set_transfer_fee", encode_args((1u128,)).unwrap()),
+        Err(LedgerError::AdminRequired)
     );
+    assert_eq!(
+        call(
+            minter,
+            "grant_role",
+            encode_args((Role::Auditor, stranger)).unwrap()
+        ),
+        Err(LedgerError::AdminRequired)
+    );
+    assert!(matches!(
+        call(
+            admin,
+            "grant_role",
+            encode_args((Role::Owner, minter)).unwrap()
+        ),
+        Err(LedgerError::InvalidRoleChange(_))
+    ));
 
-    let updated_allowance: Nat = decode_one(
-        &pic.query_call(
-            backend_canister,
-            Principal::anonymous(),
-            "icrc1_allowance",
-            encode_args((user.clone(), spender.clone())).unwrap(),
-        )
-        .unwrap(),
-    )
-    .unwrap();
     assert_eq!(
-        updated_allowance,
-        approve_amount - transfer_from_amount,
-        "ICRC-2 allowance should decrease after transfer_from"
+        call(
+            admin,
+            "revoke_role",
+            encode_args((Role::Minter, minter)).unwrap()
+        ),
+        Ok(())
     );
+    assert!(members(Role::Minter).is_empty());
+    assert_eq!(mint(minter), Err(LedgerError::AdminRequired));
 
-    let metadata: Vec<(String, String)> = decode_one(
+    // Ownership moves only once the proposed owner accepts it.
+    let community_before: Nat = decode_one(
         &pic.query_call(
             backend_canister,
             Principal::anonymous(),
-            "icrc1_metadata",
+            "get_community_pool_balance",
             encode_args(()).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
-    assert!(
-        metadata
-            .iter()
-            .any(|(k, v)| k == "icrc1:name" && v == "Chronolock"),
-        "Metadata should include name"
-    );
-    assert!(
-        metadata
-            .iter()
-            .any(|(k, v)| k == "icrc1:symbol" && v == "CRNL"),
-        "Metadata should include symbol"
-    );
-}
-
-#[test]
-fn test_transaction_recording() {
-    let (pic, backend_canister, admin) = setup();
-
-    let from = Account {
-        owner: admin,
-        subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
-    };
-
-    let to = Account {
-        owner: Principal::from_text("2vxsx-fae").unwrap(),
-        subaccount: None,
-    };
-
-    // Perform a transfer
-    let transfer_args = TransferArgs {
-        from_subaccount: from.subaccount,
-        to: to.clone(),
-        amount: Nat::from(1_000_000_000_u128), // 10 CRNL
-    };
-
-    let transfer_response = pic
-        .update_call(
-            backend_canister,
+    assert_eq!(
+        call(
             admin,
-            "icrc1_transfer",
-            encode_args((transfer_args,)).unwrap(),
-        )
-        .expect("Failed to call icrc1_transfer");
-
-    let transfer_result: Result<Nat, LedgerError> = decode_one(&transfer_response).unwrap();
-    assert!(transfer_result.is_ok());
-
-    let start_tx_id: [u8; 32] = [0; 32];
-    let limit: u64 = 10;
-
-    let query_args = encode_args((from.owner, start_tx_id, limit))
-        .expect("Failed to encode args for transaction query");
-
-    let response = pic
-        .query_call(
-            backend_canister,
-            Principal::anonymous(),
-            "get_transactions_by_principal",
-            query_args,
-        )
-        .expect("Failed to query transactions");
-
-    let transactions: Vec<TransactionEvent> =
-        decode_one(&response).expect("Failed to decode transactions");
-
-    assert!(
-        !transactions.is_empty(),
-        "Transaction history should not be empty"
+            "transfer_ownership",
+            encode_args((new_owner,)).unwrap()
+        ),
+        Ok(())
     );
-
-    let tx = &transactions[0];
-    assert_eq!(tx.event_type, "Transfer");
-    assert_eq!(tx.from.owner, admin);
     assert_eq!(
-        tx.to.as_ref().unwrap().owner,
-        Principal::from_text("2vxsx-fae").unwrap()
+        call(stranger, "accept_ownership", encode_args(()).unwrap()),
+        Err(LedgerError::Unauthorized)
     );
-    assert_eq!(tx.amount, Nat::from(1_000_000_000_u128));
+    assert_eq!(members(Role::Owner), vec![admin]);
     assert_eq!(
-        tx.tx_id.len(),
-        32,
-        "tx_id should be a SHA-256 hash of 32 bytes"
+        call(new_owner, "accept_ownership", encode_args(()).unwrap()),
+        Ok(())
     );
-}
-
-#[test]
-fn test_pool_to_pool_transfer() {
-    let (pic, backend_canister, admin) = setup();
-
-    // Get initial balances
-    let community_balance_before: Nat = decode_one(
+    assert_eq!(members(Role::Owner), vec![new_owner]);
+    let pending: Option<Principal> = decode_one(
         &pic.query_call(
             backend_canister,
             Principal::anonymous(),
-            "get_community_pool_balance",
+            "get_pending_owner",
             encode_args(()).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
+    assert_eq!(pending, None);
 
-    let reserve_balance_before: Nat = decode_one(
-        &pic.query_call(
+    // The previous owner has no admin rights left, including over the pools,
+    // while the pool balances are unchanged.
+    assert_eq!(
+        call(admin, "set_transfer_fee", encode_args((1u128,)).unwrap()),
+        Err(LedgerError::AdminRequired)
+    );
+    let transfer_args = TransferArgs {
+        from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+        to: Account {
+            owner: stranger,
+            subaccount: None,
+        },
+        amount: Nat::from(1_000u64),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let result: Result<Nat, TransferError> = decode_one(
+        &pic.update_call(
             backend_canister,
-            Principal::anonymous(),
-            "get_reserve_pool_balance",
-            encode_args(()).unwrap(),
+            admin,
+            "icrc1_transfer",
+            encode_args((transfer_args,)).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
-
-    println!("Community balance before: {}", community_balance_before);
-    println!("Reserve balance before: {}", reserve_balance_before);
-
-    // Transfer 1000 CRNL from reserve to community pool
-    let transfer_amount = Nat::from(100_000_000_000_u128); // 1000 CRNL
-    let args = encode_args((PoolTransferArgs {
-        from_pool: "reserve".to_string(),
-        to_pool: Some("community".to_string()),
-        to_principal: None,
-        amount: transfer_amount.clone(),
-        description: "Pool rebalancing".to_string(),
-    },))
-    .expect("Failed to encode args");
-
-    let response = pic
-        .update_call(backend_canister, admin, "admin_transfer", args)
-        .expect("Failed to call admin_transfer");
-    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
-    assert!(result.is_ok(), "Transfer should succeed: {:?}", result);
-
-    // Verify balances changed correctly
-    let community_balance_after: Nat = decode_one(
+    assert!(matches!(result, Err(TransferError::GenericError { .. })));
+    let community_after: Nat = decode_one(
         &pic.query_call(
             backend_canister,
             Principal::anonymous(),
@@ -1730,235 +2725,3513 @@ fn test_pool_to_pool_transfer() {
         .unwrap(),
     )
     .unwrap();
+    assert_eq!(community_after, community_before);
 
-    let reserve_balance_after: Nat = decode_one(
-        &pic.query_call(
+    // The new owner manages the pools through admin_transfer.
+    let pool_transfer = PoolTransferArgs {
+        from_pool: "community".to_string(),
+        to_pool: None,
+        to_principal: Some(Account {
+            owner: stranger,
+            subaccount: None,
+        }),
+        amount: Nat::from(1_000u64),
+        description: "Ops payout".to_string(),
+        memo: None,
+    };
+    let result: Result<String, LedgerError> = decode_one(
+        &pic.update_call(
             backend_canister,
-            Principal::anonymous(),
-            "get_reserve_pool_balance",
-            encode_args(()).unwrap(),
+            new_owner,
+            "admin_transfer",
+            encode_args((pool_transfer,)).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
-
-    println!("Community balance after: {}", community_balance_after);
-    println!("Reserve balance after: {}", reserve_balance_after);
-
-    assert_eq!(
-        community_balance_after,
-        community_balance_before.clone() + transfer_amount.clone()
-    );
-    assert_eq!(
-        reserve_balance_after,
-        reserve_balance_before.clone() - transfer_amount.clone()
+    assert!(
+        result.is_ok(),
+        "Pool transfer by the new owner failed: {:?}",
+        result
     );
 }
 
 #[test]
-fn test_pool_to_principal_transfer() {
+fn test_multisig_proposals() {
     let (pic, backend_canister, admin) = setup();
-
-    // Create a recipient account
+    let signers: Vec<Principal> = (21..24).map(create_mock_ii_principal).collect();
+    let stranger = create_mock_ii_principal(24);
     let recipient = Account {
-        owner: create_mock_ii_principal(5),
+        owner: create_mock_ii_principal(25),
         subaccount: None,
     };
 
-    // Get initial balances
-    let reserve_balance_before: Nat = decode_one(
-        &pic.query_call(
-            backend_canister,
-            Principal::anonymous(),
-            "get_reserve_pool_balance",
-            encode_args(()).unwrap(),
+    let update = |caller: Principal, method: &str, args: Vec<u8>| -> Vec<u8> {
+        pic.update_call(backend_canister, caller, method, args)
+            .expect("Update call failed")
+    };
+    let vote = |signer: Principal, method: &str, id: u64| -> Result<ProposalStatus, LedgerError> {
+        decode_one(&update(signer, method, encode_args((id,)).unwrap())).unwrap()
+    };
+    let proposal = |id: u64| -> Proposal {
+        let proposal: Option<Proposal> = decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "get_proposal",
+                encode_args((id,)).unwrap(),
+            )
+            .unwrap(),
         )
-        .unwrap(),
-    )
-    .unwrap();
-
-    let recipient_balance_before: Nat = decode_one(
-        &pic.query_call(
-            backend_canister,
-            Principal::anonymous(),
-            "icrc1_balance_of",
-            encode_args((recipient.clone(),)).unwrap(),
+        .unwrap();
+        proposal.expect("Proposal should exist")
+    };
+    let balance = || -> Nat {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "icrc1_balance_of",
+                encode_args((recipient.clone(),)).unwrap(),
+            )
+            .unwrap(),
         )
-        .unwrap(),
-    )
-    .unwrap();
-
-    println!("Reserve balance before: {}", reserve_balance_before);
-    println!("Recipient balance before: {}", recipient_balance_before);
+        .unwrap()
+    };
 
-    // Transfer 500 CRNL from reserve to recipient
-    let transfer_amount = Nat::from(50_000_000_000_u128); // 500 CRNL
-    let args = encode_args((PoolTransferArgs {
-        from_pool: "reserve".to_string(),
+    // Invalid configurations are rejected; a valid one applies directly while
+    // approval is still off.
+    let bad_config = MultisigConfig {
+        signers: signers.clone(),
+        threshold: 4,
+        proposal_ttl: 3_600,
+    };
+    let result: Result<(), LedgerError> = decode_one(&update(
+        admin,
+        "set_multisig_config",
+        encode_args((bad_config,)).unwrap(),
+    ))
+    .unwrap();
+    assert!(matches!(result, Err(LedgerError::InvalidMultisigConfig(_))));
+    let config = MultisigConfig {
+        signers: signers.clone(),
+        threshold: 2,
+        proposal_ttl: 3_600,
+    };
+    let result: Result<(), LedgerError> = decode_one(&update(
+        admin,
+        "set_multisig_config",
+        encode_args((config,)).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(result, Ok(()));
+
+    // A mint waits for two signers and then executes.
+    let result: Result<Nat, LedgerError> = decode_one(&update(
+        admin,
+        "admin_mint",
+        encode_args((recipient.clone(), Nat::from(5_000u64), None::<String>)).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(result, Err(LedgerError::ProposalPending { proposal_id: 0 }));
+    assert_eq!(balance(), Nat::from(0u64));
+
+    assert_eq!(
+        vote(stranger, "approve_proposal", 0),
+        Err(LedgerError::Unauthorized)
+    );
+    assert_eq!(
+        vote(signers[0], "approve_proposal", 0),
+        Ok(ProposalStatus::Pending)
+    );
+    assert!(matches!(
+        vote(signers[0], "approve_proposal", 0),
+        Err(LedgerError::InvalidProposal(_))
+    ));
+    assert!(matches!(
+        vote(signers[1], "approve_proposal", 0),
+        Ok(ProposalStatus::Executed(_))
+    ));
+    assert_eq!(balance(), Nat::from(5_000u64));
+    assert_eq!(proposal(0).approvals, vec![signers[0], signers[1]]);
+
+    // Two rejections out of three signers close a proposal.
+    let result: Result<(), LedgerError> = decode_one(&update(
+        admin,
+        "set_transfer_fee",
+        encode_args((1u128,)).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(result, Err(LedgerError::ProposalPending { proposal_id: 1 }));
+    assert_eq!(
+        vote(signers[0], "reject_proposal", 1),
+        Ok(ProposalStatus::Pending)
+    );
+    assert_eq!(
+        vote(signers[1], "reject_proposal", 1),
+        Ok(ProposalStatus::Rejected)
+    );
+    let fee: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_fee",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(fee, Nat::from(100_000u64));
+
+    // A signer proposing a call counts as its first approval, and proposals
+    // expire after the configured TTL.
+    let result: Result<(), LedgerError> = decode_one(&update(
+        admin,
+        "grant_role",
+        encode_args((Role::PoolManager, signers[2])).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(result, Ok(()));
+    let pool_transfer = PoolTransferArgs {
+        from_pool: "community".to_string(),
         to_pool: None,
         to_principal: Some(recipient.clone()),
-        amount: transfer_amount.clone(),
-        description: "Grant payment".to_string(),
-    },))
-    .expect("Failed to encode args");
+        amount: Nat::from(1_000u64),
+        description: "Grant".to_string(),
+        memo: None,
+    };
+    let result: Result<String, LedgerError> = decode_one(&update(
+        signers[2],
+        "admin_transfer",
+        encode_args((pool_transfer,)).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(result, Err(LedgerError::ProposalPending { proposal_id: 2 }));
+    assert_eq!(proposal(2).approvals, vec![signers[2]]);
+
+    pic.advance_time(Duration::from_secs(3_601));
+    pic.tick();
+    assert!(matches!(
+        vote(signers[0], "approve_proposal", 2),
+        Err(LedgerError::InvalidProposal(_))
+    ));
+    assert_eq!(proposal(2).status, ProposalStatus::Expired);
+    assert_eq!(balance(), Nat::from(5_000u64));
+
+    // The treasury cannot spend the pools directly to get around approval.
+    let transfer_args = TransferArgs {
+        from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+        to: recipient.clone(),
+        amount: Nat::from(1_000u64),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let result: Result<Nat, TransferError> = decode_one(&update(
+        admin,
+        "icrc1_transfer",
+        encode_args((transfer_args,)).unwrap(),
+    ))
+    .unwrap();
+    assert!(matches!(result, Err(TransferError::GenericError { .. })));
+    assert_eq!(balance(), Nat::from(5_000u64));
+
+    // Vesting schedules and the fee distribution go through approval too.
+    let vesting_args = CreateVestingScheduleArgs {
+        beneficiary: recipient.clone(),
+        total: Nat::from(1_000u64),
+        start: None,
+        cliff: 0,
+        duration: 1_000,
+        period: 100,
+        revocable: false,
+    };
+    let result: Result<u64, LedgerError> = decode_one(&update(
+        admin,
+        "create_vesting_schedule",
+        encode_args((vesting_args,)).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(result, Err(LedgerError::ProposalPending { proposal_id: 3 }));
+    let result: Result<(), LedgerError> = decode_one(&update(
+        admin,
+        "set_fee_distribution",
+        encode_args((
+            vec![FeeShare {
+                destination: FeeDestination::Burn,
+                basis_points: 10_000,
+            }],
+            FeeDestination::Burn,
+        ))
+        .unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(result, Err(LedgerError::ProposalPending { proposal_id: 4 }));
+    assert!(matches!(
+        vote(signers[0], "approve_proposal", 3),
+        Ok(ProposalStatus::Pending)
+    ));
+    assert!(matches!(
+        vote(signers[1], "approve_proposal", 3),
+        Ok(ProposalStatus::Executed(_))
+    ));
+
+    // Turning approval off needs approval as well.
+    let disabled = MultisigConfig {
+        signers: Vec::new(),
+        threshold: 0,
+        proposal_ttl: 3_600,
+    };
+    let result: Result<(), LedgerError> = decode_one(&update(
+        admin,
+        "set_multisig_config",
+        encode_args((disabled,)).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(result, Err(LedgerError::ProposalPending { proposal_id: 5 }));
+}
 
-    let response = pic
-        .update_call(backend_canister, admin, "admin_transfer", args)
-        .expect("Failed to call admin_transfer");
-    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
-    assert!(result.is_ok(), "Transfer should succeed: {:?}", result);
+#[test]
+fn test_mint_limits() {
+    std::env::set_var("POCKET_IC_BIN", "/usr/local/bin/pocket-ic");
+    let pic = PocketIc::new();
+    let backend_canister = pic.create_canister();
+    pic.add_cycles(backend_canister, 2_000_000_000_000);
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'cargo build'.");
+    let admin = create_mock_ii_principal(1);
+    let minter = create_mock_ii_principal(2);
+    let total_supply = 100_000_000_000_u128;
+    let init_args = encode_args((
+        "Chronolock".to_string(),
+        "CRNL".to_string(),
+        total_supply,
+        31_536_000_u64,
+        100_000_u128,
+        admin,
+        Some(total_supply + 10_000),
+    ))
+    .expect("Failed to encode init arguments");
+    pic.install_canister(backend_canister, wasm, init_args, None);
 
-    // Verify balances changed correctly
-    let reserve_balance_after: Nat = decode_one(
+    let to = Account {
+        owner: create_mock_ii_principal(3),
+        subaccount: None,
+    };
+    let mint = |caller: Principal, amount: u64| -> Result<Nat, LedgerError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                caller,
+                "admin_mint",
+                encode_args((to.clone(), Nat::from(amount), None::<String>)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    let headroom = |minter: Principal| -> MintHeadroom {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "get_mint_headroom",
+                encode_args((minter,)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+
+    let metadata: Vec<(String, String)> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_metadata",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(metadata
+        .iter()
+        .any(|(k, v)| k == "icrc1:max_supply" && *v == (total_supply + 10_000).to_string()));
+
+    // The supply cap applies to every minter.
+    let info = headroom(admin);
+    assert_eq!(info.supply_headroom, Some(Nat::from(10_000u64)));
+    assert_eq!(info.budget_headroom, None);
+    assert!(mint(admin, 6_000).is_ok());
+    assert_eq!(
+        mint(admin, 5_000),
+        Err(LedgerError::MintLimitExceeded {
+            headroom: Nat::from(4_000u64)
+        })
+    );
+
+    // A minter with a budget is limited by whichever headroom is smaller.
+    let budget = MintBudget {
+        amount: 3_000,
+        window: 3_600,
+    };
+    for (method, args) in [
+        (
+            "set_mint_budget",
+            encode_args((minter, Some(budget.clone()))).unwrap(),
+        ),
+        ("grant_role", encode_args((Role::Minter, minter)).unwrap()),
+    ] {
+        let result: Result<(), LedgerError> = decode_one(
+            &pic.update_call(backend_canister, admin, method, args)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(result, Ok(()));
+    }
+    assert!(mint(minter, 2_000).is_ok());
+    assert_eq!(
+        mint(minter, 2_000),
+        Err(LedgerError::MintLimitExceeded {
+            headroom: Nat::from(1_000u64)
+        })
+    );
+    let info = headroom(minter);
+    assert_eq!(info.budget, Some(budget));
+    assert_eq!(info.budget_headroom, Some(Nat::from(1_000u64)));
+    assert_eq!(info.supply_headroom, Some(Nat::from(2_000u64)));
+
+    // The budget refills once earlier mints leave the window; the cap does not.
+    pic.advance_time(Duration::from_secs(3_601));
+    pic.tick();
+    assert!(mint(minter, 2_000).is_ok());
+    assert_eq!(
+        mint(minter, 1),
+        Err(LedgerError::MintLimitExceeded {
+            headroom: Nat::from(0u64)
+        })
+    );
+}
+
+#[test]
+fn test_convert_dapp_funds_to_cycles() {
+    let (pic, backend_canister, admin) = setup();
+    let install_mock = |wasm_path: &str| -> Principal {
+        let canister = pic.create_canister();
+        pic.add_cycles(canister, 2_000_000_000_000);
+        let wasm = fs::read(wasm_path).expect("Wasm file not found, run 'cargo build'.");
+        pic.install_canister(canister, wasm, encode_args(()).unwrap(), None);
+        canister
+    };
+    let swap_canister = install_mock(MOCK_SWAP_WASM);
+    let cmc_canister = install_mock(MOCK_CMC_WASM);
+    let targets = [
+        Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 9, 1, 1]),
+        Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 10, 1, 1]),
+    ];
+
+    let update = |caller: Principal, method: &str, args: Vec<u8>| -> Vec<u8> {
+        pic.update_call(backend_canister, caller, method, args)
+            .unwrap()
+    };
+    let convert = |top_ups: Vec<(Principal, u64)>| -> Result<Vec<CyclesConversion>, LedgerError> {
+        let top_ups: Vec<CyclesTopUp> = top_ups
+            .into_iter()
+            .map(|(canister_id, amount)| CyclesTopUp {
+                canister_id,
+                amount: Nat::from(amount),
+            })
+            .collect();
+        decode_one(&update(
+            admin,
+            "convert_dapp_funds_to_cycles",
+            encode_args((top_ups,)).unwrap(),
+        ))
+        .unwrap()
+    };
+    let dapp_funds = || -> Nat {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "get_dapp_funds",
+                encode_args(()).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    let swap_balance = || -> Nat {
+        let account = Account {
+            owner: swap_canister,
+            subaccount: None,
+        };
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "icrc1_balance_of",
+                encode_args((account,)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+
+    // Nothing can be converted before a swap canister is configured.
+    assert!(matches!(
+        convert(vec![(targets[0], 1_000)]),
+        Err(LedgerError::CyclesConversionError(_))
+    ));
+    let config = CyclesConversionConfig {
+        swap_canister: Some(swap_canister),
+        cmc_canister,
+    };
+    let result: Result<(), LedgerError> = decode_one(&update(
+        admin,
+        "set_cycles_conversion_config",
+        encode_args((config,)).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(result, Ok(()));
+
+    let pool_transfer = PoolTransferArgs {
+        from_pool: "reserve".to_string(),
+        to_pool: Some("dapp".to_string()),
+        to_principal: None,
+        amount: Nat::from(10_000u64),
+        description: "Fund cycles".to_string(),
+        memo: None,
+    };
+    let result: Result<String, LedgerError> = decode_one(&update(
+        admin,
+        "admin_transfer",
+        encode_args((pool_transfer,)).unwrap(),
+    ))
+    .unwrap();
+    assert!(result.is_ok(), "Funding the dapp pool failed: {:?}", result);
+    let funds = dapp_funds();
+    assert_eq!(
+        convert(vec![(targets[0], 1_000), (targets[1], 0)]),
+        Err(LedgerError::CyclesConversionError(format!(
+            "Amount for {} must be positive",
+            targets[1]
+        )))
+    );
+    assert_eq!(
+        convert(vec![(targets[0], 10_000_000)]),
+        Err(LedgerError::InsufficientPoolFunds)
+    );
+
+    // Each target is swapped and topped up on its own.
+    let conversions = convert(vec![(targets[0], 1_000), (targets[1], 2_000)]).unwrap();
+    assert_eq!(conversions.len(), 2);
+    for (conversion, (target, amount)) in conversions
+        .iter()
+        .zip([(targets[0], 1_000u64), (targets[1], 2_000)])
+    {
+        assert_eq!(conversion.status, CyclesConversionStatus::Completed);
+        assert_eq!(conversion.canister_id, target);
+        assert_eq!(conversion.icp_amount, Some(Nat::from(amount / 100)));
+        assert_eq!(conversion.cycles, Some(Nat::from(1_000_000_000_000u64)));
+    }
+    assert_eq!(dapp_funds(), funds.clone() - Nat::from(3_000u64));
+    assert_eq!(swap_balance(), Nat::from(3_000u64));
+
+    // The ICP is sent to the CMC subaccount of the target with the top-up memo.
+    let swaps: Vec<SwapArgs> = decode_one(
+        &pic.query_call(
+            swap_canister,
+            Principal::anonymous(),
+            "get_swaps",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = targets[1].as_slice().len() as u8;
+    subaccount[1..1 + targets[1].as_slice().len()].copy_from_slice(targets[1].as_slice());
+    assert_eq!(
+        swaps[1].to,
+        Account {
+            owner: cmc_canister,
+            subaccount: Some(subaccount),
+        }
+    );
+    assert_eq!(swaps[1].memo, 1_347_768_404);
+
+    // A failed swap refunds the dapp funds.
+    pic.update_call(
+        swap_canister,
+        admin,
+        "set_failure",
+        encode_args((Some("No liquidity".to_string()),)).unwrap(),
+    )
+    .unwrap();
+    let conversions = convert(vec![(targets[0], 1_000)]).unwrap();
+    assert_eq!(
+        conversions[0].status,
+        CyclesConversionStatus::Refunded("No liquidity".to_string())
+    );
+    assert_eq!(dapp_funds(), funds.clone() - Nat::from(3_000u64));
+    assert_eq!(swap_balance(), Nat::from(3_000u64));
+    pic.update_call(
+        swap_canister,
+        admin,
+        "set_failure",
+        encode_args((None::<String>,)).unwrap(),
+    )
+    .unwrap();
+
+    // A rejected swap call may still have swapped, so nothing is refunded until
+    // the owner resolves the conversion.
+    pic.stop_canister(swap_canister, None).unwrap();
+    let conversion = convert(vec![(targets[0], 1_000)]).unwrap().remove(0);
+    assert!(matches!(
+        conversion.status,
+        CyclesConversionStatus::SwapUnknown(_)
+    ));
+    assert_eq!(dapp_funds(), funds.clone() - Nat::from(4_000u64));
+    assert_eq!(swap_balance(), Nat::from(4_000u64));
+    pic.start_canister(swap_canister, None).unwrap();
+    let resolve = |caller: Principal| -> Result<CyclesConversion, LedgerError> {
+        decode_one(&update(
+            caller,
+            "resolve_cycles_conversion",
+            encode_args((conversion.id, None::<u64>)).unwrap(),
+        ))
+        .unwrap()
+    };
+    assert_eq!(
+        resolve(create_mock_ii_principal(9)),
+        Err(LedgerError::AdminRequired)
+    );
+    let resolved = resolve(admin).unwrap();
+    assert_eq!(
+        resolved.status,
+        CyclesConversionStatus::Refunded("Swap did not happen".to_string())
+    );
+    assert_eq!(dapp_funds(), funds.clone() - Nat::from(3_000u64));
+    assert_eq!(swap_balance(), Nat::from(3_000u64));
+    assert!(matches!(
+        resolve(admin),
+        Err(LedgerError::CyclesConversionError(_))
+    ));
+
+    // A failed notification keeps the ICP block so that the top-up can be retried.
+    let set_cmc_failure = |err: Option<NotifyError>| {
+        pic.update_call(
+            cmc_canister,
+            admin,
+            "set_failure",
+            encode_args((err,)).unwrap(),
+        )
+        .unwrap();
+    };
+    set_cmc_failure(Some(NotifyError::Processing));
+    let conversion = convert(vec![(targets[0], 1_000)]).unwrap().remove(0);
+    assert!(matches!(
+        conversion.status,
+        CyclesConversionStatus::TopUpFailed(_)
+    ));
+    assert!(conversion.icp_block_index.is_some());
+    assert_eq!(dapp_funds(), funds.clone() - Nat::from(4_000u64));
+
+    set_cmc_failure(None);
+    let result: Result<CyclesConversion, LedgerError> = decode_one(&update(
+        admin,
+        "retry_cycles_top_up",
+        encode_args((conversion.id,)).unwrap(),
+    ))
+    .unwrap();
+    let retried = result.unwrap();
+    assert_eq!(retried.status, CyclesConversionStatus::Completed);
+    assert_eq!(retried.icp_block_index, conversion.icp_block_index);
+
+    // Completed conversions cannot be retried.
+    let result: Result<CyclesConversion, LedgerError> = decode_one(&update(
+        admin,
+        "retry_cycles_top_up",
+        encode_args((conversion.id,)).unwrap(),
+    ))
+    .unwrap();
+    assert!(matches!(result, Err(LedgerError::CyclesConversionError(_))));
+
+    let history: Vec<CyclesConversion> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            admin,
+            "get_cycles_conversions",
+            encode_args((0u64, 10u64)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let statuses: Vec<CyclesConversionStatus> = history
+        .into_iter()
+        .map(|conversion| conversion.status)
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            CyclesConversionStatus::Completed,
+            CyclesConversionStatus::Completed,
+            CyclesConversionStatus::Refunded("No liquidity".to_string()),
+            CyclesConversionStatus::Refunded("Swap did not happen".to_string()),
+            CyclesConversionStatus::Completed,
+        ]
+    );
+}
+
+#[test]
+fn test_icrc1_and_icrc2_compliance() {
+    let (pic, backend_canister, admin) = setup();
+    let user = Account {
+        owner: create_mock_ii_principal(2),
+        subaccount: None,
+    };
+    let spender = Account {
+        owner: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+        subaccount: None,
+    };
+
+    // ICRC-1 Compliance Checks
+    let supported_standards: Vec<SupportedStandard> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_supported_standards",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(
+        supported_standards.iter().any(|s| s.name == "ICRC-1"),
+        "Canister must support ICRC-1"
+    );
+    assert!(
+        supported_standards.iter().any(|s| s.name == "ICRC-2"),
+        "Canister must support ICRC-2"
+    );
+
+    let name: String = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_name",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(name, "Chronolock", "ICRC-1 name must match");
+
+    let symbol: String = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_symbol",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(symbol, "CRNL", "ICRC-1 symbol must match");
+
+    let decimals: u8 = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_decimals",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(decimals, 8, "ICRC-1 decimals must match");
+
+    let total_supply: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_total_supply",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        total_supply,
+        Nat::from(100_000_000_000_000_000_000_u128),
+        "ICRC-1 total supply must match"
+    );
+
+    let fee: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_fee",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(fee, Nat::from(100_000_u128), "ICRC-1 fee must match");
+
+    // Register a user to give them some tokens
+    pic.update_call(
+        backend_canister,
+        user.owner, // Use the actual user's principal for authentication
+        "register_user",
+        encode_args((user.clone(),)).unwrap(),
+    )
+    .expect("Failed to register user");
+
+    let user_balance: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            encode_args((user.clone(),)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        user_balance,
+        Nat::from(20_000_000_000_u128),
+        "ICRC-1 balance_of should reflect registration"
+    );
+
+    let transfer_amount = Nat::from(1_000_000_000_u128);
+    let transfer_fee = Nat::from(100_000_u128);
+    let transfer_result: Result<Nat, TransferError> = decode_one(
+        &pic.update_call(
+            backend_canister,
+            user.owner,
+            "icrc1_transfer",
+            encode_args((TransferArgs {
+                from_subaccount: user.subaccount,
+                to: spender.clone(),
+                amount: transfer_amount.clone(),
+                fee: None,
+                memo: None,
+                created_at_time: None,
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(transfer_result.is_ok(), "ICRC-1 transfer should succeed");
+
+    let spender_balance: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            encode_args((spender.clone(),)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        spender_balance, transfer_amount,
+        "ICRC-1 transfer should credit the full amount"
+    );
+
+    // ICRC-2 Compliance Checks
+    let approve_amount = Nat::from(1_000_000_000_u128);
+    let approve_result: Result<Nat, ApproveError> = decode_one(
+        &pic.update_call(
+            backend_canister,
+            user.owner,
+            "icrc2_approve",
+            encode_args((ApproveArgs {
+                from_subaccount: user.subaccount,
+                spender: spender.clone(),
+                amount: approve_amount.clone(),
+                expected_allowance: None,
+                expires_at: None,
+                fee: None,
+                memo: None,
+                created_at_time: None,
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(approve_result.is_ok(), "ICRC-2 approve should succeed");
+
+    let allowance: Allowance = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc2_allowance",
+            encode_args((AllowanceArgs {
+                account: user.clone(),
+                spender: spender.clone(),
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        allowance.allowance, approve_amount,
+        "ICRC-2 allowance should reflect approval"
+    );
+
+    let transfer_from_amount = Nat::from(500_000_000_u128);
+    let transfer_from_result: Result<Nat, TransferFromError> = decode_one(
+        &pic.update_call(
+            backend_canister,
+            spender.owner,
+            "icrc2_transfer_from",
+            encode_args((TransferFromArgs {
+                spender_subaccount: spender.subaccount,
+                from: user.clone(),
+                to: Account {
+                    owner: admin,
+                    subaccount: None,
+                },
+                amount: transfer_from_amount.clone(),
+                fee: None,
+                memo: None,
+                created_at_time: None,
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(
+        transfer_from_result.is_ok(),
+        "ICRC-2 transfer_from should succeed"
+    );
+
+    let updated_allowance: Allowance = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc2_allowance",
+            encode_args((AllowanceArgs {
+                account: user.clone(),
+                spender: spender.clone(),
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        updated_allowance.allowance,
+        approve_amount - transfer_from_amount - transfer_fee,
+        "ICRC-2 allowance should decrease by amount plus fee after transfer_from"
+    );
+
+    let metadata: Vec<(String, String)> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_metadata",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(
+        metadata
+            .iter()
+            .any(|(k, v)| k == "icrc1:name" && v == "Chronolock"),
+        "Metadata should include name"
+    );
+    assert!(
+        metadata
+            .iter()
+            .any(|(k, v)| k == "icrc1:symbol" && v == "CRNL"),
+        "Metadata should include symbol"
+    );
+}
+
+#[test]
+fn test_transaction_recording() {
+    let (pic, backend_canister, admin) = setup();
+
+    let from = Account {
+        owner: admin,
+        subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+    };
+
+    let to = Account {
+        owner: Principal::from_text("2vxsx-fae").unwrap(),
+        subaccount: None,
+    };
+
+    // Perform a transfer
+    let transfer_args = TransferArgs {
+        from_subaccount: from.subaccount,
+        to: to.clone(),
+        amount: Nat::from(1_000_000_000_u128), // 10 CRNL
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let transfer_response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "icrc1_transfer",
+            encode_args((transfer_args,)).unwrap(),
+        )
+        .expect("Failed to call icrc1_transfer");
+
+    let transfer_result: Result<Nat, TransferError> = decode_one(&transfer_response).unwrap();
+    let block_index = u64::try_from(transfer_result.expect("Transfer should succeed").0).unwrap();
+
+    let start: u64 = 0;
+    let limit: u64 = 10;
+
+    let query_args = encode_args((to.owner, start, limit, None::<Vec<u8>>))
+        .expect("Failed to encode args for transaction query");
+
+    let response = pic
+        .query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transactions_by_principal",
+            query_args,
+        )
+        .expect("Failed to query transactions");
+
+    let transactions: Vec<TransactionEvent> =
+        decode_one(&response).expect("Failed to decode transactions");
+
+    assert_eq!(
+        transactions.len(),
+        1,
+        "Recipient should appear in exactly one block"
+    );
+
+    let tx = &transactions[0];
+    assert_eq!(tx.index, block_index);
+    assert_eq!(tx.event_type, "Transfer");
+    assert_eq!(tx.from.owner, admin);
+    assert_eq!(
+        tx.to.as_ref().unwrap().owner,
+        Principal::from_text("2vxsx-fae").unwrap()
+    );
+    assert_eq!(tx.amount, Nat::from(1_000_000_000_u128));
+
+    // The block can also be looked up by its hash.
+    let by_id: Option<TransactionEvent> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transaction_by_id",
+            encode_args((tx.tx_id,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(by_id.map(|event| event.index), Some(block_index));
+}
+
+#[test]
+fn test_transfer_memos() {
+    let (pic, backend_canister, admin) = setup();
+
+    let to = Account {
+        owner: Principal::from_text("2vxsx-fae").unwrap(),
+        subaccount: None,
+    };
+    let transfer = |memo: Option<Vec<u8>>| -> Result<Nat, TransferError> {
+        let transfer_args = TransferArgs {
+            from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+            to: to.clone(),
+            amount: Nat::from(1_000_000_u128),
+            fee: None,
+            memo,
+            created_at_time: None,
+        };
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "icrc1_transfer",
+                encode_args((transfer_args,)).unwrap(),
+            )
+            .expect("Failed to call icrc1_transfer"),
+        )
+        .unwrap()
+    };
+
+    transfer(Some(b"invoice-1".to_vec())).expect("Transfer with memo should succeed");
+    transfer(Some(b"invoice-2".to_vec())).expect("Transfer with memo should succeed");
+    transfer(None).expect("Transfer without memo should succeed");
+
+    // The memo is persisted and can be used to filter a principal's history.
+    let transactions: Vec<TransactionEvent> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transactions_by_principal",
+            encode_args((to.owner, 0u64, 10u64, Some(b"invoice-2".to_vec()))).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(transactions.len(), 1);
+    assert_eq!(transactions[0].memo, Some(b"invoice-2".to_vec()));
+
+    // Memos longer than the default maximum of 32 bytes are rejected.
+    let max_length: u64 = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_max_memo_length",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(max_length, 32);
+    match transfer(Some(vec![0u8; 33])) {
+        Err(TransferError::GenericError { message, .. }) => {
+            assert!(
+                message.contains("MemoTooLong"),
+                "Unexpected error: {}",
+                message
+            )
+        }
+        other => panic!("Expected MemoTooLong, got {:?}", other),
+    }
+
+    // The admin can raise the limit.
+    let result: Result<(), LedgerError> = decode_one(
+        &pic.update_call(
+            backend_canister,
+            admin,
+            "set_max_memo_length",
+            encode_args((64u64,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(result.is_ok());
+    transfer(Some(vec![0u8; 33])).expect("Memo within the new limit should be accepted");
+
+    let result: Result<(), LedgerError> = decode_one(
+        &pic.update_call(
+            backend_canister,
+            to.owner,
+            "set_max_memo_length",
+            encode_args((8u64,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(result.is_err(), "Only the admin can change the memo limit");
+}
+
+#[test]
+fn test_get_account_transactions() {
+    let (pic, backend_canister, admin) = setup();
+
+    let owner = create_mock_ii_principal(5);
+    let savings = Account {
+        owner,
+        subaccount: Some([7u8; 32]),
+    };
+    let spending = Account {
+        owner,
+        subaccount: None,
+    };
+    let amount = Nat::from(1_000_000_u128);
+    let mut block_ids = Vec::new();
+    for to in [&savings, &spending, &savings, &savings] {
+        let transfer_args = TransferArgs {
+            from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+            to: to.clone(),
+            amount: amount.clone(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let result: Result<Nat, TransferError> = decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "icrc1_transfer",
+                encode_args((transfer_args,)).unwrap(),
+            )
+            .expect("Failed to call icrc1_transfer"),
+        )
+        .unwrap();
+        block_ids.push(result.expect("Transfer should succeed"));
+    }
+
+    let get_page = |account: &Account, start: Option<Nat>, max_results: u64| -> GetTransactions {
+        let args = GetAccountTransactionsArgs {
+            account: account.clone(),
+            start,
+            max_results: Nat::from(max_results),
+        };
+        let result: Result<GetTransactions, GetTransactionsErr> = decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "get_account_transactions",
+                encode_args((args,)).unwrap(),
+            )
+            .expect("Failed to query account transactions"),
+        )
+        .unwrap();
+        result.expect("Query should succeed")
+    };
+
+    // Pages are returned newest first and only contain the requested subaccount.
+    let page = get_page(&savings, None, 2);
+    let ids: Vec<Nat> = page.transactions.iter().map(|tx| tx.id.clone()).collect();
+    assert_eq!(ids, vec![block_ids[3].clone(), block_ids[2].clone()]);
+    assert_eq!(page.balance, Nat::from(3_000_000_u128));
+    assert_eq!(page.oldest_tx_id, Some(block_ids[0].clone()));
+
+    // The next page starts after the last block seen.
+    let page = get_page(&savings, Some(block_ids[2].clone()), 2);
+    let ids: Vec<Nat> = page.transactions.iter().map(|tx| tx.id.clone()).collect();
+    assert_eq!(ids, vec![block_ids[0].clone()]);
+
+    let page = get_page(&spending, None, 10);
+    assert_eq!(page.transactions.len(), 1);
+    assert_eq!(page.transactions[0].id, block_ids[1]);
+    assert_eq!(page.transactions[0].transaction.to, Some(spending.clone()));
+    assert_eq!(page.balance, amount);
+
+    // get_transactions_by_principal covers all subaccounts of the principal.
+    let transactions: Vec<TransactionEvent> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transactions_by_principal",
+            encode_args((owner, 0u64, 10u64, None::<Vec<u8>>)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let indices: Vec<Nat> = transactions.iter().map(|tx| Nat::from(tx.index)).collect();
+    assert_eq!(indices, block_ids);
+}
+
+#[test]
+fn test_account_transactions_default_subaccount_and_principal_lengths() {
+    let (pic, backend_canister, admin) = setup();
+
+    // The short principal sorts after the long one byte-wise but is encoded first.
+    let short_owner = Principal::from_slice(&[0xffu8; 5]);
+    let long_owner = Principal::from_slice(&[0x01u8; 29]);
+    let accounts = [
+        Account {
+            owner: short_owner,
+            subaccount: None,
+        },
+        Account {
+            owner: long_owner,
+            subaccount: None,
+        },
+        Account {
+            owner: short_owner,
+            subaccount: Some([1u8; 32]),
+        },
+        Account {
+            owner: long_owner,
+            subaccount: Some([0u8; 32]),
+        },
+    ];
+    let mut block_ids = Vec::new();
+    for to in &accounts {
+        let transfer_args = TransferArgs {
+            from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+            to: to.clone(),
+            amount: Nat::from(1_000_000_u128),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let result: Result<Nat, TransferError> = decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "icrc1_transfer",
+                encode_args((transfer_args,)).unwrap(),
+            )
+            .expect("Failed to call icrc1_transfer"),
+        )
+        .unwrap();
+        block_ids.push(result.expect("Transfer should succeed"));
+    }
+
+    let account_ids = |account: &Account| -> Vec<Nat> {
+        let args = GetAccountTransactionsArgs {
+            account: account.clone(),
+            start: None,
+            max_results: Nat::from(10u64),
+        };
+        let result: Result<GetTransactions, GetTransactionsErr> = decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "get_account_transactions",
+                encode_args((args,)).unwrap(),
+            )
+            .expect("Failed to query account transactions"),
+        )
+        .unwrap();
+        let page = result.expect("Query should succeed");
+        page.transactions.into_iter().map(|tx| tx.id).collect()
+    };
+    let principal_ids = |owner: Principal| -> Vec<Nat> {
+        let transactions: Vec<TransactionEvent> = decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "get_transactions_by_principal",
+                encode_args((owner, 0u64, 10u64, None::<Vec<u8>>)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        transactions.iter().map(|tx| Nat::from(tx.index)).collect()
+    };
+
+    assert_eq!(account_ids(&accounts[0]), vec![block_ids[0].clone()]);
+    assert_eq!(account_ids(&accounts[2]), vec![block_ids[2].clone()]);
+    // None and the all-zero subaccount are the same account.
+    let default_long = vec![block_ids[3].clone(), block_ids[1].clone()];
+    assert_eq!(account_ids(&accounts[1]), default_long);
+    assert_eq!(account_ids(&accounts[3]), default_long);
+
+    assert_eq!(
+        principal_ids(short_owner),
+        vec![block_ids[0].clone(), block_ids[2].clone()]
+    );
+    assert_eq!(
+        principal_ids(long_owner),
+        vec![block_ids[1].clone(), block_ids[3].clone()]
+    );
+}
+
+#[test]
+fn test_block_log_records_ledger_mutations() {
+    let (pic, backend_canister, admin) = setup();
+
+    let spender = Principal::from_text("2vxsx-fae").unwrap();
+    let recipient = Account {
+        owner: Principal::from_text(
+            "dmp4o-pkoo3-lnzzj-cystz-2jlkk-v4zcv-yc5h4-iqoeg-v5arm-avsbm-bae",
+        )
+        .unwrap(),
+        subaccount: None,
+    };
+
+    let count_before: u64 = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transaction_count",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    // Initial pool allocations are recorded as genesis mint blocks.
+    assert_eq!(count_before, 3);
+
+    // Mint, approve and transfer all append blocks.
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "admin_mint",
+            encode_args((
+                recipient.clone(),
+                Nat::from(1_000_000_000_u128),
+                None::<String>,
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+    let result: Result<Nat, LedgerError> = decode_one(&response).unwrap();
+    assert!(result.is_ok());
+
+    let approve_args = ApproveArgs {
+        from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+        spender: Account {
+            owner: spender,
+            subaccount: None,
+        },
+        amount: Nat::from(500_000_000_u128),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "icrc2_approve",
+            encode_args((approve_args,)).unwrap(),
+        )
+        .unwrap();
+    let result: Result<Nat, ApproveError> = decode_one(&response).unwrap();
+    assert!(result.is_ok());
+
+    let transactions: Vec<TransactionEvent> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transactions",
+            encode_args((0u64, u64::MAX)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    // Block indices are dense and start at zero.
+    for (position, tx) in transactions.iter().enumerate() {
+        assert_eq!(tx.index, position as u64);
+    }
+    let event_types: Vec<&str> = transactions
+        .iter()
+        .skip(count_before as usize)
+        .map(|tx| tx.event_type.as_str())
+        .collect();
+    assert_eq!(event_types[0], "Mint");
+    assert_eq!(event_types[1], "Approval");
+    assert!(event_types[2..]
+        .iter()
+        .all(|event_type| *event_type == "Burn" || *event_type == "FeeDistribution"));
+}
+
+#[test]
+fn test_pool_to_pool_transfer() {
+    let (pic, backend_canister, admin) = setup();
+
+    // Get initial balances
+    let community_balance_before: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_community_pool_balance",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let reserve_balance_before: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_reserve_pool_balance",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    println!("Community balance before: {}", community_balance_before);
+    println!("Reserve balance before: {}", reserve_balance_before);
+
+    // Transfer 1000 CRNL from reserve to community pool
+    let transfer_amount = Nat::from(100_000_000_000_u128); // 1000 CRNL
+    let args = encode_args((PoolTransferArgs {
+        from_pool: "reserve".to_string(),
+        to_pool: Some("community".to_string()),
+        to_principal: None,
+        amount: transfer_amount.clone(),
+        description: "Pool rebalancing".to_string(),
+        memo: None,
+    },))
+    .expect("Failed to encode args");
+
+    let response = pic
+        .update_call(backend_canister, admin, "admin_transfer", args)
+        .expect("Failed to call admin_transfer");
+    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
+    assert!(result.is_ok(), "Transfer should succeed: {:?}", result);
+
+    // Verify balances changed correctly
+    let community_balance_after: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_community_pool_balance",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let reserve_balance_after: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_reserve_pool_balance",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    println!("Community balance after: {}", community_balance_after);
+    println!("Reserve balance after: {}", reserve_balance_after);
+
+    assert_eq!(
+        community_balance_after,
+        community_balance_before.clone() + transfer_amount.clone()
+    );
+    assert_eq!(
+        reserve_balance_after,
+        reserve_balance_before.clone() - transfer_amount.clone()
+    );
+}
+
+#[test]
+fn test_pool_to_principal_transfer() {
+    let (pic, backend_canister, admin) = setup();
+
+    // Create a recipient account
+    let recipient = Account {
+        owner: create_mock_ii_principal(5),
+        subaccount: None,
+    };
+
+    // Get initial balances
+    let reserve_balance_before: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_reserve_pool_balance",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let recipient_balance_before: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            encode_args((recipient.clone(),)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    println!("Reserve balance before: {}", reserve_balance_before);
+    println!("Recipient balance before: {}", recipient_balance_before);
+
+    // Transfer 500 CRNL from reserve to recipient
+    let transfer_amount = Nat::from(50_000_000_000_u128); // 500 CRNL
+    let args = encode_args((PoolTransferArgs {
+        from_pool: "reserve".to_string(),
+        to_pool: None,
+        to_principal: Some(recipient.clone()),
+        amount: transfer_amount.clone(),
+        description: "Grant payment".to_string(),
+        memo: None,
+    },))
+    .expect("Failed to encode args");
+
+    let response = pic
+        .update_call(backend_canister, admin, "admin_transfer", args)
+        .expect("Failed to call admin_transfer");
+    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
+    assert!(result.is_ok(), "Transfer should succeed: {:?}", result);
+
+    // Verify balances changed correctly
+    let reserve_balance_after: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_reserve_pool_balance",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let recipient_balance_after: Nat = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            encode_args((recipient.clone(),)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    println!("Reserve balance after: {}", reserve_balance_after);
+    println!("Recipient balance after: {}", recipient_balance_after);
+
+    assert_eq!(
+        reserve_balance_after,
+        reserve_balance_before.clone() - transfer_amount.clone()
+    );
+    assert_eq!(
+        recipient_balance_after,
+        recipient_balance_before.clone() + transfer_amount.clone()
+    );
+}
+
+#[test]
+fn test_pool_transfer_insufficient_balance() {
+    let (pic, backend_canister, admin) = setup();
+
+    // Try to transfer more than the pool has
+    let excessive_amount = Nat::from(100_000_000_000_000_000_000_u128); // More than any pool
+    let args = encode_args((PoolTransferArgs {
+        from_pool: "community".to_string(),
+        to_pool: Some("reserve".to_string()),
+        to_principal: None,
+        amount: excessive_amount,
+        description: "Should fail".to_string(),
+        memo: None,
+    },))
+    .expect("Failed to encode args");
+
+    let response = pic
+        .update_call(backend_canister, admin, "admin_transfer", args)
+        .expect("Failed to call admin_transfer");
+    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
+    assert!(
+        matches!(result, Err(LedgerError::InsufficientBalance)),
+        "Should fail with insufficient balance"
+    );
+}
+
+#[test]
+fn test_pool_transfer_authorization() {
+    let (pic, backend_canister, _admin) = setup();
+    let non_admin = create_mock_ii_principal(10);
+
+    // Try to transfer as non-admin
+    let transfer_amount = Nat::from(1_000_000_000_u128); // 10 CRNL
+    let args = encode_args((PoolTransferArgs {
+        from_pool: "reserve".to_string(),
+        to_pool: Some("community".to_string()),
+        to_principal: None,
+        amount: transfer_amount,
+        description: "Unauthorized attempt".to_string(),
+        memo: None,
+    },))
+    .expect("Failed to encode args");
+
+    let response = pic
+        .update_call(backend_canister, non_admin, "admin_transfer", args)
+        .expect("Failed to call admin_transfer");
+    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
+    assert!(
+        matches!(
+            result,
+            Err(LedgerError::AdminRequired) | Err(LedgerError::NotAuthenticated)
+        ),
+        "Non-admin should not be able to transfer from pools: {:?}",
+        result
+    );
+}
+
+#[test]
+fn test_pool_transfer_invalid_pool_name() {
+    let (pic, backend_canister, admin) = setup();
+
+    // Try to transfer from invalid pool
+    let transfer_amount = Nat::from(1_000_000_000_u128);
+    let args = encode_args((PoolTransferArgs {
+        from_pool: "invalid_pool".to_string(),
+        to_pool: Some("community".to_string()),
+        to_principal: None,
+        amount: transfer_amount,
+        description: "Invalid pool".to_string(),
+        memo: None,
+    },))
+    .expect("Failed to encode args");
+
+    let response = pic
+        .update_call(backend_canister, admin, "admin_transfer", args)
+        .expect("Failed to call admin_transfer");
+    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
+    assert!(
+        matches!(result, Err(LedgerError::InvalidAccount)),
+        "Should fail with invalid account"
+    );
+}
+
+#[test]
+fn test_pool_transfer_all_pool_combinations() {
+    let (pic, backend_canister, admin) = setup();
+
+    let pools = vec!["community", "reserve", "dapp"];
+    let transfer_amount = Nat::from(1_000_000_000_u128); // 10 CRNL
+
+    for from_pool in &pools {
+        for to_pool in &pools {
+            if from_pool == to_pool {
+                continue; // Skip same pool transfers
+            }
+
+            println!("Testing transfer from {} to {}", from_pool, to_pool);
+
+            let args = encode_args((PoolTransferArgs {
+                from_pool: from_pool.to_string(),
+                to_pool: Some(to_pool.to_string()),
+                to_principal: None,
+                amount: transfer_amount.clone(),
+                description: format!("Test {} to {}", from_pool, to_pool),
+                memo: None,
+            },))
+            .expect("Failed to encode args");
+
+            let response = pic
+                .update_call(backend_canister, admin, "admin_transfer", args)
+                .expect("Failed to call admin_transfer");
+            let result: Result<String, LedgerError> = decode_one(&response).unwrap();
+            assert!(
+                result.is_ok(),
+                "Transfer from {} to {} should succeed: {:?}",
+                from_pool,
+                to_pool,
+                result
+            );
+        }
+    }
+}
+
+#[test]
+fn test_icrc3_get_blocks() {
+    let (pic, backend_canister, admin) = setup();
+    // Move the clock off a whole second, so that block timestamps show whether
+    // they keep their nanoseconds.
+    let now = pic.get_time().as_nanos_since_unix_epoch();
+    pic.advance_time(Duration::from_nanos(
+        1_000_000_000 - now % 1_000_000_000 + 123_456_789,
+    ));
+    let now = pic.get_time().as_nanos_since_unix_epoch();
+
+    let transfer_args = TransferArgs {
+        from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+        to: Account {
+            owner: Principal::from_text("2vxsx-fae").unwrap(),
+            subaccount: None,
+        },
+        amount: Nat::from(1_000_000_000_u128),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "icrc1_transfer",
+            encode_args((transfer_args,)).unwrap(),
+        )
+        .unwrap();
+    let result: Result<Nat, TransferError> = decode_one(&response).unwrap();
+    assert!(result.is_ok());
+    let approve_args = ApproveArgs {
+        from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+        spender: Account {
+            owner: create_mock_ii_principal(2),
+            subaccount: None,
+        },
+        amount: Nat::from(5_000_000u64),
+        expected_allowance: Some(Nat::from(0u64)),
+        expires_at: Some(u64::MAX),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "icrc2_approve",
+            encode_args((approve_args,)).unwrap(),
+        )
+        .unwrap();
+    let result: Result<Nat, ApproveError> = decode_one(&response).unwrap();
+    assert!(result.is_ok());
+
+    let args = vec![GetBlocksArgs {
+        start: Nat::from(0u64),
+        length: Nat::from(100u64),
+    }];
+    let blocks: GetBlocksResult = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc3_get_blocks",
+            encode_args((args,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(blocks.log_length, Nat::from(blocks.blocks.len()));
+    assert!(blocks.archived_blocks.is_empty());
+
+    let transactions: Vec<TransactionEvent> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transactions",
+            encode_args((0u64, u64::MAX)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(transactions.len(), blocks.blocks.len());
+
+    let supported: Vec<SupportedBlockType> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc3_supported_block_types",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    for (position, block) in blocks.blocks.iter().enumerate() {
+        assert_eq!(block.id, Nat::from(position));
+
+        // Every block links to the hash of its predecessor.
+        let phash = map_field(&block.block, "phash");
+        if position == 0 {
+            assert!(phash.is_none());
+        } else {
+            assert_eq!(
+                phash,
+                Some(&Value::Blob(transactions[position - 1].tx_id.to_vec()))
+            );
+        }
+
+        let Some(Value::Text(btype)) = map_field(&block.block, "btype") else {
+            panic!("Block {} has no btype", position);
+        };
+        assert!(
+            supported.iter().any(|t| &t.block_type == btype),
+            "Block type {} should be advertised",
+            btype
+        );
+    }
+
+    // The transfer is a standard ICRC-1 transfer block carrying the fee.
+    let transfer_block = &blocks.blocks[transactions
+        .iter()
+        .position(|tx| tx.event_type == "Transfer")
+        .unwrap()]
+    .block;
+    assert_eq!(
+        map_field(transfer_block, "btype"),
+        Some(&Value::Text("1xfer".to_string()))
+    );
+    assert!(map_field(transfer_block, "fee").is_some());
+    let tx = map_field(transfer_block, "tx").unwrap();
+    assert_eq!(
+        map_field(tx, "amt"),
+        Some(&Value::Nat(Nat::from(1_000_000_000_u128)))
+    );
+    let Some(Value::Nat(ts)) = map_field(transfer_block, "ts") else {
+        panic!("The transfer block has no ts");
+    };
+    assert!(*ts >= Nat::from(now));
+
+    // Approval blocks carry the expected allowance and the expiry.
+    let approve_block = &blocks.blocks[transactions
+        .iter()
+        .position(|tx| tx.event_type == "Approval")
+        .unwrap()]
+    .block;
+    let tx = map_field(approve_block, "tx").unwrap();
+    assert_eq!(
+        map_field(tx, "expected_allowance"),
+        Some(&Value::Nat(Nat::from(0u64)))
+    );
+    assert_eq!(
+        map_field(tx, "expires_at"),
+        Some(&Value::Nat(Nat::from(u64::MAX)))
+    );
+
+    let archives: Vec<ICRC3ArchiveInfo> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc3_get_archives",
+            encode_args((GetArchivesArgs { from: None },)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(archives.is_empty());
+}
+
+#[test]
+fn test_icrc3_get_tip_certificate() {
+    let (pic, backend_canister, _admin) = setup();
+
+    let certificate: Option<ICRC3DataCertificate> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc3_get_tip_certificate",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let certificate = certificate.expect("The genesis blocks should be certified");
+    assert!(!certificate.certificate.is_empty());
+    // The hash tree is self-describing CBOR.
+    assert_eq!(&certificate.hash_tree[..3], &[0xd9, 0xd9, 0xf7]);
+}
+
+#[test]
+fn test_block_archiving() {
+    let (pic, backend_canister, admin) = setup();
+
+    let archive_wasm = fs::read(ARCHIVE_WASM).expect("Archive wasm not found, run 'cargo build'.");
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "set_archive_wasm",
+            encode_args((archive_wasm,)).unwrap(),
+        )
+        .unwrap();
+    let result: Result<(), LedgerError> = decode_one(&response).unwrap();
+    assert!(result.is_ok());
+
+    let options = ArchiveOptions {
+        trigger_threshold: 8,
+        num_blocks_to_archive: 6,
+        max_log_entries: 100_000,
+        cycles_for_archive_creation: 500_000_000_000,
+    };
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "set_archive_options",
+            encode_args((options,)).unwrap(),
+        )
+        .unwrap();
+    let result: Result<(), LedgerError> = decode_one(&response).unwrap();
+    assert!(result.is_ok());
+
+    // Each transfer appends the transfer block and its fee split.
+    for i in 0..2u64 {
+        let transfer_args = TransferArgs {
+            from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+            to: Account {
+                owner: Principal::from_text("2vxsx-fae").unwrap(),
+                subaccount: None,
+            },
+            amount: Nat::from(1_000_000_000_u128 + i as u128),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let response = pic
+            .update_call(
+                backend_canister,
+                admin,
+                "icrc1_transfer",
+                encode_args((transfer_args,)).unwrap(),
+            )
+            .unwrap();
+        let result: Result<Nat, TransferError> = decode_one(&response).unwrap();
+        assert!(result.is_ok());
+    }
+
+    // Let the background archiving run complete.
+    for _ in 0..10 {
+        pic.tick();
+    }
+
+    let archives: Vec<ICRC3ArchiveInfo> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc3_get_archives",
+            encode_args((GetArchivesArgs { from: None },)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(archives.len(), 1, "An archive should have been spawned");
+    let archive = &archives[0];
+    assert_eq!(archive.start, Nat::from(0u64));
+    assert_eq!(archive.end, Nat::from(5u64));
+
+    // The ledger still reports the full log and redirects old blocks to the archive.
+    let log_length: u64 = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transaction_count",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let args = vec![GetBlocksArgs {
+        start: Nat::from(0u64),
+        length: Nat::from(log_length),
+    }];
+    let result: GetBlocksResult = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "icrc3_get_blocks",
+            encode_args((args,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(result.log_length, Nat::from(log_length));
+    assert_eq!(
+        result.blocks.first().map(|b| b.id.clone()),
+        Some(Nat::from(6u64))
+    );
+    assert_eq!(result.archived_blocks.len(), 1);
+    let archived = &result.archived_blocks[0];
+    assert_eq!(archived.callback.0.principal, archive.canister_id);
+
+    let archived_result: GetBlocksResult = decode_one(
+        &pic.query_call(
+            archive.canister_id,
+            Principal::anonymous(),
+            &archived.callback.0.method,
+            encode_args((archived.args.clone(),)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let ids: Vec<Nat> = archived_result
+        .blocks
+        .iter()
+        .map(|b| b.id.clone())
+        .collect();
+    assert_eq!(ids, (0..6u64).map(Nat::from).collect::<Vec<_>>());
+    assert_eq!(
+        map_field(&archived_result.blocks[0].block, "btype"),
+        Some(&Value::Text("1mint".to_string()))
+    );
+
+    // The first block kept by the ledger links to the last archived block.
+    let first_local_phash = map_field(&result.blocks[0].block, "phash").cloned();
+    assert!(first_local_phash.is_some());
+
+    // Archived blocks are removed from the ledger's storage and indexes.
+    let local_blocks: u64 = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_local_block_count",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(local_blocks, log_length - 6);
+    let archived_tx_id = match map_field(&result.blocks[0].block, "phash") {
+        Some(Value::Blob(hash)) => <[u8; 32]>::try_from(hash.as_slice()).unwrap(),
+        other => panic!("Unexpected phash {:?}", other),
+    };
+    let by_id: Option<TransactionEvent> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_transaction_by_id",
+            encode_args((archived_tx_id,)).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(by_id.is_none());
+    let history: Result<GetTransactions, GetTransactionsErr> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_account_transactions",
+            encode_args((GetAccountTransactionsArgs {
+                account: Account {
+                    owner: admin,
+                    subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+                },
+                start: None,
+                max_results: Nat::from(100u64),
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let oldest = history.unwrap().oldest_tx_id.unwrap();
+    assert!(oldest >= Nat::from(6u64));
+
+    // Archiving is halted outside the Normal mode.
+    assert_eq!(
+        set_operating_mode(&pic, backend_canister, admin, OperatingMode::ReadOnly),
+        Ok(())
+    );
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "archive_blocks",
+            encode_args(()).unwrap(),
+        )
+        .unwrap();
+    let result: Result<u64, LedgerError> = decode_one(&response).unwrap();
+    assert!(matches!(result, Err(LedgerError::Unavailable(_))));
+}
+
+#[test]
+fn test_icrc21_consent_messages() {
+    let (pic, backend_canister, _admin) = setup();
+
+    let recipient = Account {
+        owner: Principal::from_text("2vxsx-fae").unwrap(),
+        subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+    };
+
+    // Transfer: amount and fee in whole tokens, recipient in ICRC-1 textual form.
+    let transfer_args = TransferArgs {
+        from_subaccount: None,
+        to: recipient.clone(),
+        amount: Nat::from(150_000_000_u128),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let info = consent_message(
+        &pic,
+        backend_canister,
+        "icrc1_transfer",
+        candid::encode_one(transfer_args).unwrap(),
+        None,
+    )
+    .expect("Transfer consent message should be available");
+    assert_eq!(info.metadata.language, "en");
+    let ConsentMessage::GenericDisplayMessage(message) = info.consent_message else {
+        panic!("Expected a generic display message");
+    };
+    assert!(message.contains("1.5 CRNL"), "{}", message);
+    assert!(message.contains("0.001 CRNL"), "{}", message);
+    assert!(message.contains("2vxsx-fae-"), "{}", message);
+    assert!(message.contains(".101010101010101010101010101010101010101010101010101010101010101"));
+
+    // Approval: allowance and expiry.
+    let approve_args = ApproveArgs {
+        from_subaccount: None,
+        spender: recipient.clone(),
+        amount: Nat::from(200_000_000_u128),
+        expected_allowance: None,
+        expires_at: Some(1_700_000_000_000_000_000),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let info = consent_message(
+        &pic,
+        backend_canister,
+        "icrc2_approve",
+        candid::encode_one(approve_args).unwrap(),
+        None,
+    )
+    .expect("Approve consent message should be available");
+    let ConsentMessage::GenericDisplayMessage(message) = info.consent_message else {
+        panic!("Expected a generic display message");
+    };
+    assert!(message.contains("2 CRNL"), "{}", message);
+    assert!(message.contains("2023-11-14 22:13 UTC"), "{}", message);
+
+    // Deduction: description, rendered on a small line display.
+    let deduct_args = DeductBalanceArgs {
+        caller: recipient,
+        amount: Nat::from(100_000_000_u128),
+        description: "Create chronolock".to_string(),
+        memo: None,
+    };
+    let info = consent_message(
+        &pic,
+        backend_canister,
+        "deduct_from_balance",
+        candid::encode_one(deduct_args).unwrap(),
+        Some(DisplayMessageType::LineDisplay {
+            characters_per_line: 20,
+            lines_per_page: 3,
+        }),
+    )
+    .expect("Deduction consent message should be available");
+    let ConsentMessage::LineDisplayMessage { pages } = info.consent_message else {
+        panic!("Expected a line display message");
+    };
+    assert!(pages
+        .iter()
+        .all(|page| page.lines.len() <= 3 && page.lines.iter().all(|l| l.chars().count() <= 20)));
+    let text: String = pages.iter().flat_map(|p| p.lines.clone()).collect();
+    assert!(text.contains("Create chronolock"), "{}", text);
+
+    // Unsupported methods are rejected.
+    let result = consent_message(&pic, backend_canister, "admin_mint", Vec::new(), None);
+    assert!(matches!(
+        result,
+        Err(Icrc21Error::UnsupportedCanisterCall(_))
+    ));
+}
+
+#[test]
+fn test_account_textual_encoding() {
+    let (pic, backend_canister, _admin) = setup();
+
+    let owner =
+        Principal::from_text("k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae")
+            .unwrap();
+    let mut subaccount = [0u8; 32];
+    for (i, byte) in subaccount.iter_mut().enumerate() {
+        *byte = i as u8 + 1;
+    }
+    let cases = vec![
+        (
+            Account {
+                owner,
+                subaccount: None,
+            },
+            "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae",
+        ),
+        (
+            Account {
+                owner,
+                subaccount: Some([0u8; 32]),
+            },
+            "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae",
+        ),
+        (
+            Account {
+                owner,
+                subaccount: Some(subaccount),
+            },
+            "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+        ),
+    ];
+
+    for (account, expected) in cases {
+        let text: String = decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "format_account",
+                encode_args((account.clone(),)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(text, expected);
+
+        let parsed: Result<Account, LedgerError> = decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "parse_account",
+                encode_args((text,)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let parsed = parsed.expect("Formatted account should parse");
+        assert_eq!(parsed.owner, account.owner);
+        assert_eq!(
+            parsed.subaccount.filter(|s| *s != [0u8; 32]),
+            account.subaccount.filter(|s| *s != [0u8; 32])
+        );
+    }
+
+    // Bad checksum, leading zeros and the explicit default subaccount are rejected.
+    for invalid in [
+        "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae-aaaaaaa.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+        "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae-dfxgiyy.0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+        "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae-q6bn32y.",
+        "not-a-principal",
+    ] {
+        let parsed: Result<Account, LedgerError> = decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "parse_account",
+                encode_args((invalid.to_string(),)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(parsed, Err(LedgerError::InvalidAccount), "{}", invalid);
+    }
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug, PartialEq)]
+struct RateLimit {
+    capacity: u32,
+    refill_interval: u64,
+}
+
+#[test]
+fn test_register_user_rate_limit() {
+    let (pic, backend_canister, admin) = setup();
+    let user = create_mock_ii_principal(40);
+    let register = |caller: Principal| {
+        let account = Account {
+            owner: caller,
+            subaccount: None,
+        };
+        decode_one::<Result<String, LedgerError>>(
+            &pic.update_call(
+                backend_canister,
+                caller,
+                "register_user",
+                encode_args((account,)).unwrap(),
+            )
+            .expect("Failed to call register_user"),
+        )
+        .unwrap()
+    };
+    let set_rate_limit = |caller: Principal, method: &str, limit: Option<RateLimit>| {
+        decode_one::<Result<(), LedgerError>>(
+            &pic.update_call(
+                backend_canister,
+                caller,
+                "set_rate_limit",
+                encode_args((method.to_string(), limit)).unwrap(),
+            )
+            .expect("Failed to call set_rate_limit"),
+        )
+        .unwrap()
+    };
+
+    let limit = RateLimit {
+        capacity: 2,
+        refill_interval: 60,
+    };
+    assert_eq!(
+        set_rate_limit(user, "register_user", Some(limit.clone())),
+        Err(LedgerError::AdminRequired)
+    );
+    assert!(matches!(
+        set_rate_limit(admin, "icrc1_transfer", Some(limit.clone())),
+        Err(LedgerError::InvalidRateLimit(_))
+    ));
+    assert_eq!(
+        set_rate_limit(admin, "register_user", Some(limit.clone())),
+        Ok(())
+    );
+    let limits: Vec<(String, RateLimit)> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_rate_limits",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(limits.contains(&("register_user".to_string(), limit)));
+
+    // Failed calls count against the bucket too.
+    assert!(register(user).is_ok());
+    assert_eq!(register(user), Err(LedgerError::AlreadyRegistered));
+    assert!(matches!(
+        register(user),
+        Err(LedgerError::RateLimited { retry_after }) if retry_after > 0 && retry_after <= 60
+    ));
+    // Buckets are per principal and admins are not limited.
+    assert!(register(create_mock_ii_principal(41)).is_ok());
+    assert!(register(admin).is_ok());
+
+    // The bucket survives an upgrade.
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'cargo build'.");
+    pic.upgrade_canister(backend_canister, wasm, encode_args(()).unwrap(), None)
+        .expect("Failed to upgrade the ledger");
+    assert!(matches!(
+        register(user),
+        Err(LedgerError::RateLimited { .. })
+    ));
+
+    // One call is regained per refill interval.
+    pic.advance_time(Duration::from_secs(61));
+    assert_eq!(register(user), Err(LedgerError::AlreadyRegistered));
+    assert!(matches!(
+        register(user),
+        Err(LedgerError::RateLimited { .. })
+    ));
+}
+
+#[test]
+fn test_inspect_message_rejects_invalid_ingress() {
+    let (pic, backend_canister, admin) = setup();
+
+    // Anonymous callers can never register.
+    let anonymous = Account {
+        owner: Principal::anonymous(),
+        subaccount: None,
+    };
+    assert!(pic
+        .update_call(
+            backend_canister,
+            Principal::anonymous(),
+            "register_user",
+            encode_args((anonymous,)).unwrap(),
+        )
+        .is_err());
+
+    // Oversized arguments are dropped before execution.
+    assert!(pic
+        .update_call(
+            backend_canister,
+            admin,
+            "set_logo",
+            encode_args(("a".repeat(100 * 1024),)).unwrap(),
+        )
+        .is_err());
+    let result: Result<(), LedgerError> = decode_one(
+        &pic.update_call(
+            backend_canister,
+            admin,
+            "set_logo",
+            encode_args(("data:image/png;base64,AAAA".to_string(),)).unwrap(),
+        )
+        .expect("Small arguments should be accepted"),
+    )
+    .unwrap();
+    assert_eq!(result, Ok(()));
+}
+
+#[derive(CandidType, serde::Deserialize, Default)]
+struct UpgradeArgs {
+    max_memo_length: Option<u64>,
+    transaction_window: Option<u64>,
+}
+
+#[test]
+fn test_upgrade_args_and_schema_version() {
+    let (pic, backend_canister, admin) = setup();
+    let query_u64 = |method: &str| -> u64 {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                method,
+                encode_args(()).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    let schema_version: u32 = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_schema_version",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(schema_version, 4);
+
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'cargo build'.");
+    let args = UpgradeArgs {
+        max_memo_length: Some(64),
+        ..Default::default()
+    };
+    pic.upgrade_canister(
+        backend_canister,
+        wasm.clone(),
+        encode_args((Some(args),)).unwrap(),
+        None,
+    )
+    .expect("Failed to upgrade with upgrade arguments");
+    assert_eq!(query_u64("get_max_memo_length"), 64);
+    assert_eq!(
+        query_u64("get_transaction_window"),
+        24 * 60 * 60 * 1_000_000_000
+    );
+
+    // The install arguments passed again on upgrade are ignored.
+    let init_args = encode_args((
+        "Chronolock".to_string(),
+        "CRNL".to_string(),
+        100_000_000_000_000_000_000_u128,
+        31_536_000_u64,
+        100_000_u128,
+        admin,
+    ))
+    .unwrap();
+    pic.upgrade_canister(backend_canister, wasm, init_args, None)
+        .expect("Failed to upgrade with the install arguments");
+    assert_eq!(query_u64("get_max_memo_length"), 64);
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+enum OperatingMode {
+    Normal,
+    ReadOnly,
+    Paused,
+    Restoring,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+enum Subsystem {
+    Transfers,
+    Minting,
+    Referrals,
+    Registration,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+enum ExportSection {
+    Supply,
+    Balances,
+    Allowances,
+    ReferralCodes,
+    ReferralClaims,
+    Blocks,
+    ReferralCampaigns,
+    Referrals,
+    VestingSchedules,
+    Roles,
+    Multisig,
+    MintBudgets,
+    Settings,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct VestingSchedule {
+    id: u64,
+    beneficiary: Account,
+    total: u128,
+    start: u64,
+    cliff: u64,
+    duration: u64,
+    period: u64,
+    claimed: u128,
+    revocable: bool,
+    revoked_at: Option<u64>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct MintUsage {
+    mints: Vec<(u64, u128)>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct Transaction {
+    timestamp: u64,
+    event_type: String,
+    from: Account,
+    to: Option<Account>,
+    spender: Option<Account>,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct Block {
+    parent_hash: Option<[u8; 32]>,
+    transaction: Transaction,
+    timestamp_nanos: Option<u64>,
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+enum ExportRecord {
+    Supply {
+        total_supply: Nat,
+        total_burned: Nat,
+    },
+    Balance {
+        account: Account,
+        amount: Nat,
+    },
+    Allowance {
+        owner: Account,
+        spender: Account,
+        amount: Nat,
+        expires_at: Option<u64>,
+    },
+    ReferralCode {
+        account: Account,
+        code: String,
+    },
+    ReferralClaim {
+        account: Account,
+    },
+    LogStart {
+        first_local_block: u64,
+        archive: Option<Principal>,
+    },
+    Block {
+        index: u64,
+        block: Block,
+    },
+    ReferralCampaign {
+        campaign: ReferralCampaign,
+    },
+    Referral {
+        referral: Referral,
+    },
+    VestingSchedule {
+        schedule: VestingSchedule,
+    },
+    RoleMember {
+        role: Role,
+        member: Principal,
+    },
+    MultisigConfig {
+        config: MultisigConfig,
+    },
+    MintBudget {
+        minter: Principal,
+        budget: MintBudget,
+        usage: MintUsage,
+    },
+    Owners {
+        owner: Principal,
+        treasury: Principal,
+    },
+    Metadata {
+        name: String,
+        symbol: String,
+        decimals: u8,
+        transfer_fee: Nat,
+        max_supply: Option<Nat>,
+        logo: Option<String>,
+        vesting_start_time: u64,
+        vesting_duration: u64,
+    },
+    FeeDistribution {
+        shares: Vec<FeeShare>,
+        dust_destination: FeeDestination,
+    },
+    Limits {
+        max_memo_length: u64,
+        transaction_window: u64,
+    },
+}
+
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ExportPage {
+    version: u8,
+    section: ExportSection,
+    offset: u64,
+    records: Vec<ExportRecord>,
+    checksum: [u8; 32],
+    next_offset: Option<u64>,
+}
+
+#[derive(CandidType, serde::Deserialize, Debug, PartialEq)]
+struct ExportChecksum {
+    section: ExportSection,
+    offset: u64,
+    records: u64,
+    checksum: [u8; 32],
+    next_offset: Option<u64>,
+}
+
+const EXPORT_SECTIONS: [ExportSection; 13] = [
+    ExportSection::Supply,
+    ExportSection::Balances,
+    ExportSection::Allowances,
+    ExportSection::ReferralCodes,
+    ExportSection::ReferralClaims,
+    ExportSection::Blocks,
+    ExportSection::ReferralCampaigns,
+    ExportSection::Referrals,
+    ExportSection::VestingSchedules,
+    ExportSection::Roles,
+    ExportSection::Multisig,
+    ExportSection::MintBudgets,
+    ExportSection::Settings,
+];
+
+fn try_export_page(
+    pic: &PocketIc,
+    canister: Principal,
+    admin: Principal,
+    section: ExportSection,
+    offset: u64,
+    limit: u64,
+) -> Result<ExportPage, LedgerError> {
+    let response = pic
+        .query_call(
+            canister,
+            admin,
+            "export_state",
+            encode_args((section, offset, limit)).unwrap(),
+        )
+        .expect("Failed to call export_state");
+    decode_one(&response).unwrap()
+}
+
+fn export_page(
+    pic: &PocketIc,
+    canister: Principal,
+    admin: Principal,
+    section: ExportSection,
+    offset: u64,
+    limit: u64,
+) -> ExportPage {
+    try_export_page(pic, canister, admin, section, offset, limit).unwrap()
+}
+
+fn set_operating_mode(
+    pic: &PocketIc,
+    canister: Principal,
+    admin: Principal,
+    mode: OperatingMode,
+) -> Result<(), LedgerError> {
+    let response = pic
+        .update_call(
+            canister,
+            admin,
+            "set_operating_mode",
+            encode_args((mode,)).unwrap(),
+        )
+        .expect("Failed to call set_operating_mode");
+    decode_one(&response).unwrap()
+}
+
+fn import_page(
+    pic: &PocketIc,
+    canister: Principal,
+    admin: Principal,
+    page: &ExportPage,
+) -> Result<u64, LedgerError> {
+    let response = pic
+        .update_call(
+            canister,
+            admin,
+            "import_state",
+            encode_args((page.clone(),)).unwrap(),
+        )
+        .expect("Failed to call import_state");
+    decode_one(&response).unwrap()
+}
+
+// Installs a fresh ledger with the init arguments of setup and the given admin.
+fn install_ledger(pic: &PocketIc, admin: Principal) -> Principal {
+    let canister = pic.create_canister();
+    pic.add_cycles(canister, 2_000_000_000_000);
+    let wasm = fs::read(BACKEND_WASM).expect("Wasm file not found, run 'cargo build'.");
+    let init_args = encode_args((
+        "Chronolock".to_string(),
+        "CRNL".to_string(),
+        100_000_000_000_000_000_000_u128,
+        31_536_000_u64,
+        100_000_u128,
+        admin,
+    ))
+    .unwrap();
+    pic.install_canister(canister, wasm, init_args, None);
+    enable_admin_bypass(pic, canister, admin);
+    canister
+}
+
+// Imports every section of source into target, in pages of the given size.
+fn import_all(
+    pic: &PocketIc,
+    source: Principal,
+    source_admin: Principal,
+    target: Principal,
+    target_admin: Principal,
+    page_size: u64,
+) {
+    for section in EXPORT_SECTIONS {
+        let mut offset = Some(0u64);
+        while let Some(current) = offset {
+            let page = export_page(pic, source, source_admin, section, current, page_size);
+            let imported = import_page(pic, target, target_admin, &page).unwrap();
+            assert_eq!(imported, page.records.len() as u64);
+            offset = page.next_offset;
+        }
+    }
+}
+
+fn export_checksums(pic: &PocketIc, canister: Principal, admin: Principal) -> Vec<ExportChecksum> {
+    let mut checksums = Vec::new();
+    for section in EXPORT_SECTIONS {
+        let mut offset = Some(0u64);
+        while let Some(current) = offset {
+            let response = pic
+                .query_call(
+                    canister,
+                    admin,
+                    "get_export_checksum",
+                    encode_args((section, current, 100u64)).unwrap(),
+                )
+                .expect("Failed to call get_export_checksum");
+            let result: Result<ExportChecksum, LedgerError> = decode_one(&response).unwrap();
+            let checksum = result.unwrap();
+            offset = checksum.next_offset;
+            checksums.push(checksum);
+        }
+    }
+    checksums
+}
+
+#[test]
+fn test_export_and_import_state() {
+    let (pic, source, admin) = setup();
+    let user = Account {
+        owner: create_mock_ii_principal(2),
+        subaccount: None,
+    };
+    let response = pic
+        .update_call(
+            source,
+            user.owner,
+            "register_user",
+            encode_args((user.clone(),)).unwrap(),
+        )
+        .expect("Failed to register user");
+    let registered: Result<String, LedgerError> = decode_one(&response).unwrap();
+    registered.unwrap();
+    let pool = Account {
+        owner: admin,
+        subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+    };
+    let approve_args = ApproveArgs {
+        from_subaccount: pool.subaccount,
+        spender: user.clone(),
+        amount: Nat::from(5_000_000u64),
+        expected_allowance: None,
+        expires_at: Some(u64::MAX),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let response = pic
+        .update_call(
+            source,
+            admin,
+            "icrc2_approve",
+            encode_args((approve_args,)).unwrap(),
+        )
+        .expect("Failed to approve");
+    let approved: Result<Nat, ApproveError> = decode_one(&response).unwrap();
+    approved.unwrap();
+    let vesting_args = CreateVestingScheduleArgs {
+        beneficiary: user.clone(),
+        total: Nat::from(1_000_000u64),
+        start: None,
+        cliff: 100,
+        duration: 1_000,
+        period: 100,
+        revocable: true,
+    };
+    let budget = MintBudget {
+        amount: 3_000,
+        window: 3_600,
+    };
+    for (method, args) in [
+        (
+            "create_vesting_schedule",
+            encode_args((vesting_args,)).unwrap(),
+        ),
+        (
+            "grant_role",
+            encode_args((Role::Auditor, user.owner)).unwrap(),
+        ),
+        (
+            "set_mint_budget",
+            encode_args((user.owner, Some(budget))).unwrap(),
+        ),
+    ] {
+        pic.update_call(source, admin, method, args)
+            .unwrap_or_else(|e| panic!("Failed to call {}: {:?}", method, e));
+    }
+
+    // Exports are refused while the source can still change.
+    assert!(matches!(
+        try_export_page(&pic, source, admin, ExportSection::Supply, 0, 10),
+        Err(LedgerError::Unavailable(_))
+    ));
+    assert_eq!(
+        set_operating_mode(&pic, source, admin, OperatingMode::ReadOnly),
+        Ok(())
+    );
+
+    // A fresh ledger installed with the same arguments
+    let target = install_ledger(&pic, admin);
+
+    // Imports are refused outside the Restoring mode.
+    let first = export_page(&pic, source, admin, ExportSection::Supply, 0, 10);
+    assert!(matches!(
+        import_page(&pic, target, admin, &first),
+        Err(LedgerError::ImportError(_))
+    ));
+    assert_eq!(
+        set_operating_mode(&pic, target, admin, OperatingMode::Restoring),
+        Ok(())
+    );
+
+    // A page whose checksum does not match its records is rejected.
+    let mut tampered = first.clone();
+    tampered.checksum[0] ^= 1;
+    assert_eq!(
+        import_page(&pic, target, admin, &tampered),
+        Err(LedgerError::ImportError("Checksum mismatch".to_string()))
+    );
+
+    // Small pages so that every section spans several of them
+    import_all(&pic, source, admin, target, admin, 2);
+
+    assert_eq!(
+        export_checksums(&pic, source, admin),
+        export_checksums(&pic, target, admin)
+    );
+    let balance_of = |canister: Principal| -> Nat {
+        decode_one(
+            &pic.query_call(
+                canister,
+                Principal::anonymous(),
+                "icrc1_balance_of",
+                encode_args((user.clone(),)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    assert_eq!(balance_of(target), balance_of(source));
+    let allowance: Allowance = decode_one(
         &pic.query_call(
-            backend_canister,
+            target,
             Principal::anonymous(),
-            "get_reserve_pool_balance",
-            encode_args(()).unwrap(),
+            "icrc2_allowance",
+            encode_args((AllowanceArgs {
+                account: pool,
+                spender: user,
+            },))
+            .unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(allowance.allowance, Nat::from(5_000_000u64));
+    let schedules: Vec<VestingScheduleInfo> = decode_one(
+        &pic.query_call(
+            target,
+            Principal::anonymous(),
+            "get_vesting_schedules",
+            encode_args((user.clone(),)).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
+    assert_eq!(schedules.len(), 1);
+    assert_eq!(schedules[0].total, Nat::from(1_000_000u64));
 
-    let recipient_balance_after: Nat = decode_one(
+    // Blocks can only continue the chain imported so far.
+    let blocks = export_page(&pic, source, admin, ExportSection::Blocks, 1, 1);
+    assert!(matches!(
+        import_page(&pic, target, admin, &blocks),
+        Err(LedgerError::ImportError(_))
+    ));
+
+    assert_eq!(
+        set_operating_mode(&pic, target, admin, OperatingMode::Normal),
+        Ok(())
+    );
+    let mode: OperatingMode = decode_one(
         &pic.query_call(
-            backend_canister,
+            target,
             Principal::anonymous(),
-            "icrc1_balance_of",
-            encode_args((recipient.clone(),)).unwrap(),
+            "get_operating_mode",
+            encode_args(()).unwrap(),
         )
         .unwrap(),
     )
     .unwrap();
+    assert_eq!(mode, OperatingMode::Normal);
+}
 
-    println!("Reserve balance after: {}", reserve_balance_after);
-    println!("Recipient balance after: {}", recipient_balance_after);
+#[test]
+fn test_import_state_into_ledger_with_other_admin() {
+    let (pic, source, admin) = setup();
+    let pool_of = |owner: Principal| Account {
+        owner,
+        subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+    };
+    let call = |canister: Principal, caller: Principal, method: &str, args: Vec<u8>| {
+        let result: Result<(), LedgerError> =
+            decode_one(&pic.update_call(canister, caller, method, args).unwrap()).unwrap();
+        result
+    };
+    let query = |canister: Principal, method: &str, args: Vec<u8>| {
+        pic.query_call(canister, Principal::anonymous(), method, args)
+            .unwrap()
+    };
 
+    // Settings that differ from those of a freshly installed ledger
+    let shares = vec![FeeShare {
+        destination: FeeDestination::Burn,
+        basis_points: 10_000,
+    }];
+    for (method, args) in [
+        ("set_transfer_fee", encode_args((12_345_u128,)).unwrap()),
+        ("set_max_memo_length", encode_args((64u64,)).unwrap()),
+        (
+            "set_transaction_window",
+            encode_args((3_600_000_000_000u64,)).unwrap(),
+        ),
+        (
+            "set_fee_distribution",
+            encode_args((shares.clone(), FeeDestination::Burn)).unwrap(),
+        ),
+    ] {
+        assert_eq!(call(source, admin, method, args), Ok(()));
+    }
     assert_eq!(
-        reserve_balance_after,
-        reserve_balance_before.clone() - transfer_amount.clone()
+        set_operating_mode(&pic, source, admin, OperatingMode::ReadOnly),
+        Ok(())
     );
+
+    // The new ledger is installed by another admin, whose pools are funded at init.
+    let other_admin = create_mock_ii_principal(3);
+    let target = install_ledger(&pic, other_admin);
     assert_eq!(
-        recipient_balance_after,
-        recipient_balance_before.clone() + transfer_amount.clone()
+        set_operating_mode(&pic, target, other_admin, OperatingMode::Restoring),
+        Ok(())
+    );
+    import_all(&pic, source, admin, target, other_admin, 100);
+
+    let balance_of = |canister: Principal, account: Account| -> Nat {
+        decode_one(&query(
+            canister,
+            "icrc1_balance_of",
+            encode_args((account,)).unwrap(),
+        ))
+        .unwrap()
+    };
+    // The init balances of the new ledger are gone, the pools of the source are back.
+    assert_eq!(balance_of(target, pool_of(other_admin)), Nat::from(0u64));
+    assert_eq!(
+        balance_of(target, pool_of(admin)),
+        balance_of(source, pool_of(admin))
+    );
+    let fee: Nat = decode_one(&query(target, "icrc1_fee", encode_args(()).unwrap())).unwrap();
+    assert_eq!(fee, Nat::from(12_345u64));
+    let max_memo_length: u64 = decode_one(&query(
+        target,
+        "get_max_memo_length",
+        encode_args(()).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(max_memo_length, 64);
+    let window: u64 = decode_one(&query(
+        target,
+        "get_transaction_window",
+        encode_args(()).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(window, 3_600_000_000_000);
+    let distribution: FeeDistribution = decode_one(&query(
+        target,
+        "get_fee_distribution",
+        encode_args(()).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(distribution.shares, shares);
+    assert_eq!(distribution.dust_destination, FeeDestination::Burn);
+
+    // The admin running the restore keeps the ledger until the source's owner
+    // accepts it.
+    let pending: Option<Principal> = decode_one(&query(
+        target,
+        "get_pending_owner",
+        encode_args(()).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(pending, Some(admin));
+    assert_eq!(
+        call(target, admin, "accept_ownership", encode_args(()).unwrap()),
+        Ok(())
+    );
+    assert_eq!(
+        export_checksums(&pic, source, admin),
+        export_checksums(&pic, target, admin)
+    );
+    assert_eq!(
+        set_operating_mode(&pic, target, admin, OperatingMode::Normal),
+        Ok(())
     );
 }
 
 #[test]
-fn test_pool_transfer_insufficient_balance() {
+fn test_operating_modes_and_circuit_breakers() {
     let (pic, backend_canister, admin) = setup();
+    let user = Account {
+        owner: create_mock_ii_principal(2),
+        subaccount: None,
+    };
+    let transfer = || -> Result<Nat, TransferError> {
+        let args = TransferArgs {
+            from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+            to: user.clone(),
+            amount: Nat::from(1_000_000u64),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "icrc1_transfer",
+                encode_args((args,)).unwrap(),
+            )
+            .expect("Failed to call icrc1_transfer"),
+        )
+        .unwrap()
+    };
+    let approve = || -> Result<Nat, ApproveError> {
+        let args = ApproveArgs {
+            from_subaccount: Some(COMMUNITY_POOL_SUBACCOUNT),
+            spender: user.clone(),
+            amount: Nat::from(1_000_000u64),
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "icrc2_approve",
+                encode_args((args,)).unwrap(),
+            )
+            .expect("Failed to call icrc2_approve"),
+        )
+        .unwrap()
+    };
+    let register = || -> Result<String, LedgerError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                user.owner,
+                "register_user",
+                encode_args((user.clone(),)).unwrap(),
+            )
+            .expect("Failed to call register_user"),
+        )
+        .unwrap()
+    };
+    let set_mode = |caller: Principal, mode: OperatingMode| -> Result<(), LedgerError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                caller,
+                "set_operating_mode",
+                encode_args((mode,)).unwrap(),
+            )
+            .expect("Failed to call set_operating_mode"),
+        )
+        .unwrap()
+    };
+    let set_breaker = |subsystem: Subsystem, tripped: bool| -> Result<(), LedgerError> {
+        decode_one(
+            &pic.update_call(
+                backend_canister,
+                admin,
+                "set_circuit_breaker",
+                encode_args((subsystem, tripped)).unwrap(),
+            )
+            .expect("Failed to call set_circuit_breaker"),
+        )
+        .unwrap()
+    };
+    let balance_of = || -> Nat {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "icrc1_balance_of",
+                encode_args((user.clone(),)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
 
-    // Try to transfer more than the pool has
-    let excessive_amount = Nat::from(100_000_000_000_000_000_000_u128); // More than any pool
-    let args = encode_args((PoolTransferArgs {
-        from_pool: "community".to_string(),
-        to_pool: Some("reserve".to_string()),
-        to_principal: None,
-        amount: excessive_amount,
-        description: "Should fail".to_string(),
-    },))
-    .expect("Failed to encode args");
+    // Only the owner changes the mode.
+    assert_eq!(
+        set_mode(user.owner, OperatingMode::Paused),
+        Err(LedgerError::AdminRequired)
+    );
 
+    // A tripped breaker halts its subsystem only.
+    assert_eq!(set_breaker(Subsystem::Transfers, true), Ok(()));
+    let breakers: Vec<Subsystem> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_circuit_breakers",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(breakers, vec![Subsystem::Transfers]);
+    assert_eq!(transfer(), Err(TransferError::TemporarilyUnavailable));
+    assert!(register().is_ok());
+    let balance = balance_of();
+    assert_eq!(set_breaker(Subsystem::Transfers, false), Ok(()));
+    assert!(transfer().is_ok());
+    assert_eq!(balance_of(), balance + Nat::from(1_000_000u64));
+
+    // Paused halts every subsystem but still takes approvals; queries keep working.
+    assert_eq!(set_mode(admin, OperatingMode::Paused), Ok(()));
+    assert_eq!(transfer(), Err(TransferError::TemporarilyUnavailable));
+    assert!(matches!(register(), Err(LedgerError::Unavailable(_))));
+    assert!(approve().is_ok());
+    balance_of();
+
+    // ReadOnly refuses approvals as well, while administration continues.
+    assert_eq!(set_mode(admin, OperatingMode::ReadOnly), Ok(()));
+    assert_eq!(approve(), Err(ApproveError::TemporarilyUnavailable));
     let response = pic
-        .update_call(backend_canister, admin, "admin_transfer", args)
-        .expect("Failed to call admin_transfer");
-    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
-    assert!(
-        matches!(result, Err(LedgerError::InsufficientBalance)),
-        "Should fail with insufficient balance"
+        .update_call(
+            backend_canister,
+            admin,
+            "set_max_memo_length",
+            encode_args((64u64,)).unwrap(),
+        )
+        .expect("Failed to call set_max_memo_length");
+    let result: Result<(), LedgerError> = decode_one(&response).unwrap();
+    assert_eq!(result, Ok(()));
+
+    assert_eq!(set_mode(admin, OperatingMode::Normal), Ok(()));
+    assert!(transfer().is_ok());
+
+    let response = pic
+        .query_call(
+            backend_canister,
+            admin,
+            "get_logs_paginated",
+            encode_args((0u64, 1000u64)).unwrap(),
+        )
+        .expect("Failed to get logs");
+    let logs: Result<Vec<LogEntry>, LedgerError> = decode_one(&response).unwrap();
+    let logs = logs.unwrap();
+    assert!(logs
+        .iter()
+        .any(|entry| entry.event_type == "CircuitBreakerSet"));
+    assert_eq!(
+        logs.iter()
+            .filter(|entry| entry.event_type == "OperatingModeSet")
+            .count(),
+        3
     );
 }
 
-#[test]
-fn test_pool_transfer_authorization() {
-    let (pic, backend_canister, _admin) = setup();
-    let non_admin = create_mock_ii_principal(10);
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct CreateReferralCampaignArgs {
+    name: String,
+    start_time: Option<u64>,
+    end_time: u64,
+    referrer_reward: Nat,
+    referee_reward: Nat,
+    second_tier_reward: Option<Nat>,
+    max_referrals_per_referrer: u32,
+    budget: Nat,
+    qualifier: Principal,
+}
 
-    // Try to transfer as non-admin
-    let transfer_amount = Nat::from(1_000_000_000_u128); // 10 CRNL
-    let args = encode_args((PoolTransferArgs {
-        from_pool: "reserve".to_string(),
-        to_pool: Some("community".to_string()),
-        to_principal: None,
-        amount: transfer_amount,
-        description: "Unauthorized attempt".to_string(),
-    },))
-    .expect("Failed to encode args");
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ReferralCampaign {
+    id: u64,
+    name: String,
+    start_time: u64,
+    end_time: u64,
+    referrer_reward: Nat,
+    referee_reward: Nat,
+    second_tier_reward: Option<Nat>,
+    max_referrals_per_referrer: u32,
+    budget: Nat,
+    committed: Nat,
+    qualifier: Principal,
+}
 
-    let response = pic
-        .update_call(backend_canister, non_admin, "admin_transfer", args)
-        .expect("Failed to call admin_transfer");
-    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
-    assert!(
-        matches!(
-            result,
-            Err(LedgerError::AdminRequired) | Err(LedgerError::NotAuthenticated)
-        ),
-        "Non-admin should not be able to transfer from pools: {:?}",
-        result
-    );
+#[derive(CandidType, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+enum ReferralStatus {
+    Pending,
+    Rewarded,
+    Expired,
 }
 
-#[test]
-fn test_pool_transfer_invalid_pool_name() {
-    let (pic, backend_canister, admin) = setup();
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct Referral {
+    referee: Account,
+    referrer: Account,
+    second_tier: Option<Account>,
+    campaign_id: u64,
+    claimed_at: u64,
+    referrer_reward: Nat,
+    referee_reward: Nat,
+    second_tier_reward: Nat,
+    status: ReferralStatus,
+}
 
-    // Try to transfer from invalid pool
-    let transfer_amount = Nat::from(1_000_000_000_u128);
-    let args = encode_args((PoolTransferArgs {
-        from_pool: "invalid_pool".to_string(),
-        to_pool: Some("community".to_string()),
-        to_principal: None,
-        amount: transfer_amount,
-        description: "Invalid pool".to_string(),
-    },))
-    .expect("Failed to encode args");
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct RefereeInfo {
+    referee: Account,
+    campaign_id: u64,
+    claimed_at: u64,
+    status: ReferralStatus,
+}
 
-    let response = pic
-        .update_call(backend_canister, admin, "admin_transfer", args)
-        .expect("Failed to call admin_transfer");
-    let result: Result<String, LedgerError> = decode_one(&response).unwrap();
-    assert!(
-        matches!(result, Err(LedgerError::InvalidAccount)),
-        "Should fail with invalid account"
-    );
+#[derive(CandidType, serde::Deserialize, Clone, Debug)]
+struct ReferralStats {
+    referees: Vec<RefereeInfo>,
+    earned: Nat,
+    pending: Nat,
 }
 
 #[test]
-fn test_pool_transfer_all_pool_combinations() {
+fn test_referral_campaigns() {
     let (pic, backend_canister, admin) = setup();
+    let qualifier = create_mock_ii_principal(9);
+    let accounts: Vec<Account> = (10..16)
+        .map(|seed| Account {
+            owner: create_mock_ii_principal(seed),
+            subaccount: None,
+        })
+        .collect();
+    let (alice, bob, carol, dave, erin, frank) = (
+        &accounts[0],
+        &accounts[1],
+        &accounts[2],
+        &accounts[3],
+        &accounts[4],
+        &accounts[5],
+    );
+    let codes: Vec<String> = accounts
+        .iter()
+        .map(|account| {
+            let response = pic
+                .update_call(
+                    backend_canister,
+                    account.owner,
+                    "register_user",
+                    encode_args((account.clone(),)).unwrap(),
+                )
+                .expect("Failed to register user");
+            decode_one::<Result<String, LedgerError>>(&response)
+                .unwrap()
+                .unwrap()
+                .split("Your referral code is: ")
+                .nth(1)
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    let claim = |referee: &Account, code: &str| -> Result<String, LedgerError> {
+        let response = pic
+            .update_call(
+                backend_canister,
+                referee.owner,
+                "claim_referral",
+                encode_args((ClaimReferralArgs {
+                    referral_code: code.to_string(),
+                },))
+                .unwrap(),
+            )
+            .expect("Failed to call claim_referral");
+        decode_one(&response).unwrap()
+    };
+    let stats = |account: &Account| -> ReferralStats {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "get_referral_stats",
+                encode_args((account.clone(),)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    let balance_of = |account: &Account| -> Nat {
+        decode_one(
+            &pic.query_call(
+                backend_canister,
+                Principal::anonymous(),
+                "icrc1_balance_of",
+                encode_args((account.clone(),)).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
 
-    let pools = vec!["community", "reserve", "dapp"];
-    let transfer_amount = Nat::from(1_000_000_000_u128); // 10 CRNL
-
-    for from_pool in &pools {
-        for to_pool in &pools {
-            if from_pool == to_pool {
-                continue; // Skip same pool transfers
-            }
+    // Referrals need a running campaign.
+    assert!(matches!(
+        claim(bob, &codes[0]),
+        Err(LedgerError::InvalidReferralCampaign(_))
+    ));
+
+    let mut args = referral_campaign_args(&pic, qualifier);
+    args.second_tier_reward = Some(Nat::from(500_000_000_u128));
+    args.max_referrals_per_referrer = 2;
+    let campaign_id = create_referral_campaign(&pic, backend_canister, admin, args.clone())
+        .expect("Failed to create campaign");
+    assert!(matches!(
+        create_referral_campaign(&pic, backend_canister, admin, args),
+        Err(LedgerError::InvalidReferralCampaign(_))
+    ));
+
+    // Claiming only holds the rewards until the referee qualifies.
+    let alice_balance = balance_of(alice);
+    assert!(claim(bob, &codes[0]).is_ok());
+    assert_eq!(balance_of(alice), alice_balance);
+    let alice_stats = stats(alice);
+    assert_eq!(alice_stats.referees.len(), 1);
+    assert_eq!(alice_stats.referees[0].referee, *bob);
+    assert_eq!(alice_stats.referees[0].status, ReferralStatus::Pending);
+    assert_eq!(alice_stats.pending, Nat::from(2_000_000_000_u128));
+    assert_eq!(alice_stats.earned, Nat::from(0u64));
+    assert_eq!(stats(bob).pending, Nat::from(1_000_000_000_u128));
+
+    // Self-referrals, mutual referrals and second claims are rejected.
+    assert_eq!(claim(alice, &codes[0]), Err(LedgerError::InvalidReferral));
+    assert_eq!(claim(alice, &codes[1]), Err(LedgerError::InvalidReferral));
+    assert_eq!(claim(bob, &codes[2]), Err(LedgerError::InvalidReferral));
+
+    // Only the campaign's qualifier reports qualifying actions, and a referral
+    // is paid once.
+    assert_eq!(
+        record_qualifying_action(&pic, backend_canister, admin, bob),
+        Err(LedgerError::UnauthorizedCaller)
+    );
+    let bob_balance = balance_of(bob);
+    assert_eq!(
+        record_qualifying_action(&pic, backend_canister, qualifier, bob),
+        Ok(true)
+    );
+    assert_eq!(
+        record_qualifying_action(&pic, backend_canister, qualifier, bob),
+        Ok(false)
+    );
+    assert_eq!(
+        record_qualifying_action(&pic, backend_canister, qualifier, frank),
+        Ok(false)
+    );
+    assert_eq!(
+        balance_of(alice),
+        alice_balance.clone() + Nat::from(2_000_000_000_u128)
+    );
+    assert_eq!(balance_of(bob), bob_balance + Nat::from(1_000_000_000_u128));
 
-            println!("Testing transfer from {} to {}", from_pool, to_pool);
+    // Once Bob has qualified, Alice earns the second tier on his referrals.
+    assert!(claim(carol, &codes[1]).is_ok());
+    assert_eq!(stats(alice).pending, Nat::from(500_000_000_u128));
+    assert_eq!(
+        record_qualifying_action(&pic, backend_canister, qualifier, carol),
+        Ok(true)
+    );
+    assert_eq!(
+        balance_of(alice),
+        alice_balance + Nat::from(2_500_000_000_u128)
+    );
+    let alice_stats = stats(alice);
+    assert_eq!(alice_stats.earned, Nat::from(2_500_000_000_u128));
+    assert_eq!(alice_stats.pending, Nat::from(0u64));
+    assert_eq!(stats(bob).earned, Nat::from(3_000_000_000_u128));
+
+    // Alice may refer two accounts in this campaign.
+    assert!(claim(dave, &codes[0]).is_ok());
+    assert!(matches!(
+        claim(erin, &codes[0]),
+        Err(LedgerError::ReferralLimitReached(_))
+    ));
+
+    // A referee may have the ledger ask the qualifier itself. Paid referrals
+    // are not asked about again, and this qualifier is not a canister, so it
+    // cannot answer for Dave.
+    let claim_rewards = |referee: &Account| -> Result<bool, LedgerError> {
+        let response = pic
+            .update_call(
+                backend_canister,
+                referee.owner,
+                "claim_referral_rewards",
+                encode_args(()).unwrap(),
+            )
+            .expect("Failed to call claim_referral_rewards");
+        decode_one(&response).unwrap()
+    };
+    assert_eq!(claim_rewards(bob), Ok(false));
+    assert!(matches!(
+        claim_rewards(dave),
+        Err(LedgerError::Unavailable(_))
+    ));
 
-            let args = encode_args((PoolTransferArgs {
-                from_pool: from_pool.to_string(),
-                to_pool: Some(to_pool.to_string()),
-                to_principal: None,
-                amount: transfer_amount.clone(),
-                description: format!("Test {} to {}", from_pool, to_pool),
-            },))
-            .expect("Failed to encode args");
+    // Pending referrals expire with their campaign.
+    let response = pic
+        .update_call(
+            backend_canister,
+            admin,
+            "end_referral_campaign",
+            encode_args((campaign_id,)).unwrap(),
+        )
+        .expect("Failed to call end_referral_campaign");
+    let result: Result<(), LedgerError> = decode_one(&response).unwrap();
+    assert_eq!(result, Ok(()));
+    let alice_stats = stats(alice);
+    assert_eq!(alice_stats.referees.len(), 2);
+    assert_eq!(alice_stats.referees[1].status, ReferralStatus::Expired);
+    assert_eq!(alice_stats.pending, Nat::from(0u64));
+    assert_eq!(
+        record_qualifying_action(&pic, backend_canister, qualifier, dave),
+        Ok(false)
+    );
+    assert!(matches!(
+        claim(erin, &codes[0]),
+        Err(LedgerError::InvalidReferralCampaign(_))
+    ));
 
-            let response = pic
-                .update_call(backend_canister, admin, "admin_transfer", args)
-                .expect("Failed to call admin_transfer");
-            let result: Result<String, LedgerError> = decode_one(&response).unwrap();
-            assert!(
-                result.is_ok(),
-                "Transfer from {} to {} should succeed: {:?}",
-                from_pool,
-                to_pool,
-                result
-            );
-        }
-    }
+    let campaigns: Vec<ReferralCampaign> = decode_one(
+        &pic.query_call(
+            backend_canister,
+            Principal::anonymous(),
+            "get_referral_campaigns",
+            encode_args(()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(campaigns.len(), 1);
+    assert_eq!(campaigns[0].committed, Nat::from(6_500_000_000_u128));
 }
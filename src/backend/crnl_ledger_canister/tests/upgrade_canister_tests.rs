@@ -0,0 +1,363 @@
+// src/backend/crnl_ledger/tests/upgrade_canister_tests.rs
+
+use candid::{decode_one, encode_args, CandidType, Nat, Principal};
+use pocket_ic::PocketIc;
+use serde::Deserialize;
+use std::fs;
+
+const BACKEND_WASM: &str =
+    "../../../target/wasm32-unknown-unknown/release/crnl_ledger_canister.wasm";
+// Built from an earlier revision by build_legacy_wasm.sh
+const LEGACY_WASM: &str = "../../../target/legacy/crnl_ledger_canister.wasm";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<[u8; 32]>,
+}
+
+// Shapes of the arguments accepted by the legacy ledger
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LegacyPoolTransferArgs {
+    from_pool: String,
+    to_pool: Option<String>,
+    to_principal: Option<Account>,
+    amount: Nat,
+    description: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LegacyApproveArgs {
+    from_subaccount: Option<[u8; 32]>,
+    spender: Account,
+    amount: Nat,
+    expires_at: Option<u64>,
+}
+
+// Transaction of the legacy hash-keyed log
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LegacyTransactionEvent {
+    tx_id: [u8; 32],
+    timestamp: u64,
+    event_type: String,
+    from: Account,
+    to: Option<Account>,
+    spender: Option<Account>,
+    amount: Nat,
+    fee: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransactionEvent {
+    index: u64,
+    tx_id: [u8; 32],
+    timestamp: u64,
+    event_type: String,
+    from: Account,
+    to: Option<Account>,
+    spender: Option<Account>,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct AllowanceArgs {
+    account: Account,
+    spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Allowance {
+    allowance: Nat,
+    expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct LogEntry {
+    timestamp: u64,
+    event_type: String,
+    details: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct QuarantinedValue {
+    id: u64,
+    type_name: String,
+    bytes: Vec<u8>,
+    error: String,
+    detected_at: u64,
+}
+
+// Only the variants these tests can run into
+#[derive(CandidType, Deserialize, Debug)]
+enum LedgerError {
+    Unauthorized,
+    NotAuthenticated,
+    AdminRequired,
+    QuarantinedValueNotFound,
+}
+
+fn create_ii_principal(seed: u8) -> Principal {
+    let mut bytes = [0u8; 10];
+    bytes[0] = seed;
+    bytes[9] = 0x01;
+    Principal::from_slice(&bytes)
+}
+
+fn read_wasm(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|_| {
+        panic!(
+            "{} not found, run 'bash build_legacy_wasm.sh' and 'cargo build --target wasm32-unknown-unknown --release'.",
+            path
+        )
+    })
+}
+
+fn query<T: CandidType + for<'de> Deserialize<'de>>(
+    pic: &PocketIc,
+    canister: Principal,
+    caller: Principal,
+    method: &str,
+    args: Vec<u8>,
+) -> T {
+    decode_one(
+        &pic.query_call(canister, caller, method, args)
+            .unwrap_or_else(|e| panic!("Failed to query {}: {:?}", method, e)),
+    )
+    .unwrap()
+}
+
+fn update(pic: &PocketIc, canister: Principal, caller: Principal, method: &str, args: Vec<u8>) {
+    pic.update_call(canister, caller, method, args)
+        .unwrap_or_else(|e| panic!("Failed to call {}: {:?}", method, e));
+}
+
+#[test]
+fn test_upgrade_from_legacy_ledger_keeps_state() {
+    std::env::set_var("POCKET_IC_BIN", "/usr/local/bin/pocket-ic");
+    let pic = PocketIc::new();
+    let admin = create_ii_principal(1);
+    let user = Account {
+        owner: create_ii_principal(2),
+        subaccount: None,
+    };
+    let spender = Account {
+        owner: create_ii_principal(3),
+        subaccount: None,
+    };
+
+    // The legacy ledger predates max_supply and stores values as bare Candid.
+    let ledger = pic.create_canister();
+    pic.add_cycles(ledger, 2_000_000_000_000);
+    let init_args = encode_args((
+        "Chronolock".to_string(),
+        "CRNL".to_string(),
+        100_000_000_000_000_000_000_u128,
+        31_536_000_u64,
+        100_000_u128,
+        admin,
+    ))
+    .unwrap();
+    pic.install_canister(ledger, read_wasm(LEGACY_WASM), init_args, None);
+
+    update(
+        &pic,
+        ledger,
+        admin,
+        "set_admin_bypass",
+        encode_args((true,)).unwrap(),
+    );
+    let transfer = LegacyPoolTransferArgs {
+        from_pool: "reserve".to_string(),
+        to_pool: None,
+        to_principal: Some(user.clone()),
+        amount: Nat::from(10_000_000u64),
+        description: "Before upgrade".to_string(),
+    };
+    update(
+        &pic,
+        ledger,
+        admin,
+        "admin_transfer",
+        encode_args((transfer,)).unwrap(),
+    );
+    let approve = LegacyApproveArgs {
+        from_subaccount: None,
+        spender: spender.clone(),
+        amount: Nat::from(500_000u64),
+        expires_at: None,
+    };
+    update(
+        &pic,
+        ledger,
+        user.owner,
+        "icrc1_approve",
+        encode_args((approve,)).unwrap(),
+    );
+
+    let anonymous = Principal::anonymous();
+    let total_supply: Nat = query(
+        &pic,
+        ledger,
+        anonymous,
+        "icrc1_total_supply",
+        encode_args(()).unwrap(),
+    );
+    let balance: Nat = query(
+        &pic,
+        ledger,
+        anonymous,
+        "icrc1_balance_of",
+        encode_args((user.clone(),)).unwrap(),
+    );
+    assert_eq!(balance, Nat::from(10_000_000u64));
+    let legacy_history: Vec<LegacyTransactionEvent> = query(
+        &pic,
+        ledger,
+        anonymous,
+        "get_transactions",
+        encode_args(([0u8; 32], [255u8; 32])).unwrap(),
+    );
+    let legacy_approval = legacy_history
+        .iter()
+        .find(|event| event.event_type == "Approval")
+        .expect("The legacy ledger should record the approval")
+        .clone();
+
+    pic.upgrade_canister(
+        ledger,
+        read_wasm(BACKEND_WASM),
+        encode_args(()).unwrap(),
+        None,
+    )
+    .expect("Upgrade from the legacy ledger failed");
+
+    let name: String = query(
+        &pic,
+        ledger,
+        anonymous,
+        "icrc1_name",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(name, "Chronolock");
+    let upgraded_supply: Nat = query(
+        &pic,
+        ledger,
+        anonymous,
+        "icrc1_total_supply",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(upgraded_supply, total_supply);
+    let upgraded_balance: Nat = query(
+        &pic,
+        ledger,
+        anonymous,
+        "icrc1_balance_of",
+        encode_args((user.clone(),)).unwrap(),
+    );
+    assert_eq!(upgraded_balance, balance);
+    let allowance: Allowance = query(
+        &pic,
+        ledger,
+        anonymous,
+        "icrc2_allowance",
+        encode_args((AllowanceArgs {
+            account: user,
+            spender: spender.clone(),
+        },))
+        .unwrap(),
+    );
+    assert_eq!(allowance.allowance, Nat::from(500_000u64));
+
+    // The legacy history is replayed into the block log and keeps its ids.
+    let transaction_count: u64 = query(
+        &pic,
+        ledger,
+        anonymous,
+        "get_transaction_count",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(transaction_count, legacy_history.len() as u64);
+    let local_blocks: u64 = query(
+        &pic,
+        ledger,
+        anonymous,
+        "get_local_block_count",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(local_blocks, transaction_count);
+    let approval: Option<TransactionEvent> = query(
+        &pic,
+        ledger,
+        anonymous,
+        "get_transaction_by_id",
+        encode_args((legacy_approval.tx_id,)).unwrap(),
+    );
+    let approval = approval.expect("Legacy transaction id should still resolve");
+    assert_eq!(approval.event_type, "Approval");
+    assert_eq!(approval.from, legacy_approval.from);
+    assert_eq!(approval.spender, Some(spender.clone()));
+    assert_eq!(approval.timestamp, legacy_approval.timestamp);
+
+    let logs: Result<Vec<LogEntry>, LedgerError> = query(
+        &pic,
+        ledger,
+        admin,
+        "get_logs_paginated",
+        encode_args((0u64, 100u64)).unwrap(),
+    );
+    let logs = logs.unwrap();
+    assert!(logs.iter().any(|entry| entry.event_type == "Init"));
+    assert!(logs.iter().all(|entry| entry.event_type != "Quarantined"));
+
+    let schema_version: u32 = query(
+        &pic,
+        ledger,
+        anonymous,
+        "get_schema_version",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(schema_version, 4);
+    let quarantined: Result<Vec<QuarantinedValue>, LedgerError> = query(
+        &pic,
+        ledger,
+        admin,
+        "get_quarantined_values",
+        encode_args((0u64, 100u64)).unwrap(),
+    );
+    assert!(quarantined.unwrap().is_empty());
+
+    // Values written after the upgrade carry the version prefix and survive a
+    // further upgrade to the same code.
+    update(
+        &pic,
+        ledger,
+        admin,
+        "set_transfer_fee",
+        encode_args((Nat::from(200_000u64),)).unwrap(),
+    );
+    pic.upgrade_canister(
+        ledger,
+        read_wasm(BACKEND_WASM),
+        encode_args(()).unwrap(),
+        None,
+    )
+    .expect("Second upgrade failed");
+    let fee: Nat = query(
+        &pic,
+        ledger,
+        anonymous,
+        "icrc1_fee",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(fee, Nat::from(200_000u64));
+    let upgraded_supply: Nat = query(
+        &pic,
+        ledger,
+        anonymous,
+        "icrc1_total_supply",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(upgraded_supply, total_supply);
+}
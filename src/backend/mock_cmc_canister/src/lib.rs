@@ -0,0 +1,78 @@
+// src/backend/mock_cmc_canister/src/lib.rs
+
+// Stand-in for the cycles minting canister used by the ledger's
+// convert_dapp_funds_to_cycles in integration tests. notify_top_up mints a
+// fixed amount of cycles per notified block unless told to fail.
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk_macros::{query, update};
+use serde::Deserialize;
+use std::cell::RefCell;
+
+// -------------------------
+// Data Structures
+// -------------------------
+
+#[derive(CandidType, Deserialize, Clone)]
+struct NotifyTopUpArg {
+    block_index: u64,
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<u64>,
+    },
+    InvalidTransaction(String),
+    TransactionTooOld(u64),
+    Processing,
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+// -------------------------
+// State
+// -------------------------
+
+// Cycles minted for each notified block
+const CYCLES_PER_TOP_UP: u64 = 1_000_000_000_000;
+
+thread_local! {
+    static TOP_UPS: RefCell<Vec<NotifyTopUpArg>> = const { RefCell::new(Vec::new()) };
+    static FAILURE: RefCell<Option<NotifyError>> = const { RefCell::new(None) };
+}
+
+// -------------------------
+// Update Functions
+// -------------------------
+
+#[update]
+fn notify_top_up(arg: NotifyTopUpArg) -> Result<Nat, NotifyError> {
+    if let Some(err) = FAILURE.with(|f| f.borrow().clone()) {
+        return Err(err);
+    }
+    TOP_UPS.with(|t| t.borrow_mut().push(arg));
+    Ok(Nat::from(CYCLES_PER_TOP_UP))
+}
+
+// Makes every following notification fail with the given error, or succeed again.
+#[update]
+fn set_failure(err: Option<NotifyError>) {
+    FAILURE.with(|f| *f.borrow_mut() = err);
+}
+
+// -------------------------
+// Query Functions
+// -------------------------
+
+#[query]
+fn get_top_ups() -> Vec<NotifyTopUpArg> {
+    TOP_UPS.with(|t| t.borrow().clone())
+}
+
+// Export Candid interface
+ic_cdk::export_candid!();
@@ -0,0 +1,84 @@
+// src/backend/mock_swap_canister/src/lib.rs
+
+// Stand-in for the CRNL to ICP swap canister used by the ledger's
+// convert_dapp_funds_to_cycles in integration tests. Swaps pay out ICP at a
+// fixed rate and report a made-up ICP ledger block index.
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk_macros::{query, update};
+use serde::Deserialize;
+use std::cell::RefCell;
+
+// -------------------------
+// Data Structures
+// -------------------------
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<[u8; 32]>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct SwapArgs {
+    amount_in: Nat,
+    to: Account,
+    memo: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct SwapReceipt {
+    amount_out: Nat,
+    block_index: u64,
+}
+
+// -------------------------
+// State
+// -------------------------
+
+// ICP e8s paid out per CRNL e8s swapped
+const ICP_PER_CRNL_DIVISOR: u64 = 100;
+
+thread_local! {
+    static SWAPS: RefCell<Vec<SwapArgs>> = const { RefCell::new(Vec::new()) };
+    static FAILURE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// -------------------------
+// Update Functions
+// -------------------------
+
+#[update]
+fn swap_crnl_to_icp(args: SwapArgs) -> Result<SwapReceipt, String> {
+    if let Some(reason) = FAILURE.with(|f| f.borrow().clone()) {
+        return Err(reason);
+    }
+    let amount_out = Nat::from(&args.amount_in.0 / ICP_PER_CRNL_DIVISOR);
+    let block_index = SWAPS.with(|s| {
+        let mut swaps = s.borrow_mut();
+        swaps.push(args);
+        swaps.len() as u64
+    });
+    Ok(SwapReceipt {
+        amount_out,
+        block_index,
+    })
+}
+
+// Makes every following swap fail with the given reason, or succeed again.
+#[update]
+fn set_failure(reason: Option<String>) {
+    FAILURE.with(|f| *f.borrow_mut() = reason);
+}
+
+// -------------------------
+// Query Functions
+// -------------------------
+
+#[query]
+fn get_swaps() -> Vec<SwapArgs> {
+    SWAPS.with(|s| s.borrow().clone())
+}
+
+// Export Candid interface
+ic_cdk::export_candid!();
@@ -144,6 +144,7 @@ export const DecryptModal: React.FC<DecryptModalProps> = ({
         decryptionKeyResult = await getTimeDecryptionKey(
           unlockTimeHex,
           transportPublicKey,
+          chronolock.id,
         );
       } else {
         // For user-specific chronolocks, extract unlock time and user from identity
@@ -157,6 +158,7 @@ export const DecryptModal: React.FC<DecryptModalProps> = ({
           unlockTimeHex,
           userIdentity,
           transportPublicKey,
+          chronolock.id,
         );
       }
 
@@ -11,6 +11,7 @@ import {
 import { useState } from 'react';
 import { useNavigate } from 'react-router';
 import { useAuth } from '../../hooks/useAuth';
+import { Principal } from '@dfinity/principal';
 
 interface IReviewAndCreate {
   name: string | undefined;
@@ -39,12 +40,13 @@ const ReviewAndCreate = ({
   onBack,
 }: IReviewAndCreate) => {
   const date = lockTime ? moment(lockTime * 1000) : null;
-  const { balanceRaw } = useCrnlToken();
+  const { balanceRaw, approve, getFee } = useCrnlToken();
   const { principal } = useAuth();
   const MEDIA_CHRONOLOCK_COST = 20n * 10n ** 8n;
   const {
     getVetkdPublicKey,
     createChronolock,
+    getMintPrice,
     isCreateChronolockLoading,
     isGetVetkdPublicKeyLoading,
   } = useChronolock();
@@ -140,6 +142,16 @@ const ReviewAndCreate = ({
       }
 
       setTimeout(async () => {
+        // The chronolock canister collects its mint price from an allowance
+        const mintPrice = BigInt((await getMintPrice()) as bigint);
+        if (mintPrice > 0n) {
+          const fee = BigInt((await getFee()) as bigint);
+          await approve(
+            Principal.fromText(process.env.CANISTER_ID_CHRONOLOCK_CANISTER!),
+            mintPrice + fee,
+          );
+        }
+
         const chronolockObject = await createChronolock([
           title,
           lockTime,
@@ -31,6 +31,7 @@ interface IUseChronolock {
       | React.MouseEvent<Element, MouseEvent>
       | undefined,
   ) => Promise<unknown>;
+  getMintPrice: () => Promise<unknown>;
   upload: (media: ArrayBuffer) => Promise<unknown>;
   getMediaChunked: (
     mediaId: string,
@@ -46,11 +47,13 @@ interface IUseChronolock {
   getTimeDecryptionKey: (
     unlockTimeHex: string,
     transportPublicKey: number[],
+    tokenId?: string,
   ) => Promise<unknown>;
   getUserTimeDecryptionKey: (
     unlockTimeHex: string,
     userId: string,
     transportPublicKey: number[],
+    tokenId?: string,
   ) => Promise<unknown>;
   // New pagination functions
   getAllChronolocksCount: () => Promise<unknown>;
@@ -154,6 +157,11 @@ export const useChronolock = (): IUseChronolock => {
     functionName: 'create_chronolock' as any,
   });
 
+  const { call: getMintPrice } = chronolockQueryCall({
+    refetchOnMount: false,
+    functionName: 'get_mint_price' as any,
+  });
+
   const {
     call: burnChronolockCall,
     loading: isBurnChronolockLoading,
@@ -358,19 +366,30 @@ export const useChronolock = (): IUseChronolock => {
     return generatedKey;
   }, []);
 
+  // The token id lets the lock's owner and recipients use free derivations.
   const getTimeDecryptionKeyWrapped = useCallback(
-    (unlockTimeHex: string, transportPublicKey: number[]) => {
-      return getTimeDecryptionKey([unlockTimeHex, transportPublicKey]);
+    (unlockTimeHex: string, transportPublicKey: number[], tokenId?: string) => {
+      return getTimeDecryptionKey([
+        unlockTimeHex,
+        transportPublicKey,
+        tokenId ? [tokenId] : [],
+      ]);
     },
     [getTimeDecryptionKey],
   );
 
   const getUserTimeDecryptionKeyWrapped = useCallback(
-    (unlockTimeHex: string, userId: string, transportPublicKey: number[]) => {
+    (
+      unlockTimeHex: string,
+      userId: string,
+      transportPublicKey: number[],
+      tokenId?: string,
+    ) => {
       return getUserTimeDecryptionKey([
         unlockTimeHex,
         userId,
         transportPublicKey,
+        tokenId ? [tokenId] : [],
       ]);
     },
     [getUserTimeDecryptionKey],
@@ -397,6 +416,7 @@ export const useChronolock = (): IUseChronolock => {
     isCreateChronolockLoading,
     createChronolockError,
     createChronolock,
+    getMintPrice,
     upload,
     getMediaChunked,
     getChronolock,
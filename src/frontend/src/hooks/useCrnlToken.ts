@@ -41,6 +41,7 @@ export interface IUseCrnlToken {
   registerUser: () => Promise<unknown>;
   checkBalance: () => Promise<unknown>;
   transfer: (transferArgs: ITransferArgs) => Promise<unknown>;
+  approve: (spender: Principal, amount: bigint) => Promise<unknown>;
   // New statistics functions
   getTotalSupply: () => Promise<unknown>;
   getTotalBurned: () => Promise<unknown>;
@@ -203,6 +204,7 @@ export const useCrnlToken = (): IUseCrnlToken => {
           caller: callerAccount,
           amount,
           description,
+          memo: [],
         },
       ]).then((res) => {
         const result = res as Record<string, unknown> | undefined;
@@ -258,8 +260,10 @@ export const useCrnlToken = (): IUseCrnlToken => {
           },
           from_subaccount: [],
           amount: args.amount,
+          fee: [],
+          memo: [],
+          created_at_time: [],
         },
-        [],
       ]).then((res) => {
         checkBalance();
         return res;
@@ -268,6 +272,40 @@ export const useCrnlToken = (): IUseCrnlToken => {
     [transferCall, checkBalance],
   );
 
+  const { call: approveCall } = crnlUpdateCall({
+    functionName: 'icrc2_approve' as any,
+  });
+
+  const approve = useCallback(
+    async (spender: Principal, amount: bigint) => {
+      return approveCall([
+        {
+          from_subaccount: [],
+          spender: { owner: spender, subaccount: [] },
+          amount,
+          expected_allowance: [],
+          expires_at: [],
+          fee: [],
+          memo: [],
+          created_at_time: [],
+        },
+      ]).then((res) => {
+        const result = res as Record<string, unknown> | undefined;
+        if (result && 'Err' in result) {
+          const errValue = result.Err;
+          const errorKey =
+            errValue && typeof errValue === 'object'
+              ? Object.keys(errValue as Record<string, unknown>)[0]
+              : 'UnknownError';
+          throw new Error(`Failed to approve payment: ${errorKey}`);
+        }
+        checkBalance();
+        return res;
+      });
+    },
+    [approveCall, checkBalance],
+  );
+
   const isLoading =
     isBalanceLoading ||
     isRegisterLoading ||
@@ -392,6 +430,7 @@ export const useCrnlToken = (): IUseCrnlToken => {
     registerUser,
     checkBalance,
     transfer,
+    approve,
     getTotalSupply,
     getTotalBurned,
     deductFromBalance,